@@ -6,7 +6,7 @@ use system76_power::{
 };
 
 fn inner() -> Result<(), FanDaemonError> {
-    let daemon = FanDaemon::new(false);
+    let mut daemon = FanDaemon::new(false);
 
     loop {
         if let Some(temp) = daemon.get_temp() {
@@ -28,6 +28,9 @@ fn inner() -> Result<(), FanDaemonError> {
         } else {
             println!("Failed to read temperature");
         }
+        for (channel, rpm) in daemon.get_rpms() {
+            println!("    fan {}: {} RPM", channel, rpm);
+        }
         thread::sleep(time::Duration::new(1, 0));
     }
 }
@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use clap::{builder::PossibleValuesParser, Parser};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[clap(
@@ -32,6 +33,129 @@ pub enum GraphicsArgs {
     },
 }
 
+#[derive(Parser)]
+pub enum BatteryArgs {
+    #[clap(
+        about = "Recalibrate the battery fuel gauge",
+        long_about = "Recalibrates the battery fuel gauge by charging to full, discharging to a \
+                      low level, and recharging, then restores the charge thresholds. Where the \
+                      battery supports it, discharging is forced while on AC; otherwise you will \
+                      be asked to unplug the AC adapter."
+    )]
+    Calibrate {
+        #[clap(long = "cancel", help = "Cancel the calibration in progress")]
+        cancel: bool,
+    },
+}
+
+#[derive(Parser)]
+pub enum FanArgs {
+    #[clap(about = "Query or select the fan curve")]
+    Curve {
+        #[clap(subcommand)]
+        cmd: FanCurveArgs,
+    },
+    #[clap(about = "Show the temperature, and the duty and speed of each fan")]
+    Status,
+    #[clap(
+        about = "Set the fan duty manually",
+        long_about = "Overrides the fan curves with a duty until the timeout expires. The \
+                      override is cancelled if the temperature becomes critical."
+    )]
+    Duty {
+        #[clap(
+            help = "The duty in percent",
+            required_unless_present = "auto",
+            value_parser = clap::value_parser!(u8).range(0..=100)
+        )]
+        percent: Option<u8>,
+        #[clap(
+            long = "timeout",
+            help = "Return to the fan curves after this many seconds",
+            default_value_t = 60,
+            value_parser = clap::value_parser!(u32).range(1..=3600)
+        )]
+        timeout: u32,
+        #[clap(long = "auto", help = "Return to the fan curves now", conflicts_with = "percent")]
+        auto:    bool,
+    },
+    #[clap(
+        about = "Measure the duty at which each fan starts, and its fastest speed",
+        long_about = "Runs each fan at full speed, stops it, and steps it up until it spins, \
+                      which takes about two minutes. Curves then no longer drive a fan below the \
+                      duty at which it starts."
+    )]
+    Calibrate {
+        #[clap(long = "cancel", help = "Cancel the calibration in progress")]
+        cancel: bool,
+    },
+    #[clap(
+        about = "Show the duties a fan curve gives, without the hardware",
+        long_about = "Prints the duty of a fan curve for each temperature from 0 to 100°C, or \
+                      replays a trace of temperatures through the curve and its smoothing, as \
+                      CSV. The trace has a temperature in degrees Celsius on each line, either \
+                      alone and a second apart, or after the time in seconds."
+    )]
+    Simulate {
+        #[clap(
+            long = "curve",
+            help = "The name of a fan curve, a TOML file with its points and tuning, or points \
+                    such as 45:30,55:35,88:100"
+        )]
+        curve: String,
+        #[clap(long = "trace", help = "A CSV file of temperatures to replay")]
+        trace: Option<PathBuf>,
+    },
+    #[clap(
+        about = "Show the temperatures, duties and fan speeds of the last day",
+        long_about = "Shows the hottest temperature of each sensor, and the highest duty and \
+                      speed of each fan, in periods of five seconds or more over the last day. \
+                      The history is kept in memory by the daemon, and starts again when it \
+                      restarts."
+    )]
+    History {
+        #[clap(long = "csv", help = "Print the history as CSV")]
+        csv:        bool,
+        #[clap(
+            long = "since",
+            help = "Show this many minutes of history",
+            default_value_t = 1440,
+            value_parser = clap::value_parser!(u64).range(1..=1440)
+        )]
+        since:      u64,
+        #[clap(
+            long = "resolution",
+            help = "Merge the history into periods of this many seconds",
+            default_value_t = 5,
+            value_parser = clap::value_parser!(u32).range(5..=86400)
+        )]
+        resolution: u32,
+    },
+}
+
+#[derive(Parser)]
+pub enum FanCurveArgs {
+    #[clap(about = "Show the fan curve in use")]
+    Get,
+    #[clap(about = "List the fan curves which can be selected")]
+    List,
+    #[clap(
+        about = "Select a fan curve",
+        long_about = "Reloads /etc/system76-power/fan.toml and selects a fan curve, which applies \
+                      immediately and is kept across restarts."
+    )]
+    Set {
+        #[clap(help = "The name of the fan curve", required_unless_present = "default")]
+        name:    Option<String>,
+        #[clap(
+            long = "default",
+            help = "Use the curve chosen in the config, or the default for this model",
+            conflicts_with = "name"
+        )]
+        default: bool,
+    },
+}
+
 #[derive(Parser)]
 #[clap(
     name = "system76-power",
@@ -82,21 +206,71 @@ pub enum Args {
         #[clap(subcommand)]
         cmd: Option<GraphicsArgs>,
     },
+    #[clap(
+        about = "Show battery health and charge state",
+        long_about = "Shows the design and full charge capacity of each battery, its wear, cycle \
+                      count, and the estimated time until it is empty or full"
+    )]
+    Battery {
+        #[clap(subcommand)]
+        cmd: Option<BatteryArgs>,
+    },
+    #[clap(about = "Query or configure the fan daemon")]
+    Fan {
+        #[clap(subcommand)]
+        cmd: FanArgs,
+    },
+    #[clap(
+        about = "Query or set the battery charge behaviour",
+        long_about = "Queries or sets the battery charge behaviour.\n\n - auto: charge normally\n \
+                      - inhibit-charge: do not charge, even on AC\n - force-discharge: discharge, \
+                      even on AC\n\nOnly the behaviours listed by the kernel for the battery are \
+                      accepted."
+    )]
+    ChargeBehaviour {
+        #[clap(help = "Set the charge behaviour")]
+        behaviour: Option<String>,
+        #[clap(
+            long = "timeout",
+            help = "Revert to auto after this many seconds",
+            requires = "behaviour"
+        )]
+        timeout:   Option<u32>,
+    },
+    #[clap(
+        about = "Query or set smart charging",
+        long_about = "Queries or sets smart charging. While enabled, the battery is held at the \
+                      max_lifespan thresholds, and charged to full shortly before the time it is \
+                      usually unplugged on that weekday. Predictions start after a few weeks of \
+                      use. Setting charge thresholds disables smart charging."
+    )]
+    SmartCharging {
+        #[clap(help = "Set whether smart charging should be on or off")]
+        #[arg(value_parser = PossibleValuesParser::new(["off", "on"]))]
+        state: Option<String>,
+    },
     #[clap(
         about = "Set thresholds for battery charging",
         // Autogenerated usage seemed to have issues
-        override_usage = "system76-power charge-thresholds [<start> <end> | --profile <profile>]",
+        override_usage = "system76-power charge-thresholds [<start> <end> | --profile <profile> | \
+                          --full-once]",
     )]
     ChargeThresholds {
         #[clap(
             long = "profile",
-            help = "Profile name",
-            value_parser = PossibleValuesParser::new(["full_charge", "balanced", "max_lifespan"]),
-            group = "profile-or-thresholds",
+            help = "Profile name, as shown by --list-profiles",
+            group = "profile-or-thresholds"
         )]
         profile:       Option<String>,
         #[clap(long = "list-profiles", help = "List profiles", group = "profile-or-thresholds")]
         list_profiles: bool,
+        #[clap(
+            long = "full-once",
+            help = "Charge to full once, then restore the current thresholds when the battery is \
+                    full or AC is unplugged",
+            group = "profile-or-thresholds"
+        )]
+        full_once:     bool,
         #[clap(
             help = "Charge thresholds",
             value_parser = clap::value_parser!(u8).range(0..=100),
@@ -0,0 +1,467 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Drivers expose charge thresholds in a few different ways. Each backend handles one of them.
+//!
+//! Vendor drivers are covered as follows:
+//!
+//! - asus-nb-wmi: any end threshold, through the end-only backend.
+//! - dell-laptop: start thresholds of 50-95% and end thresholds of 55-100%, through the generic
+//!   backend.
+//! - lg-laptop: an end threshold of 80% or 100%, through the end-only backend.
+//! - msi-ec: end thresholds of 10-100%, with the start threshold following 10% below, through the
+//!   end-only backend.
+//! - samsung-laptop: the battery life extender, which stops charging at 80%.
+//!
+//! Other drivers which implement the generic ABI work without being listed, but their limits are
+//! only found when a write is rejected.
+
+use crate::power_supply;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use system76_power_zbus::ChargeCapabilities;
+
+const PLATFORM: &str = "/sys/devices/platform";
+const HUAWEI_THRESHOLDS: &str = "huawei-wmi/charge_control_thresholds";
+const SAMSUNG_EXTENDER: &str = "samsung/battery_life_extender";
+const EMULATED_THRESHOLDS: &str = "/var/lib/system76-power/emulated-charge-thresholds.json";
+
+/// Drivers whose start threshold is set from the end threshold, which are treated as end-only.
+const END_ONLY_DRIVERS: &[&str] = &["msi-ec"];
+
+/// The start and end thresholds accepted by the platform driver, if it does not accept every
+/// value. Empty values accept any threshold.
+fn driver_values(platform: &Path) -> (Vec<u8>, Vec<u8>) {
+    if platform.join("lg-laptop").exists() {
+        (Vec::new(), vec![80, 100])
+    } else if platform.join("dell-laptop").exists() {
+        ((50..=95).collect(), (55..=100).collect())
+    } else if platform.join("msi-ec").exists() {
+        (Vec::new(), (10..=100).collect())
+    } else {
+        (Vec::new(), Vec::new())
+    }
+}
+
+pub trait ChargeBackend {
+    fn capabilities(&self) -> ChargeCapabilities;
+
+    /// Reads the current thresholds. The start threshold is zero if it is not supported.
+    fn get(&self) -> io::Result<(u8, u8)>;
+
+    /// Writes thresholds which have been checked against the capabilities.
+    fn set(&self, thresholds: (u8, u8)) -> io::Result<()>;
+
+    /// Enforces the thresholds, for backends which are implemented by the daemon.
+    fn enforce(&self) -> io::Result<()> { Ok(()) }
+}
+
+/// Finds the backend for the charge threshold interface of this system, if it has one.
+pub fn detect() -> Option<Box<dyn ChargeBackend + Send>> {
+    detect_in(Path::new(PLATFORM), &power_supply::batteries())
+}
+
+/// Finds the backend among the given platform devices and batteries.
+fn detect_in(platform: &Path, batteries: &[PathBuf]) -> Option<Box<dyn ChargeBackend + Send>> {
+    if let Some(backend) = Generic::detect(platform, batteries) {
+        return Some(Box::new(backend));
+    }
+
+    let huawei = platform.join(HUAWEI_THRESHOLDS);
+    if huawei.exists() {
+        return Some(Box::new(Huawei(huawei)));
+    }
+
+    let samsung = platform.join(SAMSUNG_EXTENDER);
+    if samsung.exists() {
+        return Some(Box::new(Samsung(samsung)));
+    }
+
+    if let Some(backend) = EndOnly::detect(platform, batteries) {
+        return Some(Box::new(backend));
+    }
+
+    if let Some(backend) = Emulated::detect(batteries) {
+        return Some(Box::new(backend));
+    }
+
+    None
+}
+
+fn read_threshold(path: &Path) -> io::Result<u8> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse::<u8>()
+        .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))
+}
+
+/// Batteries which have all of the given attributes.
+fn batteries_with(batteries: &[PathBuf], attrs: &[&str]) -> Vec<PathBuf> {
+    batteries
+        .iter()
+        .filter(|battery| attrs.iter().all(|attr| battery.join(attr).exists()))
+        .cloned()
+        .collect()
+}
+
+/// The generic `charge_control_{start,end}_threshold` power supply ABI.
+///
+/// Every battery which supports it is set to the same thresholds.
+struct Generic {
+    batteries:    Vec<PathBuf>,
+    start_values: Vec<u8>,
+    end_values:   Vec<u8>,
+}
+
+impl Generic {
+    const END: &'static str = "charge_control_end_threshold";
+    const START: &'static str = "charge_control_start_threshold";
+
+    fn detect(platform: &Path, batteries: &[PathBuf]) -> Option<Self> {
+        let batteries = batteries_with(batteries, &[Self::START, Self::END]);
+        if batteries.is_empty()
+            || END_ONLY_DRIVERS.iter().any(|driver| platform.join(driver).exists())
+        {
+            return None;
+        }
+
+        let (start_values, end_values) = driver_values(platform);
+        Some(Self { batteries, start_values, end_values })
+    }
+}
+
+impl ChargeBackend for Generic {
+    fn capabilities(&self) -> ChargeCapabilities {
+        ChargeCapabilities {
+            backend: "charge_control_threshold".into(),
+            start: true,
+            end: true,
+            start_values: self.start_values.clone(),
+            end_values: self.end_values.clone(),
+            ..ChargeCapabilities::default()
+        }
+    }
+
+    fn get(&self) -> io::Result<(u8, u8)> {
+        let battery = &self.batteries[0];
+        Ok((read_threshold(&battery.join(Self::START))?, read_threshold(&battery.join(Self::END))?))
+    }
+
+    fn set(&self, (start, end): (u8, u8)) -> io::Result<()> {
+        for battery in &self.batteries {
+            // Without this, setting start threshold may fail if the previous end
+            // threshold is higher.
+            fs::write(battery.join(Self::END), "100")?;
+
+            fs::write(battery.join(Self::START), format!("{}", start))?;
+            fs::write(battery.join(Self::END), format!("{}", end))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Drivers such as asus-nb-wmi and lg-laptop, which only provide an end threshold.
+struct EndOnly {
+    batteries:  Vec<PathBuf>,
+    end_values: Vec<u8>,
+}
+
+impl EndOnly {
+    fn detect(platform: &Path, batteries: &[PathBuf]) -> Option<Self> {
+        let batteries = batteries_with(batteries, &[Generic::END]);
+        if batteries.is_empty() {
+            return None;
+        }
+
+        let (_, end_values) = driver_values(platform);
+        Some(Self { batteries, end_values })
+    }
+}
+
+impl ChargeBackend for EndOnly {
+    fn capabilities(&self) -> ChargeCapabilities {
+        ChargeCapabilities {
+            backend:      "charge_control_end_threshold".into(),
+            start:        false,
+            end:          true,
+            start_values: Vec::new(),
+            end_values:   self.end_values.clone(),
+            emulated:     false,
+        }
+    }
+
+    fn get(&self) -> io::Result<(u8, u8)> {
+        Ok((0, read_threshold(&self.batteries[0].join(Generic::END))?))
+    }
+
+    fn set(&self, (_, end): (u8, u8)) -> io::Result<()> {
+        for battery in &self.batteries {
+            fs::write(battery.join(Generic::END), format!("{}", end))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The huawei-wmi platform file, which takes both thresholds at once as `start end`.
+struct Huawei(PathBuf);
+
+impl ChargeBackend for Huawei {
+    fn capabilities(&self) -> ChargeCapabilities {
+        ChargeCapabilities {
+            backend: "huawei-wmi".into(),
+            start: true,
+            end: true,
+            ..ChargeCapabilities::default()
+        }
+    }
+
+    fn get(&self) -> io::Result<(u8, u8)> {
+        let thresholds = fs::read_to_string(&self.0)?;
+        let mut values = thresholds.split_whitespace().map(str::parse::<u8>);
+
+        match (values.next(), values.next()) {
+            (Some(Ok(start)), Some(Ok(end))) => Ok((start, end)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected thresholds: {:?}", thresholds.trim()),
+            )),
+        }
+    }
+
+    fn set(&self, (start, end): (u8, u8)) -> io::Result<()> {
+        fs::write(&self.0, format!("{} {}", start, end))
+    }
+}
+
+/// The samsung-laptop battery life extender, which stops charging at 80% when enabled.
+struct Samsung(PathBuf);
+
+impl ChargeBackend for Samsung {
+    fn capabilities(&self) -> ChargeCapabilities {
+        ChargeCapabilities {
+            backend: "samsung-laptop".into(),
+            start: false,
+            end: true,
+            end_values: vec![80, 100],
+            ..ChargeCapabilities::default()
+        }
+    }
+
+    fn get(&self) -> io::Result<(u8, u8)> {
+        let enabled = read_threshold(&self.0)?;
+        Ok((0, if enabled == 0 { 100 } else { 80 }))
+    }
+
+    fn set(&self, (_, end): (u8, u8)) -> io::Result<()> {
+        fs::write(&self.0, if end < 100 { "1" } else { "0" })
+    }
+}
+
+/// The charge behaviour to switch to, if any, for emulated thresholds at the given capacity.
+///
+/// An end threshold of 100 never inhibits charging, but must still release a battery which was
+/// inhibited by lower thresholds. Behaviours other than `auto` and `inhibit-charge` were chosen by
+/// the user, and are left alone.
+fn emulated_behaviour(
+    selected: &str,
+    capacity: u8,
+    (start, end): (u8, u8),
+) -> Option<&'static str> {
+    match selected {
+        "auto" if end < 100 && capacity >= end => Some("inhibit-charge"),
+        "inhibit-charge" if end == 100 || capacity <= start => Some("auto"),
+        _ => None,
+    }
+}
+
+/// Emulates thresholds on batteries which can only be told to stop charging, by switching
+/// `charge_behaviour` between `auto` and `inhibit-charge` as the capacity changes.
+///
+/// Charging is inhibited once the capacity reaches the end threshold, and allowed again once it
+/// falls to the start threshold, so that the battery is not charged in short bursts.
+struct Emulated {
+    battery: PathBuf,
+}
+
+impl Emulated {
+    /// Thresholds which never inhibit charging, used until the user sets some.
+    const DEFAULT: (u8, u8) = (0, 100);
+
+    fn detect(batteries: &[PathBuf]) -> Option<Self> {
+        let battery = batteries_with(batteries, &["charge_behaviour"]).into_iter().next()?;
+        let (choices, _) = power_supply::charge_behaviours(&battery)?;
+
+        choices.iter().any(|choice| choice == "inhibit-charge").then_some(Self { battery })
+    }
+}
+
+impl ChargeBackend for Emulated {
+    fn capabilities(&self) -> ChargeCapabilities {
+        ChargeCapabilities {
+            backend: "charge_behaviour".into(),
+            start: true,
+            end: true,
+            emulated: true,
+            ..ChargeCapabilities::default()
+        }
+    }
+
+    fn get(&self) -> io::Result<(u8, u8)> {
+        match fs::read_to_string(EMULATED_THRESHOLDS) {
+            Ok(thresholds) => serde_json::from_str(&thresholds)
+                .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why)),
+            Err(why) if why.kind() == io::ErrorKind::NotFound => Ok(Self::DEFAULT),
+            Err(why) => Err(why),
+        }
+    }
+
+    fn set(&self, thresholds: (u8, u8)) -> io::Result<()> {
+        if let Some(parent) = Path::new(EMULATED_THRESHOLDS).parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(EMULATED_THRESHOLDS, serde_json::to_string(&thresholds)?)?;
+        self.enforce()
+    }
+
+    fn enforce(&self) -> io::Result<()> {
+        let (start, end) = self.get()?;
+
+        let Some(capacity) = power_supply::capacity(&self.battery) else {
+            return Ok(());
+        };
+
+        let Some((_, selected)) = power_supply::charge_behaviours(&self.battery) else {
+            return Ok(());
+        };
+
+        let Some(behaviour) = emulated_behaviour(&selected, capacity, (start, end)) else {
+            return Ok(());
+        };
+
+        log::info!("battery at {}%: setting charge behaviour to {}", capacity, behaviour);
+        power_supply::set_charge_behaviour(&self.battery, behaviour)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Detects the backend in a fake sysfs with the given files, relative to a directory which
+    /// holds `platform` and the battery `BAT0`.
+    fn detect_with(name: &str, files: &[(&str, &str)]) -> Option<ChargeCapabilities> {
+        let root =
+            std::env::temp_dir().join(format!("system76-power-{}{}", name, std::process::id()));
+        let battery = root.join("BAT0");
+        fs::create_dir_all(root.join("platform")).unwrap();
+        fs::create_dir_all(&battery).unwrap();
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        let capabilities =
+            detect_in(&root.join("platform"), &[battery]).map(|backend| backend.capabilities());
+        fs::remove_dir_all(&root).unwrap();
+        capabilities
+    }
+
+    #[test]
+    fn backend_selection() {
+        let generic = [
+            ("BAT0/charge_control_start_threshold", "0"),
+            ("BAT0/charge_control_end_threshold", "100"),
+        ];
+
+        let capabilities = detect_with("generic", &generic).unwrap();
+        assert_eq!(capabilities.backend, "charge_control_threshold");
+        assert!(capabilities.start && capabilities.end);
+        assert!(capabilities.start_values.is_empty() && capabilities.end_values.is_empty());
+
+        let dell = [generic[0], generic[1], ("platform/dell-laptop/uevent", "")];
+        let capabilities = detect_with("dell", &dell).unwrap();
+        assert_eq!(capabilities.backend, "charge_control_threshold");
+        assert_eq!(capabilities.start_values, (50..=95).collect::<Vec<_>>());
+        assert_eq!(capabilities.end_values, (55..=100).collect::<Vec<_>>());
+
+        // msi-ec sets the start threshold from the end threshold.
+        let msi = [generic[0], generic[1], ("platform/msi-ec/uevent", "")];
+        let capabilities = detect_with("msi", &msi).unwrap();
+        assert_eq!(capabilities.backend, "charge_control_end_threshold");
+        assert!(!capabilities.start);
+        assert_eq!(capabilities.end_values, (10..=100).collect::<Vec<_>>());
+
+        let lg = [generic[1], ("platform/lg-laptop/uevent", "")];
+        let capabilities = detect_with("lg", &lg).unwrap();
+        assert_eq!(capabilities.backend, "charge_control_end_threshold");
+        assert_eq!(capabilities.end_values, vec![80, 100]);
+
+        let huawei = [("platform/huawei-wmi/charge_control_thresholds", "40 70\n")];
+        assert_eq!(detect_with("huawei", &huawei).unwrap().backend, "huawei-wmi");
+
+        let samsung = [("platform/samsung/battery_life_extender", "0\n")];
+        let capabilities = detect_with("samsung", &samsung).unwrap();
+        assert_eq!(capabilities.backend, "samsung-laptop");
+        assert_eq!(capabilities.end_values, vec![80, 100]);
+
+        let behaviour = [("BAT0/charge_behaviour", "[auto] inhibit-charge force-discharge")];
+        let capabilities = detect_with("behaviour", &behaviour).unwrap();
+        assert_eq!(capabilities.backend, "charge_behaviour");
+        assert!(capabilities.emulated);
+
+        let discharge_only = [("BAT0/charge_behaviour", "[auto] force-discharge")];
+        assert!(detect_with("discharge", &discharge_only).is_none());
+        assert!(detect_with("none", &[]).is_none());
+    }
+
+    #[test]
+    fn emulated_hysteresis() {
+        let thresholds = (50, 80);
+
+        // Charging is inhibited once the capacity reaches the end threshold.
+        assert_eq!(emulated_behaviour("auto", 79, thresholds), None);
+        assert_eq!(emulated_behaviour("auto", 80, thresholds), Some("inhibit-charge"));
+        assert_eq!(emulated_behaviour("auto", 95, thresholds), Some("inhibit-charge"));
+
+        // And stays inhibited until the capacity falls to the start threshold.
+        assert_eq!(emulated_behaviour("inhibit-charge", 80, thresholds), None);
+        assert_eq!(emulated_behaviour("inhibit-charge", 51, thresholds), None);
+        assert_eq!(emulated_behaviour("inhibit-charge", 50, thresholds), Some("auto"));
+        assert_eq!(emulated_behaviour("inhibit-charge", 20, thresholds), Some("auto"));
+
+        // An end threshold of 100 never inhibits, and releases an inhibited battery.
+        assert_eq!(emulated_behaviour("auto", 100, (0, 100)), None);
+        assert_eq!(emulated_behaviour("inhibit-charge", 90, (0, 100)), Some("auto"));
+
+        // A forced discharge belongs to the user.
+        assert_eq!(emulated_behaviour("force-discharge", 90, thresholds), None);
+        assert_eq!(emulated_behaviour("force-discharge", 10, thresholds), None);
+    }
+
+    #[test]
+    fn platform_files() {
+        let dir =
+            std::env::temp_dir().join(format!("system76-power-platform{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let huawei = Huawei(dir.join("charge_control_thresholds"));
+        huawei.set((40, 70)).unwrap();
+        assert_eq!(huawei.get().unwrap(), (40, 70));
+
+        let samsung = Samsung(dir.join("battery_life_extender"));
+        samsung.set((0, 80)).unwrap();
+        assert_eq!(fs::read_to_string(&samsung.0).unwrap(), "1");
+        assert_eq!(samsung.get().unwrap(), (0, 80));
+        samsung.set((0, 100)).unwrap();
+        assert_eq!(samsung.get().unwrap(), (0, 100));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
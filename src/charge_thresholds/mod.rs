@@ -0,0 +1,380 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use crate::errors::ChargeThresholdError;
+use serde::Deserialize;
+use std::{fs, path::Path};
+use system76_power_zbus::{ChargeCapabilities, ChargeProfile};
+
+mod backends;
+use self::backends::ChargeBackend;
+
+const CHARGE_PROFILES_DIR: &str = "/etc/system76-power/charge-profiles.d";
+const SAVED_THRESHOLDS: &str = "/var/lib/system76-power/charge-thresholds.json";
+const UNSUPPORTED_ERROR: &str = "No supported charge threshold interface found";
+const OUT_OF_RANGE_ERROR: &str = "Charge threshold out of range: should be 0-100";
+const ORDER_ERROR: &str = "Charge end threshold must be strictly greater than start";
+
+/// Thresholds which charge the battery to full. Charging begins below the start threshold, so it
+/// must be close to the end threshold for the battery to charge regardless of its current level.
+pub(crate) const FULL_THRESHOLDS: (u8, u8) = (99, 100);
+
+fn backend() -> anyhow::Result<Box<dyn ChargeBackend + Send>> {
+    backends::detect().ok_or_else(|| anyhow::anyhow!(UNSUPPORTED_ERROR))
+}
+
+/// A charge profile defined by the administrator in `CHARGE_PROFILES_DIR`.
+///
+/// The ID defaults to the file stem, so `desk_docked.toml` defines `desk_docked`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChargeProfileConfig {
+    id:          Option<String>,
+    title:       Option<String>,
+    #[serde(default)]
+    description: String,
+    start:       u8,
+    end:         u8,
+}
+
+/// Checks that the thresholds are within range, and that charging stops after it starts.
+fn validate_thresholds(start: u8, end: u8) -> anyhow::Result<()> {
+    if start > 100 || end > 100 {
+        return Err(anyhow::anyhow!(OUT_OF_RANGE_ERROR));
+    } else if end <= start {
+        return Err(anyhow::anyhow!(ORDER_ERROR));
+    }
+
+    Ok(())
+}
+
+fn read_charge_profile(path: &Path) -> anyhow::Result<ChargeProfile> {
+    let config: ChargeProfileConfig = toml::from_str(&fs::read_to_string(path)?)?;
+    validate_thresholds(config.start, config.end)?;
+
+    let id = match config.id {
+        Some(id) => id,
+        None => path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| anyhow::anyhow!("file name is not valid UTF-8"))?
+            .to_owned(),
+    };
+
+    Ok(ChargeProfile {
+        title: config.title.unwrap_or_else(|| id.clone()),
+        id,
+        description: config.description,
+        start: config.start,
+        end: config.end,
+    })
+}
+
+/// Reads additional charge profiles from a directory, in file name order.
+///
+/// Invalid files are logged and skipped so that one bad file does not hide the rest.
+fn read_charge_profiles(dir: &Path) -> Vec<ChargeProfile> {
+    let Ok(dir) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut paths = dir
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .collect::<Vec<_>>();
+
+    paths.sort();
+
+    paths
+        .iter()
+        .filter_map(|path| match read_charge_profile(path) {
+            Ok(profile) => Some(profile),
+            Err(why) => {
+                log::warn!("ignoring charge profile {}: {}", path.display(), why);
+                None
+            }
+        })
+        .collect()
+}
+
+/// The built-in charge profiles, followed by those defined in `CHARGE_PROFILES_DIR`.
+///
+/// A profile with the same ID as a built-in profile replaces it.
+#[must_use]
+pub fn get_charge_profiles() -> Vec<ChargeProfile> {
+    charge_profiles_in(Path::new(CHARGE_PROFILES_DIR))
+}
+
+fn charge_profiles_in(dir: &Path) -> Vec<ChargeProfile> {
+    let mut profiles = default_charge_profiles();
+
+    for profile in read_charge_profiles(dir) {
+        match profiles.iter_mut().find(|p| p.id == profile.id) {
+            Some(existing) => *existing = profile,
+            None => profiles.push(profile),
+        }
+    }
+
+    profiles
+}
+
+fn default_charge_profiles() -> Vec<ChargeProfile> {
+    vec![
+        ChargeProfile {
+            id:          "full_charge".to_string(),
+            title:       "Full Charge".to_string(),
+            description: "Battery is charged to its full capacity for the longest possible use on \
+                          battery power. Charging resumes when the battery falls below 96% charge."
+                .to_string(),
+            start:       90,
+            end:         100,
+        },
+        ChargeProfile {
+            id:          "balanced".to_string(),
+            title:       "Balanced".to_string(),
+            description: "Use this threshold when you unplug frequently but don't need the full \
+                          battery capacity. Charging stops when the battery reaches 90% capacity \
+                          and resumes when the battery falls below 85%."
+                .to_string(),
+            start:       86,
+            end:         90,
+        },
+        ChargeProfile {
+            id:          "max_lifespan".to_string(),
+            title:       "Maximum Lifespan".to_string(),
+            description: "Use this threshold if you rarely use the system on battery for extended \
+                          periods. Charging stops when the battery reaches 60% capacity and \
+                          resumes when the battery falls below 50%."
+                .to_string(),
+            start:       50,
+            end:         60,
+        },
+    ]
+}
+
+/// Checks thresholds against what the charge threshold backend supports.
+///
+/// An unsupported start threshold must be zero, so that profiles can be applied by passing
+/// `(0, profile.end)`.
+pub fn check_capabilities(
+    capabilities: &ChargeCapabilities,
+    (start, end): (u8, u8),
+) -> anyhow::Result<()> {
+    let check = |name: &str, supported: bool, values: &[u8], value: u8| {
+        if !supported && value != 0 {
+            Err(anyhow::anyhow!("Charge {} threshold is not supported by this hardware", name))
+        } else if supported && !values.is_empty() && !values.contains(&value) {
+            Err(anyhow::anyhow!(
+                "Charge {} threshold of {} is not supported: should be one of {:?}",
+                name,
+                value,
+                values
+            ))
+        } else {
+            Ok(())
+        }
+    };
+
+    check("start", capabilities.start, &capabilities.start_values, start)?;
+    check("end", capabilities.end, &capabilities.end_values, end)
+}
+
+pub(crate) fn get_charge_capabilities() -> anyhow::Result<ChargeCapabilities> {
+    Ok(backend()?.capabilities())
+}
+
+pub(crate) fn get_charge_thresholds() -> anyhow::Result<(u8, u8)> { Ok(backend()?.get()?) }
+
+/// Sets the charge thresholds chosen by the user, and saves them so that they can be restored if
+/// the firmware resets them. They are only saved once the hardware reports them.
+///
+/// Returns the thresholds which were set, with the start threshold zeroed if it is not supported.
+pub(crate) fn set_charge_thresholds(thresholds: (u8, u8)) -> anyhow::Result<(u8, u8)> {
+    let thresholds = apply_thresholds(thresholds)?;
+    save_thresholds(thresholds);
+    Ok(thresholds)
+}
+
+/// Sets the charge thresholds without saving them, for features which change them temporarily,
+/// and checks that the hardware reports the same values afterwards.
+///
+/// Returns the thresholds which were set, with the start threshold zeroed if it is not supported.
+pub(crate) fn apply_thresholds(thresholds: (u8, u8)) -> anyhow::Result<(u8, u8)> {
+    let backend = backend()?;
+    let capabilities = backend.capabilities();
+    let thresholds = supported_thresholds(&capabilities, thresholds);
+
+    validate_thresholds(thresholds.0, thresholds.1)?;
+    check_capabilities(&capabilities, thresholds)?;
+    backend.set(thresholds)?;
+    verify_thresholds(&*backend, thresholds)?;
+
+    Ok(thresholds)
+}
+
+/// Only the end threshold matters when the start threshold is not supported.
+fn supported_thresholds(capabilities: &ChargeCapabilities, thresholds: (u8, u8)) -> (u8, u8) {
+    if capabilities.start {
+        thresholds
+    } else {
+        (0, thresholds.1)
+    }
+}
+
+/// Sets the charge thresholds again, if the hardware no longer reports them: those of a feature
+/// which changed them temporarily, if one is active, or else the saved thresholds.
+///
+/// Some firmware resets the thresholds on boot, resume, or when AC is plugged in. Returns the
+/// thresholds which were restored, if any.
+pub(crate) fn restore_charge_thresholds(
+    temporary: Option<(u8, u8)>,
+) -> anyhow::Result<Option<(u8, u8)>> {
+    let Some(thresholds) = temporary.or_else(load_thresholds) else {
+        return Ok(None);
+    };
+
+    let backend = backend()?;
+    let thresholds = supported_thresholds(&backend.capabilities(), thresholds);
+    if backend.get()? == thresholds {
+        return Ok(None);
+    }
+
+    log::info!("restoring charge thresholds {:?}", thresholds);
+    backend.set(thresholds)?;
+    verify_thresholds(&*backend, thresholds)?;
+
+    Ok(Some(thresholds))
+}
+
+/// Charge thresholds which are emulated by the daemon rather than the firmware, and must be
+/// enforced as the battery charges.
+pub(crate) struct EmulatedThresholds(Box<dyn ChargeBackend + Send>);
+
+impl EmulatedThresholds {
+    /// Finds the charge threshold backend, if the thresholds of this system are emulated.
+    pub(crate) fn detect() -> Option<Self> {
+        backends::detect().filter(|backend| backend.capabilities().emulated).map(Self)
+    }
+
+    pub(crate) fn enforce(&self) -> anyhow::Result<()> { Ok(self.0.enforce()?) }
+}
+
+/// Reads the thresholds back, since some firmware silently clamps them.
+fn verify_thresholds(backend: &dyn ChargeBackend, requested: (u8, u8)) -> anyhow::Result<()> {
+    let actual = backend.get()?;
+    if actual != requested {
+        return Err(ChargeThresholdError::Mismatch { requested, actual }.into());
+    }
+
+    Ok(())
+}
+
+fn load_thresholds() -> Option<(u8, u8)> {
+    let thresholds = fs::read_to_string(SAVED_THRESHOLDS).ok()?;
+    match serde_json::from_str(&thresholds) {
+        Ok(thresholds) => Some(thresholds),
+        Err(why) => {
+            log::warn!("ignoring invalid saved charge thresholds: {}", why);
+            None
+        }
+    }
+}
+
+fn save_thresholds(thresholds: (u8, u8)) {
+    let result = Path::new(SAVED_THRESHOLDS)
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(SAVED_THRESHOLDS, serde_json::to_string(&thresholds)?));
+
+    if let Err(why) = result {
+        log::error!("failed to save charge thresholds to {}: {}", SAVED_THRESHOLDS, why);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_order() {
+        assert!(validate_thresholds(50, 60).is_ok());
+        assert!(validate_thresholds(0, 100).is_ok());
+        assert!(validate_thresholds(60, 60).is_err());
+        assert!(validate_thresholds(80, 60).is_err());
+        assert!(validate_thresholds(50, 101).is_err());
+        assert!(validate_thresholds(101, 100).is_err());
+    }
+
+    #[test]
+    fn capability_checks() {
+        let dell = ChargeCapabilities {
+            start: true,
+            end: true,
+            start_values: (50..=95).collect(),
+            end_values: (55..=100).collect(),
+            ..ChargeCapabilities::default()
+        };
+        assert!(check_capabilities(&dell, (50, 55)).is_ok());
+        assert!(check_capabilities(&dell, (95, 100)).is_ok());
+        assert!(check_capabilities(&dell, (40, 80)).is_err());
+        assert!(check_capabilities(&dell, (60, 50)).is_err());
+
+        let lg = ChargeCapabilities {
+            end: true,
+            end_values: vec![80, 100],
+            ..ChargeCapabilities::default()
+        };
+        assert!(check_capabilities(&lg, (0, 80)).is_ok());
+        assert!(check_capabilities(&lg, (0, 90)).is_err());
+        // A start threshold is rejected by end-only hardware, unless it was zeroed.
+        assert!(check_capabilities(&lg, (70, 80)).is_err());
+        assert_eq!(supported_thresholds(&lg, (70, 80)), (0, 80));
+        assert!(check_capabilities(&lg, supported_thresholds(&lg, (70, 80))).is_ok());
+
+        let any = ChargeCapabilities { start: true, end: true, ..ChargeCapabilities::default() };
+        assert!(check_capabilities(&any, (13, 67)).is_ok());
+    }
+
+    #[test]
+    fn admin_profiles() {
+        let dir = std::env::temp_dir()
+            .join(format!("system76-power-charge-profiles{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = [
+            ("desk_docked.toml", "start = 40\nend = 50\n"),
+            ("travel.toml", "id = \"full_charge\"\ntitle = \"Travel\"\nstart = 95\nend = 100\n"),
+            ("equal.toml", "start = 60\nend = 60\n"),
+            ("over.toml", "start = 90\nend = 101\n"),
+            ("unknown.toml", "start = 40\nend = 50\ncolour = \"red\"\n"),
+            ("notes.txt", "start = 40\nend = 50\n"),
+        ];
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let profiles = charge_profiles_in(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let summary = profiles
+            .iter()
+            .map(|p| (p.id.as_str(), p.title.as_str(), p.start, p.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("full_charge", "Travel", 95, 100),
+                ("balanced", "Balanced", 86, 90),
+                ("max_lifespan", "Maximum Lifespan", 50, 60),
+                ("desk_docked", "desk_docked", 40, 50),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_profile_dir() {
+        let dir = std::env::temp_dir().join("system76-power-no-charge-profiles");
+        assert_eq!(charge_profiles_in(&dir).len(), default_charge_profiles().len());
+    }
+}
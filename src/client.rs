@@ -2,12 +2,23 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use crate::args::{Args, GraphicsArgs};
+use crate::{
+    args::{Args, BatteryArgs, FanArgs, FanCurveArgs, GraphicsArgs},
+    charge_thresholds::check_capabilities,
+    fan::{simulate, FanConfig, FanCurve},
+};
 use anyhow::Context;
+use futures_lite::StreamExt;
 use intel_pstate::PState;
-use std::io;
+use std::{
+    fs, io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use sysfs_class::{Backlight, Brightness, Leds, SysClass};
-use system76_power_zbus::PowerDaemonProxy;
+use system76_power_zbus::{
+    BatteryInfo, FanCalibrationInfo, FanProxy, PowerDaemonProxy, ThermalSample,
+};
 
 async fn profile(client: &mut PowerDaemonProxy<'_>) -> io::Result<()> {
     let profile = client.get_profile().await.ok();
@@ -50,8 +61,331 @@ async fn profile(client: &mut PowerDaemonProxy<'_>) -> io::Result<()> {
     Ok(())
 }
 
+fn battery_info(battery: &BatteryInfo) {
+    let unit = &battery.capacity_unit;
+
+    println!("Battery {}: {} {}", battery.name, battery.manufacturer, battery.model);
+    println!("  Technology: {}", battery.technology);
+    println!("  Status: {}", battery.status);
+    println!(
+        "  Capacity: {:.2} / {:.2} {} (design {:.2} {})",
+        battery.capacity, battery.full_capacity, unit, battery.design_capacity, unit
+    );
+    if battery.design_capacity > 0.0 {
+        println!("  Health: {:.1}% ({:.1}% wear)", 100.0 - battery.wear, battery.wear);
+    }
+    if battery.cycle_count > 0 {
+        println!("  Cycle Count: {}", battery.cycle_count);
+    }
+    println!("  Power: {:.2} W", battery.power);
+    if battery.seconds_to_empty > 0 {
+        println!("  Time to Empty: {}", duration(battery.seconds_to_empty));
+    }
+    if battery.seconds_to_full > 0 {
+        println!("  Time to Full: {}", duration(battery.seconds_to_full));
+    }
+}
+
+fn duration(seconds: u64) -> String { format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60) }
+
+/// Starts calibrating the battery, if it is not already, and follows the progress.
+async fn calibrate(client: &mut PowerDaemonProxy<'_>) -> anyhow::Result<()> {
+    let mut progress = client.receive_calibration_progress().await?;
+    let mut finished = client.receive_calibration_finished().await?;
+
+    let (stage, message) = client.get_calibration().await.map_err(zbus_error)?;
+    if stage.is_empty() {
+        client.start_calibration().await.map_err(zbus_error)?;
+    } else {
+        println!("{}", message);
+    }
+
+    println!(
+        "Calibration continues in the background if this command is stopped. Run `system76-power \
+         battery calibrate --cancel` to cancel it."
+    );
+
+    loop {
+        tokio::select! {
+            Some(signal) = progress.next() => println!("{}", signal.args()?.message),
+            Some(signal) = finished.next() => {
+                println!("Calibration finished");
+                battery_info(&signal.args()?.battery);
+                return Ok(());
+            }
+            else => return Ok(()),
+        }
+    }
+}
+
+async fn fan(connection: &zbus::Connection, args: &FanArgs) -> anyhow::Result<()> {
+    let client =
+        FanProxy::new(connection).await.context("failed to connect to system76-power daemon")?;
+
+    match args {
+        FanArgs::Curve { cmd: FanCurveArgs::Get } => {
+            let (name, points) = client.get_curve().await.map_err(zbus_error)?;
+            println!("Fan curve: {}", name);
+            for (temp, duty) in points {
+                println!("  {}°C: {}%", f32::from(temp) / 100.0, f32::from(duty) / 100.0);
+            }
+            Ok(())
+        }
+        FanArgs::Curve { cmd: FanCurveArgs::List } => {
+            for name in client.get_curves().await.map_err(zbus_error)? {
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        FanArgs::Curve { cmd: FanCurveArgs::Set { name, .. } } => {
+            client.set_curve(name.as_deref().unwrap_or_default()).await.map_err(zbus_error)
+        }
+        FanArgs::Status => {
+            match client.get_temperature().await.map_err(zbus_error)? {
+                0 => println!("Temperature: unknown"),
+                temp => println!("Temperature: {}°C", f64::from(temp) / 1000.0),
+            }
+
+            let (curve, _) = client.get_curve().await.map_err(zbus_error)?;
+            println!("Fan curve: {}", curve);
+
+            let duties = client.get_duties().await.map_err(zbus_error)?;
+            if duties.is_empty() {
+                println!("Fans are controlled by the firmware");
+            }
+
+            for (channel, duty) in duties {
+                println!("Fan {} duty: {}%", channel, f32::from(duty) / 100.0);
+            }
+
+            for (channel, rpm) in client.get_speeds().await.map_err(zbus_error)? {
+                println!("Fan {} speed: {} RPM", channel, rpm);
+            }
+
+            let (calibrating, progress, calibrations) =
+                client.get_calibration().await.map_err(zbus_error)?;
+            if calibrating {
+                println!("Fan calibration: {}% done", progress);
+            }
+
+            fan_calibration(&calibrations);
+
+            Ok(())
+        }
+        FanArgs::Duty { percent, timeout, auto } => {
+            let timeout = if *auto { 0 } else { *timeout };
+            client.set_manual_duty(percent.unwrap_or(0), timeout).await.map_err(zbus_error)
+        }
+        FanArgs::Calibrate { cancel: true } => {
+            client.cancel_calibration().await.map_err(zbus_error)
+        }
+        FanArgs::Calibrate { cancel: false } => calibrate_fans(&client).await,
+        FanArgs::Simulate { curve, trace } => simulate_fan(curve, trace.as_deref()),
+        FanArgs::History { csv, since, resolution } => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs());
+            let since = now.saturating_sub(since * 60);
+            let history =
+                client.get_thermal_history(since, *resolution).await.map_err(zbus_error)?;
+
+            if *csv {
+                thermal_history_csv(&history);
+            } else if history.is_empty() {
+                println!("No history has been recorded yet");
+            } else {
+                for sample in &history {
+                    thermal_sample(now, sample);
+                }
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Prints one period of the thermal history.
+fn thermal_sample(now: u64, sample: &ThermalSample) {
+    let ago = now.saturating_sub(sample.time);
+    let temperature = match sample.temperature {
+        0 => "unknown".to_owned(),
+        temp => format!("{}°C", f64::from(temp) / 1000.0),
+    };
+
+    let mut line = format!(
+        "{}h {:02}m {:02}s ago: {}, {}",
+        ago / 3600,
+        ago % 3600 / 60,
+        ago % 60,
+        temperature,
+        sample.profile
+    );
+
+    if sample.duties.is_empty() {
+        line.push_str(", fans controlled by the firmware");
+    }
+
+    for &(channel, duty) in &sample.duties {
+        line.push_str(&format!(", fan {} at {}%", channel, f32::from(duty) / 100.0));
+        if let Some((_, rpm)) = sample.speeds.iter().find(|&&(fan, _)| fan == channel) {
+            line.push_str(&format!(" and {} RPM", rpm));
+        }
+    }
+
+    println!("{}", line);
+}
+
+/// Prints the thermal history as CSV, with a column for each sensor and fan that was seen.
+fn thermal_history_csv(history: &[ThermalSample]) {
+    let mut sensors: Vec<&str> = Vec::new();
+    let mut channels: Vec<u8> = Vec::new();
+    for sample in history {
+        for (name, _) in &sample.sensors {
+            if !sensors.contains(&name.as_str()) {
+                sensors.push(name);
+            }
+        }
+
+        let fans = sample.duties.iter().map(|&(channel, _)| channel);
+        for channel in fans.chain(sample.speeds.iter().map(|&(channel, _)| channel)) {
+            if !channels.contains(&channel) {
+                channels.push(channel);
+            }
+        }
+    }
+    channels.sort_unstable();
+
+    let mut header = vec!["time".to_owned(), "temperature".to_owned(), "profile".to_owned()];
+    header.extend(sensors.iter().map(|name| format!("{}_temperature", name)));
+    header.extend(channels.iter().map(|channel| format!("fan{}_duty", channel)));
+    header.extend(channels.iter().map(|channel| format!("fan{}_rpm", channel)));
+    println!("{}", header.join(","));
+
+    let temp = |temp: u32| format!("{}", f64::from(temp) / 1000.0);
+    for sample in history {
+        let mut row = vec![
+            sample.time.to_string(),
+            match sample.temperature {
+                0 => String::new(),
+                temperature => temp(temperature),
+            },
+            sample.profile.clone(),
+        ];
+
+        row.extend(sensors.iter().map(|name| {
+            let reading = sample.sensors.iter().find(|(sensor, _)| sensor == name);
+            reading.map_or(String::new(), |&(_, reading)| temp(reading))
+        }));
+        row.extend(channels.iter().map(|channel| {
+            let duty = sample.duties.iter().find(|&(fan, _)| fan == channel);
+            duty.map_or(String::new(), |&(_, duty)| format!("{}", f32::from(duty) / 100.0))
+        }));
+        row.extend(channels.iter().map(|channel| {
+            let rpm = sample.speeds.iter().find(|&(fan, _)| fan == channel);
+            rpm.map_or(String::new(), |&(_, rpm)| rpm.to_string())
+        }));
+
+        println!("{}", row.join(","));
+    }
+}
+
+fn fan_calibration(calibrations: &[FanCalibrationInfo]) {
+    for fan in calibrations {
+        println!(
+            "Fan {} starts at {}%, and runs at up to {} RPM",
+            fan.channel,
+            f32::from(fan.min_duty) / 100.0,
+            fan.max_rpm
+        );
+    }
+}
+
+/// Starts calibrating the fans, if they are not already, and waits for the results.
+async fn calibrate_fans(client: &FanProxy<'_>) -> anyhow::Result<()> {
+    let mut calibrated = client.receive_fan_calibrated().await?;
+
+    let (calibrating, progress, _) = client.get_calibration().await.map_err(zbus_error)?;
+    if calibrating {
+        println!("Fan calibration is {}% done", progress);
+    } else {
+        client.calibrate().await.map_err(zbus_error)?;
+        println!("Calibrating fans, which takes about two minutes");
+    }
+
+    println!(
+        "Calibration continues in the background if this command is stopped. Run `system76-power \
+         fan calibrate --cancel` to cancel it."
+    );
+
+    if let Some(signal) = calibrated.next().await {
+        let calibrations = signal.args()?.calibrations;
+        if calibrations.is_empty() {
+            return Err(anyhow::anyhow!("Fan calibration was cancelled"));
+        }
+
+        println!("Calibration finished");
+        fan_calibration(&calibrations);
+    }
+
+    Ok(())
+}
+
+/// Prints the duties of a fan curve as CSV, without the daemon.
+fn simulate_fan(curve: &str, trace: Option<&Path>) -> anyhow::Result<()> {
+    let curve = if Path::new(curve).is_file() {
+        FanConfig::curve_file(Path::new(curve))?
+    } else if curve.contains(':') {
+        curve.parse::<FanCurve>()?
+    } else {
+        FanConfig::load()?.curve(curve)?
+    };
+
+    let duty = |duty: Option<u16>| {
+        duty.map_or(String::new(), |duty| format!("{}", f32::from(duty) / 100.0))
+    };
+
+    match trace {
+        Some(trace) => {
+            let csv = fs::read_to_string(trace)
+                .with_context(|| format!("failed to read {}", trace.display()))?;
+            let trace = simulate::parse_trace(&csv)
+                .with_context(|| format!("failed to parse {}", trace.display()))?;
+
+            println!("time,temperature,target,duty,pwm");
+            for sample in simulate::replay(&curve, &trace) {
+                println!(
+                    "{},{},{},{},{}",
+                    sample.time,
+                    sample.temp,
+                    duty(sample.target),
+                    duty(sample.duty),
+                    sample.pwm().map_or(String::new(), |pwm| pwm.to_string())
+                );
+            }
+        }
+        None => {
+            println!("temperature,duty,pwm");
+            for sample in simulate::sweep(&curve) {
+                println!(
+                    "{},{},{}",
+                    sample.temp,
+                    duty(sample.duty),
+                    sample.pwm().map_or(String::new(), |pwm| pwm.to_string())
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[tokio::main(flavor = "current_thread")]
 pub async fn client(args: &Args) -> anyhow::Result<()> {
+    // Simulating a curve needs neither the daemon nor root.
+    if let Args::Fan { cmd: FanArgs::Simulate { curve, trace } } = args {
+        return simulate_fan(curve, trace.as_deref());
+    }
+
     let connection =
         zbus::Connection::system().await.context("failed to create zbus system connection")?;
 
@@ -122,7 +456,78 @@ this device is either a desktop or doesn't have both an iGPU and dGPU.
                 }
             }
         }
-        Args::ChargeThresholds { profile, list_profiles, thresholds } => {
+        Args::Battery { cmd: Some(BatteryArgs::Calibrate { cancel }) } => {
+            if *cancel {
+                return client.cancel_calibration().await.map_err(zbus_error);
+            }
+
+            calibrate(&mut client).await
+        }
+        Args::Battery { cmd: None } => {
+            let batteries = client.get_battery_info().await.map_err(zbus_error)?;
+            if batteries.is_empty() {
+                return Err(anyhow::anyhow!("No battery found"));
+            }
+
+            for battery in &batteries {
+                battery_info(battery);
+            }
+
+            Ok(())
+        }
+        Args::Fan { cmd } => fan(&connection, cmd).await,
+        Args::ChargeBehaviour { behaviour, timeout } => {
+            let (selected, choices) = client.get_charge_behaviour().await.map_err(zbus_error)?;
+
+            match behaviour {
+                Some(behaviour) => {
+                    if !choices.contains(behaviour) {
+                        return Err(anyhow::anyhow!(
+                            "Charge behaviour '{}' is not supported by this battery, expected one \
+                             of: {}",
+                            behaviour,
+                            choices.join(", ")
+                        ));
+                    }
+
+                    client
+                        .set_charge_behaviour(behaviour, timeout.unwrap_or(0))
+                        .await
+                        .map_err(zbus_error)
+                }
+                None => {
+                    println!("Charge Behaviour: {}", selected);
+                    println!("Supported: {}", choices.join(", "));
+                    Ok(())
+                }
+            }
+        }
+        Args::SmartCharging { state } => match state.as_deref() {
+            Some(state) => client.set_smart_charging(state == "on").await.map_err(zbus_error),
+            None => {
+                if !client.get_smart_charging().await.map_err(zbus_error)? {
+                    println!("Smart charging: off");
+                    return Ok(());
+                }
+
+                println!("Smart charging: on");
+                match client.get_next_full_charge().await.map_err(zbus_error)? {
+                    0 => println!("Next full charge: not predicted yet"),
+                    timestamp => {
+                        let now = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map_or(0, |duration| duration.as_secs());
+                        println!(
+                            "Next full charge: in {}",
+                            duration(timestamp.saturating_sub(now))
+                        );
+                    }
+                }
+
+                Ok(())
+            }
+        },
+        Args::ChargeThresholds { profile, list_profiles, full_once, thresholds } => {
             if client.get_desktop().await.map_err(zbus_error)? {
                 return Err(anyhow::anyhow!(
                     r#"
@@ -134,18 +539,31 @@ Charge thresholds are not supported on desktop computers.
             let profiles = client.get_charge_profiles().await.map_err(zbus_error)?;
 
             if !thresholds.is_empty() {
-                let start = thresholds[0];
-                let end = thresholds[1];
-                client.set_charge_thresholds(&(start, end)).await.map_err(zbus_error)?;
+                let capabilities = client.get_charge_capabilities().await.map_err(zbus_error)?;
+                let thresholds = (thresholds[0], thresholds[1]);
+                check_capabilities(&capabilities, thresholds)?;
+                client.set_charge_thresholds(&thresholds).await.map_err(zbus_error)?;
             } else if let Some(name) = profile {
                 if let Some(profile) = profiles.iter().find(|p| &p.id == name) {
+                    let capabilities =
+                        client.get_charge_capabilities().await.map_err(zbus_error)?;
+                    let start = if capabilities.start { profile.start } else { 0 };
+                    check_capabilities(&capabilities, (start, profile.end))
+                        .with_context(|| format!("cannot apply profile '{}'", profile.id))?;
                     client
-                        .set_charge_thresholds(&(profile.start, profile.end))
+                        .set_charge_thresholds(&(start, profile.end))
                         .await
                         .map_err(zbus_error)?;
                 } else {
-                    return Err(anyhow::anyhow!("No such profile '{}'", name));
+                    let ids = profiles.iter().map(|p| p.id.as_str()).collect::<Vec<_>>();
+                    return Err(anyhow::anyhow!(
+                        "No such profile '{}', expected one of: {}",
+                        name,
+                        ids.join(", ")
+                    ));
                 }
+            } else if *full_once {
+                client.charge_to_full_once().await.map_err(zbus_error)?;
             } else if *list_profiles {
                 for profile in &profiles {
                     println!("{}", profile.id);
@@ -158,13 +576,22 @@ Charge thresholds are not supported on desktop computers.
             }
 
             let (start, end) = client.get_charge_thresholds().await.map_err(zbus_error)?;
-            if let Some(profile) = profiles.iter().find(|p| p.start == start && p.end == end) {
+            let capabilities = client.get_charge_capabilities().await.ok();
+            let start_supported = capabilities.as_ref().map_or(true, |c| c.start);
+            if let Some(profile) =
+                profiles.iter().find(|p| (!start_supported || p.start == start) && p.end == end)
+            {
                 println!("Profile: {} ({})", profile.title, profile.id);
             } else {
                 println!("Profile: Custom");
             }
-            println!("Start: {}", start);
+            if start_supported {
+                println!("Start: {}", start);
+            }
             println!("End: {}", end);
+            if capabilities.is_some_and(|c| c.emulated) {
+                println!("Emulated: charging is paused by the daemon at the end threshold");
+            }
 
             Ok(())
         }
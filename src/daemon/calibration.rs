@@ -0,0 +1,482 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Recalibrates the battery fuel gauge with a full charge, a deep discharge and a recharge.
+//!
+//! Fuel gauges drift when the battery is kept partially charged for a long time, which makes the
+//! reported capacity and time estimates unreliable.
+//!
+//! The calibration is stored on disk, so that a daemon restart in the middle of it resumes it,
+//! and still restores the thresholds and charge behaviour afterwards.
+
+use crate::{
+    charge_thresholds::{apply_thresholds, FULL_THRESHOLDS},
+    errors::ChargeThresholdError,
+    power_supply::{self, battery_capacity, battery_status},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use system76_power_zbus::BatteryInfo;
+
+const STATE_PATH: &str = "/var/lib/system76-power/battery-calibration.json";
+
+/// The charge level, in percent, that the battery is discharged to.
+const LOW_CAPACITY: u8 = 10;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+enum Stage {
+    Charging,
+    Discharging,
+    Recharging,
+}
+
+impl Stage {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Charging => "charging",
+            Self::Discharging => "discharging",
+            Self::Recharging => "recharging",
+        }
+    }
+}
+
+/// The battery and charge thresholds that a calibration observes and changes.
+pub trait CalibrationSystem {
+    /// The battery to calibrate.
+    fn battery(&self) -> Option<PathBuf>;
+
+    fn status(&self) -> Option<String>;
+
+    fn capacity(&self) -> Option<u8>;
+
+    fn info(&self, battery: &Path) -> BatteryInfo;
+
+    /// The charge behaviours the battery supports, and the one which is selected.
+    fn behaviours(&self, battery: &Path) -> Option<(Vec<String>, String)>;
+
+    fn set_behaviour(&self, battery: &Path, behaviour: &str) -> io::Result<()>;
+
+    /// Sets the charge thresholds without saving them.
+    fn apply_thresholds(&self, thresholds: (u8, u8)) -> anyhow::Result<(u8, u8)>;
+
+    /// Where the calibration is stored while it is in progress.
+    fn state_path(&self) -> &Path;
+}
+
+/// The batteries and charge thresholds of this system.
+pub struct Sysfs;
+
+impl CalibrationSystem for Sysfs {
+    fn battery(&self) -> Option<PathBuf> { power_supply::battery() }
+
+    fn status(&self) -> Option<String> { battery_status() }
+
+    fn capacity(&self) -> Option<u8> { battery_capacity() }
+
+    fn info(&self, battery: &Path) -> BatteryInfo { power_supply::battery_info(battery) }
+
+    fn behaviours(&self, battery: &Path) -> Option<(Vec<String>, String)> {
+        power_supply::charge_behaviours(battery)
+    }
+
+    fn set_behaviour(&self, battery: &Path, behaviour: &str) -> io::Result<()> {
+        power_supply::set_charge_behaviour(battery, behaviour)
+    }
+
+    fn apply_thresholds(&self, thresholds: (u8, u8)) -> anyhow::Result<(u8, u8)> {
+        apply_thresholds(thresholds)
+    }
+
+    fn state_path(&self) -> &Path { Path::new(STATE_PATH) }
+}
+
+pub enum CalibrationEvent {
+    /// The calibration moved to a new stage, named by the first field.
+    Progress(&'static str, String),
+    /// The battery was recharged and the thresholds restored.
+    Finished(BatteryInfo),
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Calibration<S = Sysfs> {
+    stage:    Stage,
+    message:  String,
+    previous: (u8, u8),
+    /// The battery to force discharging, if its `charge_behaviour` supports it.
+    force:    Option<PathBuf>,
+    #[serde(skip)]
+    system:   S,
+}
+
+impl Calibration {
+    /// Raises the charge thresholds so that the battery charges to full.
+    ///
+    /// `previous` are the thresholds to restore when calibration finishes or is cancelled.
+    pub fn start(previous: (u8, u8)) -> anyhow::Result<(Self, CalibrationEvent)> {
+        Self::start_with(Sysfs, previous)
+    }
+
+    /// Resumes a calibration which was in progress when the daemon last stopped.
+    pub fn load() -> Option<Self> { Self::load_with(Sysfs) }
+}
+
+impl<S: CalibrationSystem> Calibration<S> {
+    pub fn start_with(system: S, previous: (u8, u8)) -> anyhow::Result<(Self, CalibrationEvent)> {
+        let battery = system.battery().ok_or_else(|| anyhow::anyhow!("no battery found"))?;
+
+        let force = system
+            .behaviours(&battery)
+            .filter(|(choices, _)| choices.iter().any(|choice| choice == "force-discharge"))
+            .map(|_| battery);
+
+        let mut calibration =
+            Self { stage: Stage::Charging, message: String::new(), previous, force, system };
+        calibration.save()?;
+
+        match calibration.system.apply_thresholds(FULL_THRESHOLDS) {
+            Ok(_) => (),
+            Err(why) if why.is::<ChargeThresholdError>() => log::warn!("{}", why),
+            Err(why) => {
+                calibration.discard();
+                return Err(why);
+            }
+        }
+
+        log::info!("battery calibration started, then restoring {:?}", previous);
+        let event = calibration.progress(Stage::Charging, "Charging the battery to full");
+        Ok((calibration, event))
+    }
+
+    /// Resumes a calibration which was in progress when the daemon last stopped. The battery is
+    /// forced to discharge again if it was, since the charge behaviour is reset on boot.
+    pub fn load_with(system: S) -> Option<Self> {
+        let state = fs::read_to_string(system.state_path()).ok()?;
+
+        match serde_json::from_str::<Calibration<()>>(&state) {
+            Ok(Calibration { stage, message, previous, force, .. }) => {
+                let calibration = Self { stage, message, previous, force, system };
+                log::info!(
+                    "resuming battery calibration during {}, then restoring {:?}",
+                    calibration.stage.as_str(),
+                    calibration.previous
+                );
+
+                if calibration.stage == Stage::Discharging {
+                    calibration.set_behaviour("force-discharge");
+                }
+
+                Some(calibration)
+            }
+            Err(why) => {
+                log::warn!("discarding invalid battery calibration state: {}", why);
+                remove_state(system.state_path());
+                None
+            }
+        }
+    }
+
+    /// The current stage and the instructions for the user.
+    pub fn status(&self) -> (&'static str, &str) { (self.stage.as_str(), &self.message) }
+
+    /// Advances the calibration when the battery reaches the level the current stage waits for.
+    pub fn step(&mut self) -> Option<CalibrationEvent> {
+        match self.stage {
+            Stage::Charging if self.system.status().as_deref() == Some("Full") => {
+                let message = if self.force.is_some() {
+                    self.set_behaviour("force-discharge");
+                    format!("Discharging the battery to {}%", LOW_CAPACITY)
+                } else {
+                    format!(
+                        "Unplug the AC adapter, and use the computer until the battery reaches {}%",
+                        LOW_CAPACITY
+                    )
+                };
+
+                Some(self.progress(Stage::Discharging, &message))
+            }
+            Stage::Discharging
+                if self.system.capacity().is_some_and(|capacity| capacity <= LOW_CAPACITY) =>
+            {
+                let message = if self.force.is_some() {
+                    self.set_behaviour("auto");
+                    "Recharging the battery to full"
+                } else {
+                    "Plug in the AC adapter to recharge the battery to full"
+                };
+
+                Some(self.progress(Stage::Recharging, message))
+            }
+            Stage::Recharging if self.system.status().as_deref() == Some("Full") => {
+                self.restore();
+
+                let info = self
+                    .system
+                    .battery()
+                    .map(|battery| self.system.info(&battery))
+                    .unwrap_or_default();
+
+                log::info!(
+                    "battery calibration finished: {:.2} of {:.2} {} design capacity",
+                    info.full_capacity,
+                    info.design_capacity,
+                    info.capacity_unit
+                );
+
+                Some(CalibrationEvent::Finished(info))
+            }
+            _ => None,
+        }
+    }
+
+    /// Stops calibrating, and restores the previous charge behaviour and thresholds.
+    pub fn cancel(self) {
+        log::info!("battery calibration cancelled during {}", self.stage.as_str());
+        self.restore();
+    }
+
+    /// Leaves the calibration to be resumed when the daemon starts again, without leaving the
+    /// battery forced to discharge in the meantime.
+    pub fn pause(&self) {
+        if self.stage == Stage::Discharging {
+            self.set_behaviour("auto");
+        }
+    }
+
+    fn restore(&self) {
+        if self.stage == Stage::Discharging {
+            self.set_behaviour("auto");
+        }
+
+        if let Err(why) = self.system.apply_thresholds(self.previous) {
+            log::error!("failed to restore charge thresholds after calibration: {}", why);
+        }
+
+        self.discard();
+    }
+
+    fn discard(&self) { remove_state(self.system.state_path()); }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let path = self.system.state_path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    fn set_behaviour(&self, behaviour: &str) {
+        if let Some(ref battery) = self.force {
+            if let Err(why) = self.system.set_behaviour(battery, behaviour) {
+                log::error!("failed to set charge behaviour to {}: {}", behaviour, why);
+            }
+        }
+    }
+
+    fn progress(&mut self, stage: Stage, message: &str) -> CalibrationEvent {
+        log::info!("battery calibration: {}", message);
+        self.stage = stage;
+        self.message = message.to_owned();
+        if let Err(why) = self.save() {
+            log::error!("failed to save battery calibration state: {}", why);
+        }
+
+        CalibrationEvent::Progress(stage.as_str(), self.message.clone())
+    }
+}
+
+fn remove_state(path: &Path) {
+    if path.exists() {
+        if let Err(why) = fs::remove_file(path) {
+            log::error!("failed to remove {}: {}", path.display(), why);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[derive(Debug)]
+    struct Battery {
+        status:     &'static str,
+        capacity:   u8,
+        behaviours: Vec<String>,
+        behaviour:  String,
+        thresholds: (u8, u8),
+    }
+
+    /// A battery which is charged and discharged by the test, with its state file in a temporary
+    /// directory. Clones share the battery, as if the daemon had restarted.
+    #[derive(Clone)]
+    struct SimulatedBattery {
+        battery: Rc<RefCell<Battery>>,
+        state:   PathBuf,
+    }
+
+    impl SimulatedBattery {
+        fn new(name: &str, behaviours: &[&str]) -> Self {
+            let state = std::env::temp_dir().join(format!(
+                "system76-power-{}{}.json",
+                name,
+                std::process::id()
+            ));
+            let battery = Battery {
+                status:     "Charging",
+                capacity:   60,
+                behaviours: behaviours.iter().map(|&behaviour| behaviour.to_owned()).collect(),
+                behaviour:  "auto".to_owned(),
+                thresholds: (40, 80),
+            };
+
+            Self { battery: Rc::new(RefCell::new(battery)), state }
+        }
+
+        fn set(&self, status: &'static str, capacity: u8) {
+            let mut battery = self.battery.borrow_mut();
+            battery.status = status;
+            battery.capacity = capacity;
+        }
+
+        fn behaviour(&self) -> String { self.battery.borrow().behaviour.clone() }
+
+        fn thresholds(&self) -> (u8, u8) { self.battery.borrow().thresholds }
+    }
+
+    impl CalibrationSystem for SimulatedBattery {
+        fn battery(&self) -> Option<PathBuf> { Some(PathBuf::from("BAT0")) }
+
+        fn status(&self) -> Option<String> { Some(self.battery.borrow().status.to_owned()) }
+
+        fn capacity(&self) -> Option<u8> { Some(self.battery.borrow().capacity) }
+
+        fn info(&self, _battery: &Path) -> BatteryInfo {
+            BatteryInfo { full_capacity: 45.0, design_capacity: 50.0, ..BatteryInfo::default() }
+        }
+
+        fn behaviours(&self, _battery: &Path) -> Option<(Vec<String>, String)> {
+            let battery = self.battery.borrow();
+            (!battery.behaviours.is_empty())
+                .then(|| (battery.behaviours.clone(), battery.behaviour.clone()))
+        }
+
+        fn set_behaviour(&self, _battery: &Path, behaviour: &str) -> io::Result<()> {
+            self.battery.borrow_mut().behaviour = behaviour.to_owned();
+            Ok(())
+        }
+
+        fn apply_thresholds(&self, thresholds: (u8, u8)) -> anyhow::Result<(u8, u8)> {
+            self.battery.borrow_mut().thresholds = thresholds;
+            Ok(thresholds)
+        }
+
+        fn state_path(&self) -> &Path { &self.state }
+    }
+
+    fn stage(event: Option<CalibrationEvent>) -> Option<&'static str> {
+        match event? {
+            CalibrationEvent::Progress(stage, _) => Some(stage),
+            CalibrationEvent::Finished(_) => Some("finished"),
+        }
+    }
+
+    #[test]
+    fn forced_discharge() {
+        let battery = SimulatedBattery::new("forced-calibration", &["auto", "force-discharge"]);
+        let (mut calibration, event) = Calibration::start_with(battery.clone(), (40, 80)).unwrap();
+        assert_eq!(stage(Some(event)), Some("charging"));
+        assert_eq!(battery.thresholds(), FULL_THRESHOLDS);
+        assert!(battery.state.exists());
+
+        assert_eq!(stage(calibration.step()), None);
+
+        battery.set("Full", 100);
+        assert_eq!(stage(calibration.step()), Some("discharging"));
+        assert_eq!(battery.behaviour(), "force-discharge");
+
+        battery.set("Discharging", 11);
+        assert_eq!(stage(calibration.step()), None);
+
+        battery.set("Discharging", 10);
+        assert_eq!(stage(calibration.step()), Some("recharging"));
+        assert_eq!(battery.behaviour(), "auto");
+
+        battery.set("Charging", 90);
+        assert_eq!(stage(calibration.step()), None);
+
+        battery.set("Full", 100);
+        match calibration.step() {
+            Some(CalibrationEvent::Finished(info)) => assert_eq!(info.full_capacity, 45.0),
+            _ => panic!("calibration did not finish"),
+        }
+
+        assert_eq!(battery.thresholds(), (40, 80));
+        assert!(!battery.state.exists());
+    }
+
+    #[test]
+    fn manual_discharge() {
+        let battery = SimulatedBattery::new("manual-calibration", &[]);
+        let (mut calibration, _) = Calibration::start_with(battery.clone(), (40, 80)).unwrap();
+
+        battery.set("Full", 100);
+        assert_eq!(stage(calibration.step()), Some("discharging"));
+        assert!(calibration.status().1.starts_with("Unplug the AC adapter"));
+
+        battery.set("Discharging", 5);
+        assert_eq!(stage(calibration.step()), Some("recharging"));
+        assert!(calibration.status().1.starts_with("Plug in the AC adapter"));
+
+        battery.set("Full", 100);
+        assert_eq!(stage(calibration.step()), Some("finished"));
+        assert_eq!(battery.behaviour(), "auto");
+        assert_eq!(battery.thresholds(), (40, 80));
+    }
+
+    #[test]
+    fn cancelled_discharge() {
+        let battery = SimulatedBattery::new("cancelled-calibration", &["auto", "force-discharge"]);
+        let (mut calibration, _) = Calibration::start_with(battery.clone(), (40, 80)).unwrap();
+
+        battery.set("Full", 100);
+        calibration.step();
+        assert_eq!(battery.behaviour(), "force-discharge");
+
+        calibration.cancel();
+        assert_eq!(battery.behaviour(), "auto");
+        assert_eq!(battery.thresholds(), (40, 80));
+        assert!(!battery.state.exists());
+        assert!(Calibration::load_with(battery).is_none());
+    }
+
+    #[test]
+    fn resumed_discharge() {
+        let battery = SimulatedBattery::new("resumed-calibration", &["auto", "force-discharge"]);
+        let (mut calibration, _) = Calibration::start_with(battery.clone(), (40, 80)).unwrap();
+
+        battery.set("Full", 100);
+        calibration.step();
+
+        // The daemon stops, which stops the discharge until it starts again.
+        calibration.pause();
+        drop(calibration);
+        assert_eq!(battery.behaviour(), "auto");
+
+        let mut calibration = Calibration::load_with(battery.clone()).unwrap();
+        assert_eq!(calibration.status().0, "discharging");
+        assert_eq!(battery.behaviour(), "force-discharge");
+
+        battery.set("Discharging", 10);
+        assert_eq!(stage(calibration.step()), Some("recharging"));
+        battery.set("Full", 100);
+        assert_eq!(stage(calibration.step()), Some("finished"));
+        assert_eq!(battery.thresholds(), (40, 80));
+        assert!(Calibration::load_with(battery).is_none());
+    }
+}
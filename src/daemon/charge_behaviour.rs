@@ -0,0 +1,150 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A charge behaviour selected by the user, which optionally reverts to `auto` after a timeout.
+//!
+//! The behaviour is stored on disk with its deadline, so that a daemon restart does not leave the
+//! battery forced to discharge without one. The kernel resets the behaviour on boot, so it is
+//! selected again when the daemon starts, unless its deadline has passed.
+
+use crate::power_supply;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const STATE_PATH: &str = "/var/lib/system76-power/charge-behaviour.json";
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ManualBehaviour {
+    behaviour: String,
+    /// Unix time in seconds at which the behaviour reverts to `auto`.
+    deadline:  Option<u64>,
+}
+
+impl ManualBehaviour {
+    /// Keeps a behaviour which the user just selected, reverting to `auto` after `timeout`
+    /// seconds, or never if it is zero.
+    pub fn start(behaviour: &str, timeout: u32) -> anyhow::Result<Self> {
+        let manual = Self::new(behaviour, timeout, unix_time());
+        manual.save()?;
+        Ok(manual)
+    }
+
+    /// A behaviour which reverts to `auto` after `timeout` seconds, or never if it is zero.
+    fn new(behaviour: &str, timeout: u32, now: u64) -> Self {
+        let deadline = (timeout != 0).then(|| now + u64::from(timeout));
+        Self { behaviour: behaviour.to_owned(), deadline }
+    }
+
+    /// Selects the behaviour again after the daemon restarts, or reverts to `auto` if its
+    /// deadline passed while the daemon was stopped.
+    pub fn load() -> Option<Self> {
+        let state = fs::read_to_string(STATE_PATH).ok()?;
+
+        let manual = match serde_json::from_str::<Self>(&state) {
+            Ok(manual) => manual,
+            Err(why) => {
+                log::warn!("discarding invalid charge behaviour state: {}", why);
+                Self::discard();
+                return None;
+            }
+        };
+
+        if manual.expired(unix_time()) {
+            log::info!("charge behaviour {} timed out while stopped", manual.behaviour);
+            manual.revert();
+            return None;
+        }
+
+        log::info!("resuming charge behaviour {}", manual.behaviour);
+        set_behaviour(&manual.behaviour);
+        Some(manual)
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        if let Some(parent) = Path::new(STATE_PATH).parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(STATE_PATH, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Forgets the behaviour without touching the battery, such as when the user selects `auto`.
+    pub fn discard() {
+        if Path::new(STATE_PATH).exists() {
+            if let Err(why) = fs::remove_file(STATE_PATH) {
+                log::error!("failed to remove {}: {}", STATE_PATH, why);
+            }
+        }
+    }
+
+    fn expired(&self, now: u64) -> bool { self.deadline.is_some_and(|deadline| now >= deadline) }
+
+    /// Reverts to `auto` once the deadline passes.
+    ///
+    /// Returns `true` when the behaviour has reverted and can be dropped.
+    pub fn step(&self) -> bool {
+        if !self.expired(unix_time()) {
+            return false;
+        }
+
+        log::info!("charge behaviour timed out, reverting to auto");
+        self.revert();
+        true
+    }
+
+    /// Reverts a behaviour with a deadline to `auto` while the daemon is stopped, leaving it to be
+    /// selected again when the daemon starts. Without a deadline, the behaviour is left as the
+    /// user chose it.
+    pub fn pause(&self) {
+        if self.deadline.is_some() {
+            set_behaviour("auto");
+        }
+    }
+
+    fn revert(&self) {
+        set_behaviour("auto");
+        Self::discard();
+    }
+}
+
+fn set_behaviour(behaviour: &str) {
+    if let Some(battery) = power_supply::charge_behaviour_battery() {
+        if let Err(why) = power_supply::set_charge_behaviour(&battery, behaviour) {
+            log::error!("failed to set charge behaviour to {}: {}", behaviour, why);
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn behaviour_deadline() {
+        let now = 1_700_000_000;
+
+        let timed = ManualBehaviour::new("force-discharge", 600, now);
+        assert!(!timed.expired(now));
+        assert!(!timed.expired(now + 599));
+        assert!(timed.expired(now + 600));
+
+        let untimed = ManualBehaviour::new("inhibit-charge", 0, now);
+        assert!(!untimed.expired(u64::MAX));
+
+        // The deadline survives a restart as an absolute time.
+        let state = serde_json::to_string(&timed).unwrap();
+        let restored: ManualBehaviour = serde_json::from_str(&state).unwrap();
+        assert_eq!(restored, timed);
+        assert!(restored.expired(now + 600));
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The `com.system76.PowerDaemon.Fan` interface, for observing and configuring the fan daemon.
+
+use super::{check_authorization, zbus_error_from_display};
+use crate::{
+    fan::{FanCalibration, FanDaemon, FanEvent, HistorySample},
+    Profile,
+};
+use std::sync::Arc;
+use system76_power_zbus::{FanCalibrationInfo, ThermalSample};
+use tokio::sync::Mutex;
+
+const FAN_POLICY: &str = "com.system76.powerdaemon.control-fans";
+
+#[derive(Clone)]
+pub struct Fan(pub Arc<Mutex<FanDaemon>>);
+
+#[zbus::dbus_interface(name = "com.system76.PowerDaemon.Fan")]
+impl Fan {
+    /// The name of the fan curve in use, and its points as temperatures in hundredths of a degree
+    /// Celsius and duties in hundredths of a percent.
+    #[dbus_interface(out_args("name", "points"))]
+    async fn get_curve(&self) -> (String, Vec<(i16, u16)>) {
+        let fan_daemon = self.0.lock().await;
+        let (name, curve) = fan_daemon.curve();
+        (name.to_owned(), curve.points().iter().map(|point| (point.temp(), point.duty())).collect())
+    }
+
+    #[dbus_interface(out_args("names"))]
+    async fn get_curves(&self) -> zbus::fdo::Result<Vec<String>> {
+        self.0.lock().await.curve_names().map_err(zbus_error_from_display)
+    }
+
+    /// The hottest temperature, in thousandths of a degree Celsius, or 0 if it can not be read.
+    #[dbus_interface(out_args("temperature"))]
+    async fn get_temperature(&self) -> u32 { self.0.lock().await.temp().unwrap_or(0) }
+
+    /// The duty of each channel, in hundredths of a percent. Empty while the firmware controls
+    /// the fans.
+    #[dbus_interface(out_args("duties"))]
+    async fn get_duties(&self) -> Vec<(u8, u16)> {
+        let fan_daemon = self.0.lock().await;
+        let duties = fan_daemon.duties().iter();
+        duties.map(|&(channel, duty)| (channel, hundredths(duty))).collect()
+    }
+
+    /// The speed of each fan, in RPM.
+    #[dbus_interface(out_args("speeds"))]
+    async fn get_speeds(&self) -> Vec<(u8, u32)> { self.0.lock().await.get_rpms() }
+
+    /// Overrides the fan curves with a duty in percent until the timeout, in seconds, expires or
+    /// the temperature becomes critical. A timeout of 0 returns to the fan curves.
+    async fn set_manual_duty(
+        &self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+        percent: u8,
+        timeout: u32,
+    ) -> zbus::fdo::Result<()> {
+        check_authorization(&header, FAN_POLICY).await?;
+        self.0.lock().await.set_manual_duty(percent, timeout).map_err(zbus_error_from_display)
+    }
+
+    /// Measures the duty at which each fan starts and its fastest speed, over about two minutes,
+    /// after which curves no longer drive a fan below the duty at which it starts.
+    async fn calibrate(
+        &self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+    ) -> zbus::fdo::Result<()> {
+        check_authorization(&header, FAN_POLICY).await?;
+        self.0.lock().await.start_calibration().map_err(zbus_error_from_display)
+    }
+
+    /// Stops calibrating the fans, and keeps the previous calibration.
+    async fn cancel_calibration(
+        &self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+    ) -> zbus::fdo::Result<()> {
+        check_authorization(&header, FAN_POLICY).await?;
+        self.0.lock().await.cancel_calibration();
+        Ok(())
+    }
+
+    /// Whether the fans are being calibrated and how far, in percent, and the calibration of each
+    /// fan: the duty at which it starts in hundredths of a percent, and its fastest speed in RPM.
+    #[dbus_interface(out_args("calibrating", "progress", "calibrations"))]
+    async fn get_calibration(&self) -> (bool, u8, Vec<FanCalibrationInfo>) {
+        let fan_daemon = self.0.lock().await;
+        let progress = fan_daemon.calibration_progress();
+        (
+            progress.is_some(),
+            (progress.unwrap_or(0.0) * 100.0) as u8,
+            fan_daemon.calibrations().iter().map(calibration_info).collect(),
+        )
+    }
+
+    /// The temperatures, duties and speeds of the last day from a time onwards, in seconds since
+    /// the Unix epoch, merged into periods of at least five seconds.
+    #[dbus_interface(out_args("samples"))]
+    async fn get_thermal_history(&self, since: u64, resolution: u32) -> Vec<ThermalSample> {
+        let history = self.0.lock().await.history(since, u64::from(resolution));
+        history.iter().map(thermal_sample).collect()
+    }
+
+    /// A fan does not spin while it is driven at a duty in hundredths of a percent. The other
+    /// fans run at full speed until it spins again.
+    #[dbus_interface(signal)]
+    async fn fan_failure(
+        context: &zbus::SignalContext<'_>,
+        channel: u8,
+        rpm: u32,
+        duty: u16,
+    ) -> zbus::Result<()>;
+
+    /// The temperature, in thousandths of a degree Celsius, rose above the critical threshold and
+    /// the fans and CPU are limited, or fell below the recovery threshold and they are restored.
+    #[dbus_interface(signal)]
+    async fn thermal_emergency(
+        context: &zbus::SignalContext<'_>,
+        active: bool,
+        temperature: u32,
+    ) -> zbus::Result<()>;
+
+    /// Calibrating the fans finished, with the calibration of each fan which could be
+    /// calibrated, or none if it was cancelled.
+    #[dbus_interface(signal)]
+    async fn fan_calibrated(
+        context: &zbus::SignalContext<'_>,
+        calibrations: Vec<FanCalibrationInfo>,
+    ) -> zbus::Result<()>;
+
+    /// Reloads the fan config and switches to the named curve, or to the default curve if the name
+    /// is empty.
+    async fn set_curve(
+        &self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+        name: &str,
+    ) -> zbus::fdo::Result<()> {
+        check_authorization(&header, FAN_POLICY).await?;
+        self.0.lock().await.set_curve(name).map_err(zbus_error_from_display)
+    }
+}
+
+/// Converts a duty from 0 to 255 to hundredths of a percent.
+fn hundredths(duty: u8) -> u16 { (u32::from(duty) * 10_000 / 255) as u16 }
+
+fn calibration_info(calibration: &FanCalibration) -> FanCalibrationInfo {
+    FanCalibrationInfo {
+        channel:  calibration.channel,
+        min_duty: hundredths(calibration.min_duty),
+        max_rpm:  calibration.max_rpm,
+    }
+}
+
+fn thermal_sample(sample: &HistorySample) -> ThermalSample {
+    let profile = match sample.profile {
+        Profile::Battery => "Battery",
+        Profile::Balanced => "Balanced",
+        Profile::Performance => "Performance",
+    };
+
+    ThermalSample {
+        time:        sample.time,
+        temperature: sample.temp.unwrap_or(0),
+        sensors:     sample.sensors.iter().map(|(name, temp)| (name.to_string(), *temp)).collect(),
+        duties:      sample
+            .duties
+            .iter()
+            .map(|&(channel, duty)| (channel, hundredths(duty)))
+            .collect(),
+        speeds:      sample.rpms.clone(),
+        profile:     profile.to_owned(),
+    }
+}
+
+pub(super) async fn fan_event(context: &zbus::SignalContext<'_>, event: FanEvent) {
+    let _res = match event {
+        FanEvent::Failure { channel, rpm, duty } => {
+            Fan::fan_failure(context, channel, rpm, hundredths(duty)).await
+        }
+        FanEvent::Emergency { temp } => Fan::thermal_emergency(context, true, temp).await,
+        FanEvent::Recovered { temp } => Fan::thermal_emergency(context, false, temp).await,
+        FanEvent::Calibrated(calibrations) => {
+            Fan::fan_calibrated(context, calibrations.iter().map(calibration_info).collect()).await
+        }
+    };
+}
@@ -0,0 +1,148 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Temporarily charges the battery to full, then restores the previous charge thresholds.
+//!
+//! The override is stored on disk so that a daemon restart in the middle of charging still
+//! restores the thresholds the user chose.
+
+use crate::{
+    charge_thresholds::{apply_thresholds, get_charge_thresholds, FULL_THRESHOLDS},
+    errors::ChargeThresholdError,
+    power_supply,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const STATE_PATH: &str = "/var/lib/system76-power/full-charge-once.json";
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FullChargeOnce {
+    /// The thresholds to restore afterwards.
+    previous: (u8, u8),
+    /// Unix time in seconds after which the previous thresholds are restored.
+    deadline: Option<u64>,
+    /// Whether AC has been seen online, so that unplugging can be detected.
+    #[serde(skip)]
+    ac_seen:  bool,
+}
+
+impl FullChargeOnce {
+    /// Raises the charge thresholds to full until the battery is full, AC is unplugged, or the
+    /// deadline passes.
+    ///
+    /// An override which is already active keeps its original thresholds to restore.
+    pub fn start(active: Option<Self>, deadline: Option<u64>) -> anyhow::Result<Self> {
+        let previous = match active {
+            Some(active) => active.previous,
+            None => get_charge_thresholds()?,
+        };
+
+        let state = Self { previous, deadline, ac_seen: false };
+        state.save()?;
+
+        // Firmware which clamps the thresholds still charges as far as it allows, and those
+        // thresholds must be restored afterwards all the same.
+        match apply_thresholds(FULL_THRESHOLDS) {
+            Ok(_) => (),
+            Err(why) if why.is::<ChargeThresholdError>() => log::warn!("{}", why),
+            Err(why) => {
+                Self::discard();
+                return Err(why);
+            }
+        }
+
+        log::info!("charging to full once, then restoring {:?}", previous);
+        Ok(state)
+    }
+
+    /// Resumes an override which was active when the daemon last stopped.
+    pub fn load() -> Option<Self> {
+        let state = fs::read_to_string(STATE_PATH).ok()?;
+
+        match serde_json::from_str::<Self>(&state) {
+            Ok(state) => {
+                log::info!("resuming full charge, then restoring {:?}", state.previous);
+                Some(state)
+            }
+            Err(why) => {
+                log::warn!("discarding invalid full charge state: {}", why);
+                Self::discard();
+                None
+            }
+        }
+    }
+
+    /// Forgets the override without restoring its thresholds, and returns them instead.
+    pub fn discard_previous(self) -> (u8, u8) {
+        Self::discard();
+        self.previous
+    }
+
+    /// Changes the thresholds to restore afterwards.
+    pub fn set_previous(&mut self, previous: (u8, u8)) {
+        self.previous = previous;
+        if let Err(why) = self.save() {
+            log::error!("failed to save full charge state: {}", why);
+        }
+    }
+
+    /// The thresholds to restore afterwards.
+    pub const fn previous(&self) -> (u8, u8) { self.previous }
+
+    /// Forgets the override without touching the thresholds, such as when the user sets new
+    /// thresholds while it is active.
+    pub fn discard() {
+        if Path::new(STATE_PATH).exists() {
+            if let Err(why) = fs::remove_file(STATE_PATH) {
+                log::error!("failed to remove {}: {}", STATE_PATH, why);
+            }
+        }
+    }
+
+    /// Restores the previous thresholds once the override has finished.
+    ///
+    /// Returns `true` when the override is complete and can be dropped.
+    pub fn step(&mut self) -> bool {
+        let reason = if power_supply::battery_status().as_deref() == Some("Full") {
+            "battery is full"
+        } else if self.deadline.is_some_and(|deadline| unix_time() >= deadline) {
+            "deadline passed"
+        } else {
+            match power_supply::ac_online() {
+                Some(true) => {
+                    self.ac_seen = true;
+                    return false;
+                }
+                Some(false) if self.ac_seen => "AC was unplugged",
+                _ => return false,
+            }
+        };
+
+        log::info!("{}: restoring charge thresholds {:?}", reason, self.previous);
+        if let Err(why) = apply_thresholds(self.previous) {
+            log::error!("failed to restore charge thresholds: {}", why);
+        }
+
+        Self::discard();
+        true
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        if let Some(parent) = Path::new(STATE_PATH).parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(STATE_PATH, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
@@ -22,23 +22,40 @@ use tokio::{
 use zbus::Interface;
 
 use crate::{
-    charge_thresholds::{get_charge_profiles, get_charge_thresholds, set_charge_thresholds},
-    errors::ProfileError,
-    fan::FanDaemon,
+    charge_thresholds::{
+        get_charge_capabilities, get_charge_profiles, get_charge_thresholds,
+        restore_charge_thresholds, set_charge_thresholds, EmulatedThresholds, FULL_THRESHOLDS,
+    },
+    errors::{ChargeThresholdError, ProfileError},
+    fan::{FanDaemon, FanEvent},
     graphics::{Graphics, GraphicsMode},
     hid_backlight,
     hotplug::{mux, Detect, HotPlugDetect},
     kernel_parameters::{KernelParameter, NmiWatchdog},
+    power_supply,
     runtime_pm::{runtime_pm_quirks, thunderbolt_hotplug_wakeup},
-    DBUS_NAME, DBUS_PATH,
+    Profile, DBUS_NAME, DBUS_PATH,
 };
 
+mod calibration;
+mod charge_behaviour;
+mod fan;
+mod full_charge;
 mod profiles;
-use self::profiles::{balanced, battery, performance};
+mod smart_charge;
+use self::{
+    calibration::{Calibration, CalibrationEvent},
+    charge_behaviour::ManualBehaviour,
+    fan::{fan_event, Fan},
+    full_charge::FullChargeOnce,
+    profiles::{balanced, battery, performance, thermal_limits},
+    smart_charge::SmartCharge,
+};
 
-use system76_power_zbus::ChargeProfile;
+use system76_power_zbus::{BatteryInfo, ChargeCapabilities, ChargeProfile};
 
 const THRESHOLD_POLICY: &str = "com.system76.powerdaemon.set-charge-thresholds";
+const BEHAVIOUR_POLICY: &str = "com.system76.powerdaemon.set-charge-behaviour";
 const NET_HADESS_POWER_PROFILES_DBUS_NAME: &str = "net.hadess.PowerProfiles";
 const NET_HADESS_POWER_PROFILES_DBUS_PATH: &str = "/net/hadess/PowerProfiles";
 const POWER_PROFILES_DBUS_NAME: &str = "org.freedesktop.UPower.PowerProfiles";
@@ -46,6 +63,45 @@ const POWER_PROFILES_DBUS_PATH: &str = "/org/freedesktop/UPower/PowerProfiles";
 
 static CONTINUE: AtomicBool = AtomicBool::new(true);
 
+#[derive(Debug)]
+enum PowerDaemonError {
+    /// A standard error, such as a polkit denial, which is replied with its own name.
+    Fdo(zbus::fdo::Error),
+    /// The hardware reports different charge thresholds than those requested.
+    ChargeThresholdsMismatch(String),
+}
+
+impl From<zbus::fdo::Error> for PowerDaemonError {
+    fn from(why: zbus::fdo::Error) -> Self { Self::Fdo(why) }
+}
+
+impl zbus::DBusError for PowerDaemonError {
+    fn create_reply(&self, call: &zbus::MessageHeader<'_>) -> zbus::Result<zbus::Message> {
+        match self {
+            Self::Fdo(why) => why.create_reply(call),
+            Self::ChargeThresholdsMismatch(why) => {
+                zbus::MessageBuilder::error(call, self.name())?.build(&(why,))
+            }
+        }
+    }
+
+    fn name(&self) -> zbus::names::ErrorName<'_> {
+        match self {
+            Self::Fdo(why) => why.name(),
+            Self::ChargeThresholdsMismatch(_) => zbus::names::ErrorName::from_static_str_unchecked(
+                "com.system76.PowerDaemon.ChargeThresholdsMismatch",
+            ),
+        }
+    }
+
+    fn description(&self) -> Option<&str> {
+        match self {
+            Self::Fdo(why) => why.description(),
+            Self::ChargeThresholdsMismatch(why) => Some(why),
+        }
+    }
+}
+
 async fn signal_handling() {
     let mut int = signal(SignalKind::interrupt()).unwrap();
     let mut hup = signal(SignalKind::hangup()).unwrap();
@@ -69,13 +125,25 @@ static PCI_RUNTIME_PM: AtomicBool = AtomicBool::new(false);
 pub(crate) fn pci_runtime_pm_support() -> bool { PCI_RUNTIME_PM.load(Ordering::SeqCst) }
 
 struct PowerDaemon {
-    initial_set:    bool,
-    graphics:       Graphics,
-    power_profile:  String,
-    profile_errors: Vec<ProfileError>,
-    held_profiles:  Vec<(u32, &'static str, String, String)>,
-    profile_ids:    u32,
-    connections:    Option<(zbus::Connection, zbus::Connection, zbus::Connection)>,
+    initial_set:       bool,
+    graphics:          Graphics,
+    power_profile:     String,
+    profile_errors:    Vec<ProfileError>,
+    held_profiles:     Vec<(u32, &'static str, String, String)>,
+    profile_ids:       u32,
+    connections:       Option<(zbus::Connection, zbus::Connection, zbus::Connection)>,
+    fan_daemon:        Option<Arc<Mutex<FanDaemon>>>,
+    full_charge:       Option<FullChargeOnce>,
+    calibration:       Option<Calibration>,
+    smart_charge:      Option<SmartCharge>,
+    /// The charge behaviour chosen by the user, which must not be changed by emulated charge
+    /// thresholds.
+    manual_behaviour:  Option<ManualBehaviour>,
+    /// Whether the CPU is limited by a thermal emergency, whatever the profile.
+    thermal_emergency: bool,
+    /// The charge threshold backend, if the daemon emulates the thresholds. It is detected again
+    /// when AC changes or the system resumes.
+    emulated:          Option<EmulatedThresholds>,
 }
 
 impl PowerDaemon {
@@ -90,6 +158,13 @@ impl PowerDaemon {
             held_profiles: Vec::new(),
             profile_ids: 0,
             connections: None,
+            fan_daemon: None,
+            full_charge: FullChargeOnce::load(),
+            calibration: Calibration::load(),
+            smart_charge: SmartCharge::load(),
+            manual_behaviour: ManualBehaviour::load(),
+            thermal_emergency: false,
+            emulated: EmulatedThresholds::detect(),
         })
     }
 
@@ -108,9 +183,24 @@ impl PowerDaemon {
 
         func(&mut self.profile_errors, self.initial_set);
 
+        // The limits of a thermal emergency outlast changes of profile.
+        if self.thermal_emergency {
+            thermal_limits(&mut self.profile_errors);
+        }
+
         self.power_profile = name.into();
 
         if self.profile_errors.is_empty() {
+            if let Some(ref fan_daemon) = self.fan_daemon {
+                let profile = match name {
+                    "Battery" => Profile::Battery,
+                    "Performance" => Profile::Performance,
+                    _ => Profile::Balanced,
+                };
+
+                fan_daemon.lock().await.set_profile(profile);
+            }
+
             Ok(())
         } else {
             let mut error_message = String::from("Errors found when setting profile:");
@@ -121,6 +211,170 @@ impl PowerDaemon {
             Err(error_message)
         }
     }
+
+    /// Limits the CPU while the fan daemon reports a thermal emergency, and restores the limits of
+    /// the power profile once it has recovered.
+    fn set_thermal_emergency(&mut self, active: bool) {
+        self.thermal_emergency = active;
+
+        let mut errors = Vec::new();
+        if active {
+            thermal_limits(&mut errors);
+        } else {
+            let func = match self.power_profile.as_str() {
+                "Battery" => battery,
+                "Performance" => performance,
+                _ => balanced,
+            };
+
+            func(&mut errors, false);
+        }
+
+        for why in errors {
+            log::warn!("thermal emergency: {}", why);
+        }
+    }
+
+    /// Restores the charge thresholds once a full charge override has finished.
+    fn full_charge_step(&mut self) {
+        if self.full_charge.as_mut().is_some_and(FullChargeOnce::step) {
+            self.full_charge = None;
+        }
+    }
+
+    fn charge_to_full(&mut self, deadline: Option<u64>) -> anyhow::Result<()> {
+        self.check_not_calibrating()?;
+        self.full_charge = Some(FullChargeOnce::start(self.full_charge.take(), deadline)?);
+        Ok(())
+    }
+
+    /// Charge thresholds are managed by the calibration until it finishes or is cancelled.
+    fn check_not_calibrating(&self) -> anyhow::Result<()> {
+        if self.calibration.is_some() {
+            return Err(anyhow::anyhow!("Battery calibration is in progress"));
+        }
+
+        Ok(())
+    }
+
+    fn start_calibration(&mut self) -> anyhow::Result<CalibrationEvent> {
+        self.check_not_calibrating()?;
+
+        // A full charge in progress is superseded, but its thresholds are restored afterwards.
+        let previous = match self.full_charge.take() {
+            Some(full_charge) => full_charge.discard_previous(),
+            None => get_charge_thresholds()?,
+        };
+
+        let (calibration, event) = Calibration::start(previous)?;
+        self.calibration = Some(calibration);
+        Ok(event)
+    }
+
+    fn set_smart_charging(&mut self, enabled: bool) -> anyhow::Result<()> {
+        self.check_not_calibrating()?;
+
+        match (enabled, self.smart_charge.take()) {
+            (true, None) => {
+                // A full charge in progress restores the thresholds the user chose.
+                let previous = match self.full_charge {
+                    Some(ref full_charge) => full_charge.previous(),
+                    None => get_charge_thresholds()?,
+                };
+
+                self.smart_charge = Some(SmartCharge::start(previous)?);
+            }
+            (false, Some(smart_charge)) => match self.full_charge {
+                Some(ref mut full_charge) => {
+                    full_charge.set_previous(smart_charge.previous());
+                    SmartCharge::discard();
+                }
+                None => smart_charge.stop(),
+            },
+            (_, smart_charge) => self.smart_charge = smart_charge,
+        }
+
+        Ok(())
+    }
+
+    /// The thresholds set by a full charge, calibration or smart charging, which are restored
+    /// instead of the saved thresholds while it is active.
+    fn temporary_thresholds(&self) -> Option<(u8, u8)> {
+        if self.full_charge.is_some() || self.calibration.is_some() {
+            return Some(FULL_THRESHOLDS);
+        }
+
+        self.smart_charge.as_ref().and_then(SmartCharge::thresholds)
+    }
+
+    /// Smart charging leaves the thresholds alone while a full charge or calibration is active.
+    fn smart_charge_step(&mut self) {
+        let paused = self.full_charge.is_some() || self.calibration.is_some();
+        if let Some(ref mut smart_charge) = self.smart_charge {
+            smart_charge.step(paused);
+        }
+    }
+
+    /// Selects a charge behaviour, optionally reverting to `auto` after `timeout` seconds.
+    fn set_charge_behaviour(&mut self, behaviour: &str, timeout: u32) -> anyhow::Result<()> {
+        self.check_not_calibrating()?;
+
+        let battery = power_supply::charge_behaviour_battery()
+            .ok_or_else(|| anyhow::anyhow!("Charge behaviour is not supported by this battery"))?;
+
+        let (choices, _) = power_supply::charge_behaviours(&battery).unwrap_or_default();
+        if !choices.iter().any(|choice| choice == behaviour) {
+            return Err(anyhow::anyhow!(
+                "Charge behaviour '{}' is not supported: should be one of {}",
+                behaviour,
+                choices.join(", ")
+            ));
+        }
+
+        power_supply::set_charge_behaviour(&battery, behaviour)?;
+
+        self.manual_behaviour = if behaviour == "auto" {
+            ManualBehaviour::discard();
+            None
+        } else {
+            Some(ManualBehaviour::start(behaviour, timeout)?)
+        };
+
+        log::info!("charge behaviour set to {} (timeout: {}s)", behaviour, timeout);
+        Ok(())
+    }
+
+    /// Reverts the charge behaviour to `auto` once its timeout passes.
+    fn charge_behaviour_step(&mut self) {
+        if self.manual_behaviour.as_ref().is_some_and(ManualBehaviour::step) {
+            self.manual_behaviour = None;
+        }
+    }
+
+    /// Enforces emulated charge thresholds, unless the charge behaviour is controlled by the user
+    /// or a calibration.
+    fn charge_threshold_step(&mut self) {
+        if self.manual_behaviour.is_some() || self.calibration.is_some() {
+            return;
+        }
+
+        let Some(ref emulated) = self.emulated else {
+            return;
+        };
+
+        if let Err(why) = emulated.enforce() {
+            log::debug!("failed to enforce charge thresholds: {}", why);
+        }
+    }
+
+    fn calibration_step(&mut self) -> Option<CalibrationEvent> {
+        let event = self.calibration.as_mut()?.step();
+        if let Some(CalibrationEvent::Finished(_)) = event {
+            self.calibration = None;
+        }
+
+        event
+    }
 }
 
 #[derive(Clone)]
@@ -292,53 +546,178 @@ impl System76Power {
         self.0.lock().await.graphics.auto_power().map_err(zbus_error_from_display)
     }
 
+    #[dbus_interface(out_args("capabilities"))]
+    async fn get_charge_capabilities(&mut self) -> zbus::fdo::Result<ChargeCapabilities> {
+        get_charge_capabilities().map_err(zbus_error_from_display)
+    }
+
     #[dbus_interface(out_args("start", "end"))]
     async fn get_charge_thresholds(&mut self) -> zbus::fdo::Result<(u8, u8)> {
         get_charge_thresholds().map_err(zbus_error_from_display)
     }
 
-    async fn set_charge_thresholds(&mut self, thresholds: (u8, u8)) -> zbus::fdo::Result<()> {
-        let connection = zbus::Connection::system().await?;
-        let polkit = zbus_polkit::policykit1::AuthorityProxy::new(&connection)
-            .await
-            .context("could not connect to polkit authority daemon")
-            .map_err(zbus_error_from_display)?;
+    async fn set_charge_thresholds(
+        &mut self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+        #[zbus(signal_context)] context: zbus::SignalContext<'_>,
+        thresholds: (u8, u8),
+    ) -> Result<(), PowerDaemonError> {
+        check_authorization(&header, THRESHOLD_POLICY).await?;
 
-        let pid = std::process::id();
+        let mut this = self.0.lock().await;
+        this.check_not_calibrating().map_err(zbus_error_from_display)?;
+        let result = set_charge_thresholds(thresholds);
+
+        // Thresholds chosen by the user replace those a full charge would restore, and end smart
+        // charging.
+        let written = result.as_ref().err().map_or(true, |why| why.is::<ChargeThresholdError>());
+        if written && this.full_charge.take().is_some() {
+            FullChargeOnce::discard();
+        }
 
-        let permitted = if pid == 0 {
-            true
-        } else {
-            let subject = zbus_polkit::policykit1::Subject::new_for_owner(pid, None, None)
-                .context("could not create policykit1 subject")
-                .map_err(zbus_error_from_display)?;
-
-            polkit
-                .check_authorization(
-                    &subject,
-                    THRESHOLD_POLICY,
-                    &std::collections::HashMap::new(),
-                    Default::default(),
-                    "",
-                )
-                .await
-                .context("could not check policykit authorization")
-                .map_err(zbus_error_from_display)?
-                .is_authorized
-        };
+        if written && this.smart_charge.take().is_some() {
+            log::info!("smart charging disabled by new charge thresholds");
+            SmartCharge::discard();
+        }
 
-        if permitted {
-            set_charge_thresholds(thresholds).map_err(zbus_error_from_display)
-        } else {
-            Err(zbus_error_from_display("Operation not permitted by Polkit"))
+        drop(this);
+
+        match result {
+            Ok((start, end)) => {
+                let _res = Self::charge_thresholds_changed(&context, start, end).await;
+                Ok(())
+            }
+            Err(why) => Err(charge_thresholds_error(&context, why).await),
         }
     }
 
+    async fn charge_to_full_once(
+        &mut self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+    ) -> zbus::fdo::Result<()> {
+        check_authorization(&header, THRESHOLD_POLICY).await?;
+        self.0.lock().await.charge_to_full(None).map_err(zbus_error_from_display)
+    }
+
+    async fn charge_to_full_until(
+        &mut self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+        timestamp: u64,
+    ) -> zbus::fdo::Result<()> {
+        check_authorization(&header, THRESHOLD_POLICY).await?;
+        self.0.lock().await.charge_to_full(Some(timestamp)).map_err(zbus_error_from_display)
+    }
+
+    async fn start_calibration(
+        &mut self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+        #[zbus(signal_context)] context: zbus::SignalContext<'_>,
+    ) -> zbus::fdo::Result<()> {
+        check_authorization(&header, THRESHOLD_POLICY).await?;
+
+        let event = self.0.lock().await.start_calibration().map_err(zbus_error_from_display)?;
+        calibration_event(&context, event).await;
+        Ok(())
+    }
+
+    async fn cancel_calibration(
+        &mut self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+    ) -> zbus::fdo::Result<()> {
+        check_authorization(&header, THRESHOLD_POLICY).await?;
+
+        match self.0.lock().await.calibration.take() {
+            Some(calibration) => {
+                calibration.cancel();
+                Ok(())
+            }
+            None => Err(zbus_error_from_display("Battery calibration is not in progress")),
+        }
+    }
+
+    #[dbus_interface(out_args("stage", "message"))]
+    async fn get_calibration(&mut self) -> zbus::fdo::Result<(String, String)> {
+        Ok(self.0.lock().await.calibration.as_ref().map_or_else(Default::default, |calibration| {
+            let (stage, message) = calibration.status();
+            (stage.to_owned(), message.to_owned())
+        }))
+    }
+
+    #[dbus_interface(out_args("behaviour", "choices"))]
+    async fn get_charge_behaviour(&mut self) -> zbus::fdo::Result<(String, Vec<String>)> {
+        power_supply::charge_behaviour_battery()
+            .and_then(|battery| power_supply::charge_behaviours(&battery))
+            .map(|(choices, selected)| (selected, choices))
+            .ok_or_else(|| zbus_error_from_display("Charge behaviour is not supported"))
+    }
+
+    async fn set_charge_behaviour(
+        &mut self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+        behaviour: &str,
+        timeout: u32,
+    ) -> zbus::fdo::Result<()> {
+        check_authorization(&header, BEHAVIOUR_POLICY).await?;
+        self.0
+            .lock()
+            .await
+            .set_charge_behaviour(behaviour, timeout)
+            .map_err(zbus_error_from_display)
+    }
+
+    #[dbus_interface(out_args("batteries"))]
+    async fn get_battery_info(&mut self) -> zbus::fdo::Result<Vec<BatteryInfo>> {
+        Ok(power_supply::batteries()
+            .iter()
+            .map(|battery| power_supply::battery_info(battery))
+            .collect())
+    }
+
+    #[dbus_interface(out_args("enabled"))]
+    async fn get_smart_charging(&mut self) -> bool { self.0.lock().await.smart_charge.is_some() }
+
+    async fn set_smart_charging(
+        &mut self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+        enabled: bool,
+    ) -> zbus::fdo::Result<()> {
+        check_authorization(&header, THRESHOLD_POLICY).await?;
+        self.0.lock().await.set_smart_charging(enabled).map_err(zbus_error_from_display)
+    }
+
+    /// Unix time at which smart charging next charges the battery to full, or zero if it is
+    /// disabled or cannot predict when the laptop is unplugged yet.
+    #[dbus_interface(out_args("timestamp"))]
+    async fn get_next_full_charge(&mut self) -> u64 {
+        let this = self.0.lock().await;
+        this.smart_charge.as_ref().and_then(SmartCharge::next_full_charge).unwrap_or(0)
+    }
+
     #[dbus_interface(out_args("profiles"))]
     async fn get_charge_profiles(&mut self) -> zbus::fdo::Result<Vec<ChargeProfile>> {
         Ok(get_charge_profiles())
     }
 
+    #[dbus_interface(signal)]
+    async fn calibration_progress(
+        context: &zbus::SignalContext<'_>,
+        stage: &str,
+        message: &str,
+    ) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn calibration_finished(
+        context: &zbus::SignalContext<'_>,
+        battery: BatteryInfo,
+    ) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn charge_thresholds_changed(
+        context: &zbus::SignalContext<'_>,
+        start: u8,
+        end: u8,
+    ) -> zbus::Result<()>;
+
     #[dbus_interface(signal)]
     async fn hot_plug_detect(context: &zbus::SignalContext<'_>, port: u64) -> zbus::Result<()>;
 
@@ -575,19 +954,26 @@ pub async fn daemon() -> anyhow::Result<()> {
         .await
         .context("unable to create system service for net.hadess.PowerProfiles")?;
 
-    // Register DBus interface for com.system76.PowerDaemon.
+    let fan_daemon = Arc::new(Mutex::new(FanDaemon::new(nvidia_exists)));
+
+    // Register DBus interfaces for com.system76.PowerDaemon.
     let connection = zbus::ConnectionBuilder::system()
         .context("failed to create zbus connection builder")?
         .name(DBUS_NAME)
         .context("unable to register name")?
         .serve_at(DBUS_PATH, system76_daemon.clone())
         .context("unable to serve")?
+        .serve_at(DBUS_PATH, Fan(fan_daemon.clone()))
+        .context("unable to serve")?
         .build()
         .await
         .context("unable to create system service for com.system76.PowerDaemon")?;
 
-    system76_daemon.0.lock().await.connections =
-        Some((connection.clone(), upp_connection, hadess_connection));
+    {
+        let mut daemon = system76_daemon.0.lock().await;
+        daemon.connections = Some((connection.clone(), upp_connection, hadess_connection));
+        daemon.fan_daemon = Some(fan_daemon.clone());
+    }
 
     let context = zbus::SignalContext::new(&connection, DBUS_PATH)
         .context("unable to create signal context")?;
@@ -598,9 +984,10 @@ pub async fn daemon() -> anyhow::Result<()> {
 
     system76_daemon.0.lock().await.initial_set = true;
 
+    restore_thresholds(&context, &system76_daemon, "daemon started").await;
+
     // Spawn hid backlight daemon
     let _hid_backlight = thread::spawn(hid_backlight::daemon);
-    let mut fan_daemon = FanDaemon::new(nvidia_exists);
     let mut hpd_res = unsafe { HotPlugDetect::new(nvidia_device_id) };
     let mux_res = unsafe { mux::DisplayPortMux::new() };
     let mut hpd = || -> [bool; 4] {
@@ -613,11 +1000,54 @@ pub async fn daemon() -> anyhow::Result<()> {
 
     let main_loop = async move {
         let mut last = hpd();
+        let mut last_ac = power_supply::ac_online();
+        let mut last_suspended = suspended_time();
 
         while CONTINUE.load(Ordering::SeqCst) {
             sleep(Duration::from_millis(1000)).await;
 
-            fan_daemon.step();
+            let events = fan_daemon.lock().await.step();
+            for event in events {
+                match event {
+                    FanEvent::Emergency { .. } => {
+                        system76_daemon.0.lock().await.set_thermal_emergency(true);
+                    }
+                    FanEvent::Recovered { .. } => {
+                        system76_daemon.0.lock().await.set_thermal_emergency(false);
+                    }
+                    FanEvent::Failure { .. } | FanEvent::Calibrated(_) => (),
+                }
+
+                fan_event(&context, event).await;
+            }
+
+            system76_daemon.0.lock().await.full_charge_step();
+            system76_daemon.0.lock().await.charge_behaviour_step();
+            system76_daemon.0.lock().await.charge_threshold_step();
+            system76_daemon.0.lock().await.smart_charge_step();
+
+            let event = system76_daemon.0.lock().await.calibration_step();
+            if let Some(event) = event {
+                calibration_event(&context, event).await;
+            }
+
+            let ac = power_supply::ac_online();
+            let suspended = suspended_time();
+            let reason = if suspended > last_suspended + Duration::from_secs(1) {
+                Some("resumed from suspend")
+            } else if ac != last_ac {
+                Some("AC adapter changed")
+            } else {
+                None
+            };
+
+            if let Some(reason) = reason {
+                system76_daemon.0.lock().await.emulated = EmulatedThresholds::detect();
+                restore_thresholds(&context, &system76_daemon, reason).await;
+            }
+
+            last_ac = ac;
+            last_suspended = suspended;
 
             // HACK: As of Linux 6.9.3, TBT5 controller must be active for HPD
             // to work on USB-C ports.
@@ -644,6 +1074,20 @@ pub async fn daemon() -> anyhow::Result<()> {
                 }
             }
         }
+
+        // Return control of the fans to the firmware.
+        fan_daemon.lock().await.set_duty(None);
+
+        // Do not leave the battery forced to discharge. The calibration, and a charge behaviour
+        // with a timeout, resume on startup.
+        let this = system76_daemon.0.lock().await;
+        if let Some(ref calibration) = this.calibration {
+            calibration.pause();
+        }
+
+        if let Some(ref manual) = this.manual_behaviour {
+            manual.pause();
+        }
     };
 
     log::info!("Handling dbus requests");
@@ -653,6 +1097,101 @@ pub async fn daemon() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Checks with polkit that the caller of a method, identified by the header of its message, is
+/// permitted to perform the given action.
+async fn check_authorization(
+    header: &zbus::MessageHeader<'_>,
+    action: &str,
+) -> zbus::fdo::Result<()> {
+    let connection = zbus::Connection::system().await?;
+    let polkit = zbus_polkit::policykit1::AuthorityProxy::new(&connection)
+        .await
+        .context("could not connect to polkit authority daemon")
+        .map_err(zbus_error_from_display)?;
+
+    let subject = zbus_polkit::policykit1::Subject::new_for_message_header(header)
+        .context("could not create policykit1 subject")
+        .map_err(zbus_error_from_display)?;
+
+    let permitted = polkit
+        .check_authorization(
+            &subject,
+            action,
+            &std::collections::HashMap::new(),
+            zbus_polkit::policykit1::CheckAuthorizationFlags::AllowUserInteraction.into(),
+            "",
+        )
+        .await
+        .context("could not check policykit authorization")
+        .map_err(zbus_error_from_display)?
+        .is_authorized;
+
+    if permitted {
+        Ok(())
+    } else {
+        Err(zbus::fdo::Error::AccessDenied("Operation not permitted by Polkit".into()))
+    }
+}
+
+/// Converts a failure to set charge thresholds into a D-Bus error.
+///
+/// If the hardware applied different thresholds than requested, `ChargeThresholdsChanged` is
+/// emitted with the thresholds it reports.
+async fn charge_thresholds_error(
+    context: &zbus::SignalContext<'_>,
+    why: anyhow::Error,
+) -> PowerDaemonError {
+    match why.downcast_ref::<ChargeThresholdError>() {
+        Some(ChargeThresholdError::Mismatch { actual, .. }) => {
+            let _res = System76Power::charge_thresholds_changed(context, actual.0, actual.1).await;
+            PowerDaemonError::ChargeThresholdsMismatch(why.to_string())
+        }
+        None => PowerDaemonError::Fdo(zbus_error_from_display(why)),
+    }
+}
+
+async fn calibration_event(context: &zbus::SignalContext<'_>, event: CalibrationEvent) {
+    let _res = match event {
+        CalibrationEvent::Progress(stage, message) => {
+            System76Power::calibration_progress(context, stage, &message).await
+        }
+        CalibrationEvent::Finished(battery) => {
+            System76Power::calibration_finished(context, battery).await
+        }
+    };
+}
+
+/// Sets the charge thresholds again if the firmware has reset them.
+async fn restore_thresholds(
+    context: &zbus::SignalContext<'_>,
+    daemon: &System76Power,
+    reason: &str,
+) {
+    let temporary = daemon.0.lock().await.temporary_thresholds();
+    match restore_charge_thresholds(temporary) {
+        Ok(Some((start, end))) => {
+            log::info!("{}: restored charge thresholds", reason);
+            let _res = System76Power::charge_thresholds_changed(context, start, end).await;
+        }
+        Ok(None) => (),
+        Err(why) => {
+            log::warn!("{}: failed to restore charge thresholds: {}", reason, why);
+            let _res = charge_thresholds_error(context, why).await;
+        }
+    }
+}
+
+/// Time spent suspended since boot, which increases whenever the system resumes.
+fn suspended_time() -> Duration {
+    let clock = |id| {
+        let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(id, &mut time) };
+        Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+    };
+
+    clock(libc::CLOCK_BOOTTIME).saturating_sub(clock(libc::CLOCK_MONOTONIC))
+}
+
 fn system76_profile_to_upp_str(system76_profile: &str) -> &'static str {
     match system76_profile {
         "Battery" => "power-saver",
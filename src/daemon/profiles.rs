@@ -134,12 +134,7 @@ pub fn battery(errors: &mut Vec<ProfileError>, set_brightness: bool) {
     LaptopMode.set(b"2");
     RadeonDevice::get_devices().for_each(|dev| dev.set_profiles("low", "battery", "low"));
     catch!(errors, scsi_host_link_time_pm_policy(&["min_power", "min_power"]));
-    crate::cpufreq::set(Profile::Battery, 50);
-
-    catch!(
-        errors,
-        pstate_values(PStateValues::default().min_perf_pct(0).max_perf_pct(50).no_turbo(true))
-    );
+    thermal_limits(errors);
 
     if set_brightness {
         catch!(errors, iterate_backlights(Backlight::iter(), &Brightness::set_if_lower_than, 10));
@@ -149,6 +144,17 @@ pub fn battery(errors: &mut Vec<ProfileError>, set_brightness: bool) {
     if pci_runtime_pm_support() {
         catch!(errors, pci_device_runtime_pm(RuntimePowerManagement::On));
     }
+}
+
+/// Limits the CPU as in the battery profile, without changing anything else, to cool down the
+/// system in a thermal emergency.
+pub fn thermal_limits(errors: &mut Vec<ProfileError>) {
+    crate::cpufreq::set(Profile::Battery, 50);
+
+    catch!(
+        errors,
+        pstate_values(PStateValues::default().min_perf_pct(0).max_perf_pct(50).no_turbo(true))
+    );
 
     if let Some(model_profiles) = ModelProfiles::new() {
         catch!(errors, model_profiles.battery.set());
@@ -0,0 +1,395 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Holds the battery at a low charge, and tops it up to full shortly before it is usually
+//! unplugged.
+//!
+//! While smart charging is enabled, the times at which AC is plugged in and unplugged are
+//! recorded. The unplug time of each weekday is predicted from the median of the recent unplugs
+//! on that weekday which followed a long charge, such as overnight.
+
+use crate::{
+    charge_thresholds::{apply_thresholds, get_charge_profiles, FULL_THRESHOLDS},
+    errors::ChargeThresholdError,
+    power_supply,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const STATE_PATH: &str = "/var/lib/system76-power/smart-charge.json";
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+
+/// How long before the predicted unplug the battery starts charging to full.
+const TOP_UP_MINUTES: u32 = 120;
+
+/// How long after the predicted unplug the battery is kept full, in case it is unplugged late.
+const GRACE_MINUTES: u32 = 60;
+
+/// Unplugs after a shorter charge, such as over lunch, do not predict when the laptop leaves.
+const MIN_CHARGE_SECS: u64 = 3 * 60 * 60;
+
+/// Weekdays with fewer qualifying unplugs than this have no prediction.
+const MIN_SAMPLES: usize = 3;
+
+/// Only the most recent unplugs of each weekday are used, so that predictions follow changes in
+/// routine.
+const MAX_SAMPLES: usize = 8;
+
+/// The number of plug and unplug events kept in the history.
+const MAX_EVENTS: usize = 200;
+
+/// A local time of the week.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct LocalTime {
+    /// Days since Sunday.
+    pub weekday: u8,
+    /// Minutes since midnight.
+    pub minute:  u16,
+}
+
+impl LocalTime {
+    /// Converts a Unix time to the local time zone.
+    fn from_unix(time: u64) -> Self {
+        let time = time as libc::time_t;
+        // SAFETY: `localtime_r` only writes to the `tm` which it is given.
+        let tm = unsafe {
+            let mut tm = std::mem::zeroed::<libc::tm>();
+            libc::localtime_r(&time, &mut tm);
+            tm
+        };
+
+        Self { weekday: tm.tm_wday as u8, minute: (tm.tm_hour * 60 + tm.tm_min) as u16 }
+    }
+
+    /// Minutes since midnight on Sunday.
+    const fn week_minute(self) -> u32 {
+        (self.weekday % 7) as u32 * MINUTES_PER_DAY + self.minute as u32
+    }
+}
+
+/// AC being plugged in or unplugged.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AcEvent {
+    /// Unix time in seconds.
+    pub time:    u64,
+    pub local:   LocalTime,
+    pub plugged: bool,
+}
+
+/// Predicts the minute of the day at which AC is unplugged, for each weekday starting on Sunday.
+#[must_use]
+pub fn predict_unplugs(history: &[AcEvent]) -> [Option<u16>; 7] {
+    let mut unplugs: [Vec<u16>; 7] = Default::default();
+
+    for events in history.windows(2) {
+        let (plug, unplug) = (events[0], events[1]);
+        if plug.plugged
+            && !unplug.plugged
+            && unplug.time.saturating_sub(plug.time) >= MIN_CHARGE_SECS
+        {
+            unplugs[usize::from(unplug.local.weekday % 7)].push(unplug.local.minute);
+        }
+    }
+
+    unplugs.map(|mut minutes| {
+        if minutes.len() < MIN_SAMPLES {
+            return None;
+        }
+
+        let mut recent = minutes.split_off(minutes.len().saturating_sub(MAX_SAMPLES));
+        recent.sort_unstable();
+        Some(recent[recent.len() / 2])
+    })
+}
+
+/// Minutes from `now` until the battery should start charging to full, or zero if it should be
+/// full now. `None` if no unplug can be predicted.
+#[must_use]
+pub fn minutes_to_top_up(predictions: &[Option<u16>; 7], now: LocalTime) -> Option<u32> {
+    let now = now.week_minute();
+
+    (0u8..)
+        .zip(predictions)
+        .filter_map(|(weekday, minute)| {
+            let unplug = LocalTime { weekday, minute: (*minute)? }.week_minute();
+            let start = (unplug + MINUTES_PER_WEEK - TOP_UP_MINUTES) % MINUTES_PER_WEEK;
+            let since_start = (now + MINUTES_PER_WEEK - start) % MINUTES_PER_WEEK;
+
+            Some(if since_start < TOP_UP_MINUTES + GRACE_MINUTES {
+                0
+            } else {
+                MINUTES_PER_WEEK - since_start
+            })
+        })
+        .min()
+}
+
+/// The thresholds the battery is held at until it is topped up: those of the `max_lifespan`
+/// profile, or else of the profile with the lowest end threshold.
+fn hold_thresholds() -> (u8, u8) {
+    let profiles = get_charge_profiles();
+    profiles
+        .iter()
+        .find(|profile| profile.id == "max_lifespan")
+        .or_else(|| profiles.iter().min_by_key(|profile| profile.end))
+        .map_or(FULL_THRESHOLDS, |profile| (profile.start, profile.end))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SmartCharge {
+    /// The thresholds to restore when smart charging is disabled.
+    previous:   (u8, u8),
+    history:    Vec<AcEvent>,
+    #[serde(skip)]
+    last_ac:    Option<bool>,
+    /// Whether the battery is being topped up, or `None` if the thresholds must be set again.
+    #[serde(skip)]
+    topping_up: Option<bool>,
+}
+
+impl SmartCharge {
+    /// Enables smart charging, which takes over the charge thresholds.
+    pub fn start(previous: (u8, u8)) -> anyhow::Result<Self> {
+        let state = Self { previous, history: Vec::new(), last_ac: None, topping_up: None };
+        state.save()?;
+
+        log::info!("smart charging enabled, previous thresholds are {:?}", previous);
+        Ok(state)
+    }
+
+    /// Resumes smart charging, if it was enabled when the daemon last stopped.
+    pub fn load() -> Option<Self> {
+        let state = fs::read_to_string(STATE_PATH).ok()?;
+
+        match serde_json::from_str::<Self>(&state) {
+            Ok(state) => {
+                log::info!("resuming smart charging with {} AC events", state.history.len());
+                Some(state)
+            }
+            Err(why) => {
+                log::warn!("discarding invalid smart charging state: {}", why);
+                Self::discard();
+                None
+            }
+        }
+    }
+
+    /// Disables smart charging, and restores the thresholds from before it was enabled.
+    pub fn stop(self) {
+        log::info!("smart charging disabled: restoring charge thresholds {:?}", self.previous);
+        if let Err(why) = apply_thresholds(self.previous) {
+            log::error!("failed to restore charge thresholds: {}", why);
+        }
+
+        Self::discard();
+    }
+
+    /// Disables smart charging without touching the thresholds, and forgets the history.
+    pub fn discard() {
+        if Path::new(STATE_PATH).exists() {
+            if let Err(why) = fs::remove_file(STATE_PATH) {
+                log::error!("failed to remove {}: {}", STATE_PATH, why);
+            }
+        }
+    }
+
+    /// The thresholds to restore when smart charging is disabled.
+    pub const fn previous(&self) -> (u8, u8) { self.previous }
+
+    /// The thresholds which smart charging last set, if it has set any since it was resumed.
+    pub fn thresholds(&self) -> Option<(u8, u8)> {
+        self.topping_up.map(|top_up| if top_up { FULL_THRESHOLDS } else { hold_thresholds() })
+    }
+
+    /// Unix time at which the battery is next charged to full, if it can be predicted.
+    pub fn next_full_charge(&self) -> Option<u64> {
+        let now = unix_time();
+        let minutes =
+            minutes_to_top_up(&predict_unplugs(&self.history), LocalTime::from_unix(now))?;
+        Some(now / 60 * 60 + u64::from(minutes) * 60)
+    }
+
+    /// Records AC changes, and switches between the hold and full thresholds.
+    ///
+    /// While `paused`, another feature controls the thresholds, and they are set again afterwards.
+    pub fn step(&mut self, paused: bool) {
+        if let Some(ac) = power_supply::ac_online() {
+            if self.last_ac.is_some_and(|last_ac| last_ac != ac) {
+                self.record(ac);
+            }
+
+            self.last_ac = Some(ac);
+        }
+
+        if paused {
+            self.topping_up = None;
+            return;
+        }
+
+        let now = LocalTime::from_unix(unix_time());
+        let top_up = minutes_to_top_up(&predict_unplugs(&self.history), now) == Some(0);
+        if self.topping_up == Some(top_up) {
+            return;
+        }
+
+        let thresholds = if top_up { FULL_THRESHOLDS } else { hold_thresholds() };
+        log::info!(
+            "smart charging: {} with thresholds {:?}",
+            if top_up { "topping up" } else { "holding" },
+            thresholds
+        );
+
+        match apply_thresholds(thresholds) {
+            Ok(_) => (),
+            Err(why) if why.is::<ChargeThresholdError>() => log::warn!("{}", why),
+            Err(why) => log::error!("smart charging failed to set charge thresholds: {}", why),
+        }
+
+        self.topping_up = Some(top_up);
+    }
+
+    fn record(&mut self, plugged: bool) {
+        let time = unix_time();
+        self.history.push(AcEvent { time, local: LocalTime::from_unix(time), plugged });
+
+        if self.history.len() > MAX_EVENTS {
+            self.history.drain(..self.history.len() - MAX_EVENTS);
+        }
+
+        if let Err(why) = self.save() {
+            log::error!("failed to save smart charging history: {}", why);
+        }
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        if let Some(parent) = Path::new(STATE_PATH).parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(STATE_PATH, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    /// A week of charging overnight and unplugging at the given times, starting on a Sunday.
+    fn week(unplugs: [(u8, u16); 7], start: u64) -> Vec<AcEvent> {
+        let mut history = Vec::new();
+
+        for (day, (hour, minute)) in (0u8..).zip(unplugs) {
+            let midnight = start + u64::from(day) * DAY;
+            let minute = u16::from(hour) * 60 + minute;
+
+            history.push(AcEvent {
+                time:    midnight - 2 * 60 * 60,
+                local:   LocalTime { weekday: (day + 6) % 7, minute: 22 * 60 },
+                plugged: true,
+            });
+            history.push(AcEvent {
+                time:    midnight + u64::from(minute) * 60,
+                local:   LocalTime { weekday: day, minute },
+                plugged: false,
+            });
+        }
+
+        history
+    }
+
+    fn weeks(unplugs: &[[(u8, u16); 7]]) -> Vec<AcEvent> {
+        (0u64..).zip(unplugs).flat_map(|(n, unplugs)| week(*unplugs, (n + 1) * 7 * DAY)).collect()
+    }
+
+    const WORKDAYS: [(u8, u16); 7] = [(10, 0), (8, 0), (8, 0), (8, 0), (8, 0), (8, 0), (11, 30)];
+
+    #[test]
+    fn needs_enough_samples() {
+        assert_eq!(predict_unplugs(&[]), [None; 7]);
+        assert_eq!(predict_unplugs(&weeks(&[WORKDAYS, WORKDAYS])), [None; 7]);
+
+        let predictions = predict_unplugs(&weeks(&[WORKDAYS, WORKDAYS, WORKDAYS]));
+        assert_eq!(predictions[0], Some(600));
+        assert_eq!(predictions[1], Some(480));
+        assert_eq!(predictions[6], Some(690));
+    }
+
+    #[test]
+    fn median_ignores_outliers() {
+        let mut late = WORKDAYS;
+        late[1] = (13, 0);
+
+        let predictions = predict_unplugs(&weeks(&[WORKDAYS, late, WORKDAYS, WORKDAYS]));
+        assert_eq!(predictions[1], Some(480));
+    }
+
+    #[test]
+    fn short_charges_are_ignored() {
+        let mut history = weeks(&[WORKDAYS, WORKDAYS, WORKDAYS]);
+        let last = history.last().unwrap().time;
+
+        // Plugged in over lunch on a Monday.
+        for n in 1..=3 {
+            let time = last + n * 7 * DAY;
+            history.push(AcEvent {
+                time,
+                local: LocalTime { weekday: 1, minute: 12 * 60 },
+                plugged: true,
+            });
+            history.push(AcEvent {
+                time:    time + 60 * 60,
+                local:   LocalTime { weekday: 1, minute: 13 * 60 },
+                plugged: false,
+            });
+        }
+
+        assert_eq!(predict_unplugs(&history)[1], Some(480));
+    }
+
+    #[test]
+    fn top_up_window() {
+        let mut predictions = [None; 7];
+        assert_eq!(minutes_to_top_up(&predictions, LocalTime { weekday: 1, minute: 0 }), None);
+
+        predictions[1] = Some(480);
+        let at = |weekday, minute| minutes_to_top_up(&predictions, LocalTime { weekday, minute });
+
+        assert_eq!(at(1, 0), Some(360));
+        assert_eq!(at(1, 359), Some(1));
+        assert_eq!(at(1, 360), Some(0));
+        assert_eq!(at(1, 480), Some(0));
+        assert_eq!(at(1, 539), Some(0));
+        assert_eq!(at(1, 540), Some(MINUTES_PER_WEEK - 180));
+        assert_eq!(at(0, 1380), Some(420));
+    }
+
+    #[test]
+    fn top_up_wraps_around_the_week() {
+        let mut predictions = [None; 7];
+        predictions[0] = Some(30);
+
+        assert_eq!(
+            minutes_to_top_up(&predictions, LocalTime { weekday: 6, minute: 1380 }),
+            Some(0)
+        );
+        assert_eq!(
+            minutes_to_top_up(&predictions, LocalTime { weekday: 6, minute: 1320 }),
+            Some(30)
+        );
+        assert_eq!(minutes_to_top_up(&predictions, LocalTime { weekday: 0, minute: 60 }), Some(0));
+    }
+}
@@ -21,6 +21,18 @@ pub enum ProfileError {
     ScsiHost(#[from] ScsiHostError),
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum ChargeThresholdError {
+    #[error(
+        "hardware reports charge thresholds {}-{} instead of the requested {}-{}",
+        actual.0,
+        actual.1,
+        requested.0,
+        requested.1
+    )]
+    Mismatch { requested: (u8, u8), actual: (u8, u8) },
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum BacklightError {
     #[error("failed to set backlight on {0}: {1}")]
@@ -0,0 +1,347 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Measures the lowest duty at which each fan reliably starts, and its fastest speed, so that the
+//! curves do not drive a fan at a duty where it stalls.
+//!
+//! Every fan runs at full speed to find its fastest speed, is stopped, and is then stepped up
+//! from 5% until it spins. The duty one step above is kept as the fan's minimum. A fan which still
+//! spins after the stop phase has been extended, such as one whose controller enforces a minimum
+//! speed, is left uncalibrated.
+
+use super::channel::STALL_RPM;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// Where the results are kept across restarts.
+const CALIBRATION_FILE: &str = "/var/lib/system76-power/fan-calibration.json";
+
+/// How many steps the fans run at full speed, before their speed is sampled.
+const FULL_STEPS: u32 = 8;
+
+/// How many steps the fans are stopped for, before they are stepped up.
+const STOP_STEPS: u32 = 10;
+
+/// How many steps the fans may be stopped for, while one is still coasting.
+const MAX_STOP_STEPS: u32 = 30;
+
+/// How many steps each duty is held for, before the speed is sampled.
+const HOLD_STEPS: u32 = 4;
+
+/// The duty, in percent, by which the fans are stepped up.
+const DUTY_STEP: u8 = 5;
+
+/// The measured limits of a fan.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FanCalibration {
+    pub channel:  u8,
+    /// The lowest duty at which the fan reliably starts, from 0 to 255.
+    pub min_duty: u8,
+    /// The speed at full duty, in RPM.
+    pub max_rpm:  u32,
+}
+
+impl FanCalibration {
+    /// Raises a duty from 0 to 255 to the calibrated minimum, unless it stops the fan.
+    pub fn clamp(&self, duty: u8) -> u8 {
+        if duty == 0 {
+            0
+        } else {
+            duty.max(self.min_duty)
+        }
+    }
+}
+
+/// What the calibration needs next.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CalibrationStep {
+    /// The duty of each channel until the next step, from 0 to 255.
+    Drive(Vec<(u8, u8)>),
+    /// The fans which could be calibrated, if any.
+    Finished(Vec<FanCalibration>),
+}
+
+#[derive(Debug)]
+struct ChannelState {
+    channel:  u8,
+    max_rpm:  u32,
+    /// The duty in percent at which the fan started.
+    start:    Option<u8>,
+    /// Whether the fan still spun at the end of the stop phase, so that its start can not be
+    /// measured.
+    spinning: bool,
+}
+
+/// Steps the duty of every channel at once, once per second.
+#[derive(Debug)]
+pub struct Calibrator {
+    channels:   Vec<ChannelState>,
+    /// Steps since the calibration started.
+    steps:      u32,
+    /// How many steps the fans are stopped for, extended while one is still coasting.
+    stop_steps: u32,
+    cancelled:  bool,
+}
+
+impl Calibrator {
+    pub fn new(channels: impl IntoIterator<Item = u8>) -> Self {
+        let channels = channels
+            .into_iter()
+            .map(|channel| ChannelState { channel, max_rpm: 0, start: None, spinning: false })
+            .collect();
+        Self { channels, steps: 0, stop_steps: STOP_STEPS, cancelled: false }
+    }
+
+    /// Stops at the next step, without results.
+    pub fn cancel(&mut self) { self.cancelled = true; }
+
+    /// How far the calibration is, from 0 to 1.
+    pub fn progress(&self) -> f32 {
+        let total = FULL_STEPS + self.stop_steps + u32::from(100 / DUTY_STEP) * HOLD_STEPS;
+        (self.steps as f32 / total as f32).min(1.0)
+    }
+
+    /// Takes the speed of each fan at the duty of the previous step, and returns the duties for
+    /// the next one.
+    pub fn step(&mut self, rpms: &[(u8, u32)]) -> CalibrationStep {
+        if self.cancelled {
+            return CalibrationStep::Finished(Vec::new());
+        }
+
+        let rpm = |channel| rpms.iter().find(|&&(c, _)| c == channel).map_or(0, |&(_, rpm)| rpm);
+        let step = self.steps;
+        self.steps += 1;
+
+        let percent = if step < FULL_STEPS {
+            // The fans have reached their speed by the last steps at full duty.
+            if step >= FULL_STEPS / 2 {
+                for state in &mut self.channels {
+                    state.max_rpm = state.max_rpm.max(rpm(state.channel));
+                }
+            }
+            100
+        } else if step < FULL_STEPS + self.stop_steps {
+            0
+        } else {
+            // Every fan must have stopped, or the duty it spins at would be taken as its start.
+            if step == FULL_STEPS + self.stop_steps {
+                let coasting = self.channels.iter().any(|state| rpm(state.channel) >= STALL_RPM);
+                if coasting && self.stop_steps < MAX_STOP_STEPS {
+                    self.stop_steps += 1;
+                    return self.drive(0);
+                }
+
+                for state in &mut self.channels {
+                    let rpm = rpm(state.channel);
+                    if rpm >= STALL_RPM {
+                        log::warn!(
+                            "fan calibration: fan {} did not stop ({} RPM), skipping it",
+                            state.channel,
+                            rpm
+                        );
+                        state.spinning = true;
+                    }
+                }
+            }
+
+            let rising = step - FULL_STEPS - self.stop_steps;
+            let held = rising / HOLD_STEPS;
+
+            // The speed is sampled at the end of each hold, at the duty of the previous step.
+            if rising % HOLD_STEPS == 0 && held > 0 {
+                let previous = (held as u8) * DUTY_STEP;
+                for state in self
+                    .channels
+                    .iter_mut()
+                    .filter(|state| state.start.is_none() && !state.spinning)
+                {
+                    if rpm(state.channel) >= STALL_RPM {
+                        state.start = Some(previous);
+                    }
+                }
+            }
+
+            let percent = (held as u8 + 1) * DUTY_STEP;
+            if percent > 100
+                || self.channels.iter().all(|state| state.start.is_some() || state.spinning)
+            {
+                return CalibrationStep::Finished(self.results());
+            }
+
+            percent
+        };
+
+        self.drive(percent)
+    }
+
+    /// Drives every channel at a duty in percent.
+    fn drive(&self, percent: u8) -> CalibrationStep {
+        let duty = percent_to_duty(percent);
+        CalibrationStep::Drive(self.channels.iter().map(|state| (state.channel, duty)).collect())
+    }
+
+    fn results(&self) -> Vec<FanCalibration> {
+        self.channels
+            .iter()
+            .filter(|state| !state.spinning)
+            .filter_map(|state| {
+                let Some(start) = state.start else {
+                    log::warn!("fan calibration: fan {} did not spin", state.channel);
+                    return None;
+                };
+
+                let min_duty = percent_to_duty((start + DUTY_STEP).min(100));
+                Some(FanCalibration { channel: state.channel, min_duty, max_rpm: state.max_rpm })
+            })
+            .collect()
+    }
+}
+
+fn percent_to_duty(percent: u8) -> u8 { ((u32::from(percent) * 255) / 100) as u8 }
+
+/// The calibration of each fan, if it was calibrated.
+pub fn load() -> Vec<FanCalibration> {
+    let Ok(calibration) = fs::read_to_string(CALIBRATION_FILE) else {
+        return Vec::new();
+    };
+
+    serde_json::from_str(&calibration).unwrap_or_else(|why| {
+        log::warn!("ignoring invalid fan calibration: {}", why);
+        Vec::new()
+    })
+}
+
+/// Keeps the calibration of each fan across restarts.
+pub fn save(calibrations: &[FanCalibration]) {
+    let result = Path::new(CALIBRATION_FILE)
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(CALIBRATION_FILE, serde_json::to_string(calibrations)?));
+
+    if let Err(why) = result {
+        log::error!("failed to save fan calibration to {}: {}", CALIBRATION_FILE, why);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fan which starts at a duty in percent, and whose speed then rises with the duty.
+    struct SimulatedFan {
+        channel: u8,
+        start:   u8,
+        max_rpm: u32,
+        /// How many steps the fan coasts for after it is stopped.
+        coast:   u32,
+        /// The speed which the controller keeps the fan at, even when it is stopped.
+        min_rpm: u32,
+        /// How many steps the fan has been stopped for.
+        stopped: u32,
+    }
+
+    impl SimulatedFan {
+        fn new(channel: u8, start: u8, max_rpm: u32) -> Self {
+            Self { channel, start, max_rpm, coast: 0, min_rpm: 0, stopped: 0 }
+        }
+
+        fn rpm(&mut self, duty: u8) -> u32 {
+            let rpm = if u32::from(duty) * 100 < u32::from(self.start) * 255 {
+                self.stopped += 1;
+                if self.stopped <= self.coast {
+                    self.max_rpm / 4
+                } else {
+                    0
+                }
+            } else {
+                self.stopped = 0;
+                self.max_rpm * u32::from(duty) / 255
+            };
+
+            rpm.max(self.min_rpm)
+        }
+    }
+
+    fn calibrate(fans: &mut [SimulatedFan]) -> (Vec<FanCalibration>, u32) {
+        let mut calibrator = Calibrator::new(fans.iter().map(|fan| fan.channel));
+        let mut rpms = Vec::new();
+
+        for steps in 0.. {
+            match calibrator.step(&rpms) {
+                CalibrationStep::Drive(duties) => {
+                    rpms = fans
+                        .iter_mut()
+                        .zip(&duties)
+                        .map(|(fan, &(channel, duty))| (channel, fan.rpm(duty)))
+                        .collect();
+                }
+                CalibrationStep::Finished(results) => return (results, steps),
+            }
+        }
+
+        unreachable!()
+    }
+
+    #[test]
+    fn calibrated_fans() {
+        let (results, steps) =
+            calibrate(&mut [SimulatedFan::new(1, 22, 2000), SimulatedFan::new(2, 41, 3200)]);
+
+        // Each fan starts at the first 5% step at or above its threshold, plus a step of margin.
+        assert_eq!(
+            results,
+            vec![
+                FanCalibration { channel: 1, min_duty: percent_to_duty(30), max_rpm: 2000 },
+                FanCalibration { channel: 2, min_duty: percent_to_duty(50), max_rpm: 3200 },
+            ]
+        );
+
+        // The calibration stops once every fan has started.
+        assert_eq!(steps, FULL_STEPS + STOP_STEPS + 9 * HOLD_STEPS);
+
+        let clamp = results[1];
+        assert_eq!(clamp.clamp(0), 0);
+        assert_eq!(clamp.clamp(1), percent_to_duty(50));
+        assert_eq!(clamp.clamp(255), 255);
+    }
+
+    #[test]
+    fn failed_calibration() {
+        // A fan which never spins is left out.
+        let (results, _) =
+            calibrate(&mut [SimulatedFan::new(1, 30, 1800), SimulatedFan::new(2, 101, 1800)]);
+        assert_eq!(results.iter().map(|result| result.channel).collect::<Vec<_>>(), vec![1]);
+
+        let mut calibrator = Calibrator::new([1]);
+        assert!(matches!(calibrator.step(&[]), CalibrationStep::Drive(_)));
+        calibrator.cancel();
+        assert_eq!(calibrator.step(&[]), CalibrationStep::Finished(Vec::new()));
+    }
+
+    #[test]
+    fn spinning_when_stopped() {
+        // The stop phase is extended until a coasting fan has stopped.
+        let mut fans = [SimulatedFan::new(1, 22, 2000), SimulatedFan::new(2, 41, 3200)];
+        fans[1].coast = STOP_STEPS + 4;
+        let (results, steps) = calibrate(&mut fans);
+        assert_eq!(
+            results,
+            vec![
+                FanCalibration { channel: 1, min_duty: percent_to_duty(30), max_rpm: 2000 },
+                FanCalibration { channel: 2, min_duty: percent_to_duty(50), max_rpm: 3200 },
+            ]
+        );
+        assert_eq!(steps, FULL_STEPS + STOP_STEPS + 5 + 9 * HOLD_STEPS);
+
+        // A fan which never stops is left out, rather than taken to start at 5%.
+        let mut fans = [SimulatedFan::new(1, 22, 2000), SimulatedFan::new(2, 41, 3200)];
+        fans[1].min_rpm = 600;
+        let (results, steps) = calibrate(&mut fans);
+        assert_eq!(
+            results,
+            vec![FanCalibration { channel: 1, min_duty: percent_to_duty(30), max_rpm: 2000 }]
+        );
+        assert_eq!(steps, FULL_STEPS + MAX_STOP_STEPS + 5 * HOLD_STEPS);
+    }
+}
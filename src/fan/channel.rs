@@ -0,0 +1,361 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Each PWM channel of the platform hwmon can follow its own curve and its own sensors.
+
+use super::{FanCalibration, FanController, FanCurve, PidController};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Below this duty, from 0 to 255, a fan may not spin, so its speed says nothing about whether it
+/// has failed.
+pub const SPIN_UP_DUTY: u8 = 77;
+
+/// Below this speed, in RPM, a fan which should be spinning is stalled.
+pub const STALL_RPM: u32 = 100;
+
+/// How many consecutive samples a fan must be stalled for before it is considered failed.
+pub const STALL_SAMPLES: u32 = 5;
+
+/// A calibrated fan is stalled below this fraction of the speed expected at its duty.
+const STALL_FRACTION: u32 = 4;
+
+/// Built-in sensors which a fan can follow.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorGroup {
+    Cpu,
+    Gpu,
+}
+
+/// A group of built-in sensors, or a sensor configured by name.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(from = "String")]
+pub enum SensorRef {
+    Group(SensorGroup),
+    Named(String),
+}
+
+impl From<String> for SensorRef {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "cpu" => Self::Group(SensorGroup::Cpu),
+            "gpu" => Self::Group(SensorGroup::Gpu),
+            _ => Self::Named(name),
+        }
+    }
+}
+
+/// How the temperatures of a fan's sensors are combined into one.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    /// The hottest sensor.
+    #[default]
+    Max,
+    /// The average of the sensors, by weight.
+    Average,
+    /// The hottest sensor, after scaling each by its weight.
+    WeightedMax,
+    /// The sensor which asks for the highest duty from its own curve.
+    CurveMax,
+}
+
+/// Temperatures read from each sensor, in thousandths of a degree Celsius.
+#[derive(Debug, Default)]
+pub struct Temperatures {
+    pub cpu:   Vec<u32>,
+    pub gpu:   Vec<u32>,
+    /// Sensors configured by name, with their offsets applied.
+    pub named: BTreeMap<String, Vec<u32>>,
+}
+
+impl Temperatures {
+    pub fn get(&self, sensor: &SensorRef) -> &[u32] {
+        match sensor {
+            SensorRef::Group(SensorGroup::Cpu) => &self.cpu,
+            SensorRef::Group(SensorGroup::Gpu) => &self.gpu,
+            SensorRef::Named(name) => self.named.get(name).map_or(&[], Vec::as_slice),
+        }
+    }
+
+    /// Adds the readings of a sensor.
+    pub fn extend(&mut self, sensor: SensorRef, temps: Vec<u32>) {
+        match sensor {
+            SensorRef::Group(SensorGroup::Cpu) => self.cpu.extend(temps),
+            SensorRef::Group(SensorGroup::Gpu) => self.gpu.extend(temps),
+            SensorRef::Named(name) => self.named.entry(name).or_default().extend(temps),
+        }
+    }
+
+    /// The highest temperature of any CPU or GPU.
+    pub fn max(&self) -> Option<u32> { self.cpu.iter().chain(&self.gpu).copied().max() }
+
+    /// Combines the temperatures of the given sensors. A channel's curve is needed to compare the
+    /// duties of sensors with their own curves.
+    pub fn aggregate(
+        &self,
+        sensors: &[FanSensor],
+        aggregation: Aggregation,
+        curve: &FanCurve,
+    ) -> Option<u32> {
+        let readings = sensors
+            .iter()
+            .flat_map(|sensor| self.get(&sensor.sensor).iter().map(move |&temp| (sensor, temp)));
+
+        match aggregation {
+            Aggregation::Max => readings.map(|(_, temp)| temp).max(),
+            Aggregation::Average => {
+                let (sum, weights) = readings.fold((0.0, 0.0), |(sum, weights), (sensor, temp)| {
+                    (sum + f64::from(temp) * f64::from(sensor.weight), weights + sensor.weight)
+                });
+                (weights > 0.0).then(|| (sum / f64::from(weights)) as u32)
+            }
+            Aggregation::WeightedMax => {
+                readings.map(|(sensor, temp)| (temp as f32 * sensor.weight) as u32).max()
+            }
+            // Each duty is converted back to the temperature at which the channel's curve gives
+            // it, so that the channel's smoothing and hysteresis still apply.
+            Aggregation::CurveMax => readings
+                .filter_map(|(sensor, temp)| {
+                    let duty =
+                        sensor.curve.as_ref().unwrap_or(curve).get_duty((temp / 10) as i16)?;
+                    curve.temp_for_duty(duty)
+                })
+                .max()
+                .map(|temp| (i32::from(temp) * 10).max(0) as u32),
+        }
+    }
+}
+
+/// A sensor which a fan follows, and how much it counts.
+#[derive(Debug)]
+pub struct FanSensor {
+    pub sensor: SensorRef,
+    /// Scales the temperature for the `weighted_max` and `average` aggregations.
+    pub weight: f32,
+    /// Replaces the channel's curve for the `curve_max` aggregation.
+    pub curve:  Option<FanCurve>,
+}
+
+impl FanSensor {
+    pub fn new(sensor: SensorRef) -> Self { Self { sensor, weight: 1.0, curve: None } }
+}
+
+/// A `pwmN` channel of the platform hwmon, and the curve and sensors it follows.
+#[derive(Debug)]
+pub struct FanChannel {
+    pub channel:     u8,
+    /// Replaces the curve selected for all fans.
+    pub curve:       Option<(String, FanCurve)>,
+    pub sensors:     Vec<FanSensor>,
+    pub aggregation: Aggregation,
+    pub controller:  FanController,
+    /// Holds the temperature at a target, instead of following a curve.
+    pub target:      Option<PidController>,
+    pub stall:       StallDetector,
+}
+
+impl FanChannel {
+    /// A channel which follows the curve selected for all fans, and the hottest sensor.
+    pub fn new(channel: u8) -> Self {
+        Self {
+            channel,
+            curve: None,
+            sensors: vec![
+                FanSensor::new(SensorRef::Group(SensorGroup::Cpu)),
+                FanSensor::new(SensorRef::Group(SensorGroup::Gpu)),
+            ],
+            aggregation: Aggregation::Max,
+            controller: FanController::default(),
+            target: None,
+            stall: StallDetector::default(),
+        }
+    }
+
+    /// The temperature this channel follows on a curve. Falls back to the hottest sensor if none
+    /// of its own sensors could be read, such as a GPU which was removed.
+    pub fn temp(&self, temps: &Temperatures, curve: &FanCurve) -> Option<u32> {
+        temps.aggregate(&self.sensors, self.aggregation, curve).or_else(|| temps.max())
+    }
+}
+
+/// Notices a fan which does not spin, or spins far slower than it should, while it is driven
+/// above its spin-up duty.
+#[derive(Debug)]
+pub struct StallDetector {
+    /// Consecutive samples in which the fan was stalled.
+    stalls:  u32,
+    failed:  bool,
+    /// The duty, from 0 to 255, above which the fan should spin.
+    spin_up: u8,
+    /// The speed of the fan at full duty, in RPM, if it was calibrated.
+    max_rpm: Option<u32>,
+}
+
+impl Default for StallDetector {
+    fn default() -> Self { Self { stalls: 0, failed: false, spin_up: SPIN_UP_DUTY, max_rpm: None } }
+}
+
+impl StallDetector {
+    /// Uses the spin-up duty and the fastest speed measured by calibrating the fan, or the
+    /// defaults if it was not calibrated.
+    pub fn set_calibration(&mut self, calibration: Option<&FanCalibration>) {
+        self.spin_up = calibration.map_or(SPIN_UP_DUTY, |calibration| calibration.min_duty);
+        self.max_rpm = calibration.map(|calibration| calibration.max_rpm);
+    }
+
+    /// The speed, in RPM, below which the fan is stalled at a duty.
+    fn stall_rpm(&self, duty: u8) -> u32 {
+        let expected = self.max_rpm.map_or(0, |max_rpm| max_rpm * u32::from(duty) / 255);
+        STALL_RPM.max(expected / STALL_FRACTION)
+    }
+
+    /// Checks the speed of the fan against the duty it was set to, and returns whether it has just
+    /// been found to have failed.
+    pub fn check(&mut self, duty: u8, rpm: u32) -> bool {
+        if duty < self.spin_up {
+            // The fan may stand still on purpose, which interrupts a stall.
+            self.stalls = 0;
+            if rpm >= STALL_RPM {
+                self.failed = false;
+            }
+        } else if rpm >= self.stall_rpm(duty) {
+            self.stalls = 0;
+            self.failed = false;
+        } else {
+            self.stalls += 1;
+        }
+
+        if !self.failed && self.stalls >= STALL_SAMPLES {
+            self.failed = true;
+            return true;
+        }
+
+        false
+    }
+
+    /// Whether the fan has failed, and has not spun since.
+    pub fn failed(&self) -> bool { self.failed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fan::{FanConfig, FanConfigError};
+
+    #[test]
+    fn stall_detection() {
+        let mut stall = StallDetector::default();
+
+        // A fan below its spin-up duty may stand still.
+        for _ in 0..10 {
+            assert!(!stall.check(0, 0));
+        }
+
+        // A fan driven above it which does not spin fails after a few samples, and only once.
+        let checks = (0..10).map(|_| stall.check(128, 0)).collect::<Vec<_>>();
+        assert_eq!(checks.iter().filter(|&&failed| failed).count(), 1);
+        assert!(checks[usize::try_from(STALL_SAMPLES).unwrap() - 1]);
+        assert!(stall.failed());
+
+        // It recovers once it spins again.
+        assert!(!stall.check(255, 1200));
+        assert!(!stall.failed());
+
+        // A fan which spins up after a short delay is not failed.
+        let mut stall = StallDetector::default();
+        assert!(!stall.check(128, 0));
+        assert!(!stall.check(128, 0));
+        assert!(!stall.check(128, 800));
+        assert!(!stall.failed());
+    }
+
+    #[test]
+    fn interrupted_stall() {
+        let mut stall = StallDetector::default();
+
+        // Stalls separated by the fan being stopped on purpose are not consecutive.
+        for _ in 0..3 {
+            for _ in 1..STALL_SAMPLES {
+                assert!(!stall.check(128, 0));
+            }
+            assert!(!stall.check(0, 0));
+        }
+        assert!(!stall.failed());
+    }
+
+    #[test]
+    fn calibrated_stall() {
+        let mut stall = StallDetector::default();
+        stall.set_calibration(Some(&FanCalibration { channel: 1, min_duty: 51, max_rpm: 2000 }));
+
+        // Below the calibrated spin-up duty, the fan may stand still.
+        assert!((0..10).all(|_| !stall.check(40, 0)));
+
+        // A fan which spins, but far slower than it should at its duty, has failed.
+        let checks = (0..STALL_SAMPLES).map(|_| stall.check(255, 300)).collect::<Vec<_>>();
+        assert_eq!(checks.last(), Some(&true));
+
+        // Once it reaches a reasonable speed, it has recovered.
+        assert!(!stall.check(255, 1500));
+        assert!(!stall.failed());
+        assert!((0..10).all(|_| !stall.check(128, 700)));
+    }
+
+    #[test]
+    fn configured_sensors() {
+        let config: FanConfig = toml::from_str(
+            r#"
+            [curves.nvme]
+            points = [[40, 20], [60, 100]]
+
+            [[sensors]]
+            name = "nvme"
+            driver = "nvme"
+            label = "Composite"
+            weight = 1.5
+            curve = "nvme"
+
+            [[fans]]
+            channel = 1
+            sensors = ["cpu", "nvme"]
+            aggregation = "weighted_max"
+
+            [[fans]]
+            channel = 2
+            sensors = ["cpu", "nvme"]
+            aggregation = "curve_max"
+
+            [[fans]]
+            channel = 3
+            sensors = ["cpu", "nvme"]
+            aggregation = "average"
+
+            [[fans]]
+            channel = 4
+            sensors = ["vrm"]
+            "#,
+        )
+        .unwrap();
+
+        let mut temps = Temperatures { cpu: vec![60_000], ..Temperatures::default() };
+        temps.extend(SensorRef::Named("nvme".into()), vec![50_000]);
+        let curve = FanCurve::standard();
+
+        // The NVMe drive counts as 75°C.
+        let weighted = config.channel(1).unwrap();
+        assert_eq!(weighted.temp(&temps, &curve), Some(75_000));
+
+        // The NVMe curve asks for 60% at 50°C, which the standard curve gives at 78°C, while the
+        // CPU asks for 37.5%, which it gives at 60°C.
+        let curve_max = config.channel(2).unwrap();
+        assert_eq!(curve_max.temp(&temps, &curve), Some(78_000));
+
+        let average = config.channel(3).unwrap();
+        assert_eq!(average.temp(&temps, &curve), Some(54_000));
+
+        assert!(matches!(config.channel(4), Err(FanConfigError::UnknownSensor(_))));
+    }
+}
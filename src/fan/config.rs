@@ -0,0 +1,623 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Fan curves configured by the administrator in `/etc/system76-power/fan.toml`.
+//!
+//! ```toml
+//! # The curve to use instead of the default for this model.
+//! curve = "quiet"
+//!
+//! # Pairs of a temperature in degrees Celsius and a fan duty in percent.
+//! [curves.quiet]
+//! points = [[50, 0], [55, 25], [70, 40], [80, 70], [88, 100]]
+//! # Optional: the weight of each second's temperature in its moving average (0-1), the fastest
+//! # the duty may rise and fall in %/s, and how many degrees the temperature must fall before the
+//! # duty is lowered.
+//! smoothing = 0.25
+//! ramp_up = 10
+//! ramp_down = 2
+//! hysteresis = 2
+//! # Optional: allow the duty to fall from one point to the next.
+//! descending = false
+//!
+//! # Optional: the curve of each power profile, which replaces `curve` above.
+//! [profiles]
+//! battery = "quiet"
+//! performance = "hedt"
+//!
+//! # Optional: above the critical temperature in degrees Celsius, the fans run at full speed and
+//! # the CPU is limited as in the Battery profile, until the temperature falls below the recovery
+//! # temperature. By default, the critical temperature is 5 degrees below the lowest `temp*_crit`
+//! # of the sensors, or 95 degrees, and the recovery temperature is 10 degrees below it.
+//! [emergency]
+//! critical = 90
+//! recovery = 80
+//!
+//! # Optional: hwmon sensors besides those of CPUs and GPUs, chosen by driver and by the label of
+//! # the sensor, or else its first sensor. The offset in degrees is added to each reading, the
+//! # weight scales it for the `weighted_max` and `average` aggregations, and the curve replaces the
+//! # curve of the fan for the `curve_max` aggregation.
+//! [[sensors]]
+//! name = "nvme"
+//! driver = "nvme"
+//! label = "Composite"
+//! offset = 0
+//! weight = 1.2
+//! curve = "quiet"
+//!
+//! # Optional: the curve and sensors of each `pwmN` channel. Channels which are not listed follow
+//! # the curve above and the hottest sensor.
+//! [[fans]]
+//! channel = 1
+//! sensors = ["cpu"]
+//!
+//! [[fans]]
+//! channel = 2
+//! curve = "hedt"
+//! sensors = ["cpu", "gpu", "nvme"]
+//! # One of "max", "average", "weighted_max" or "curve_max".
+//! aggregation = "curve_max"
+//!
+//! # Optional: instead of a curve, hold the temperature of the channel's sensors at a target in
+//! # degrees Celsius with the lowest duty which does so. The gains are in percent of duty per
+//! # degree above the target (kp), per degree each second (ki), and per degree per second that
+//! # the temperature rises (kd).
+//! [[fans]]
+//! channel = 3
+//! sensors = ["cpu"]
+//! target = { temperature = 75, kp = 5, ki = 0.2, kd = 0, min_duty = 20, max_duty = 100 }
+//! ```
+
+use super::{
+    channel::{Aggregation, FanChannel, FanSensor, SensorRef},
+    pid::{PidController, PidTuning},
+    sources::HwmonSource,
+    uevent::HwmonTree,
+    FanCurve, FanCurveError, FanTuning,
+};
+use crate::Profile;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+use sysfs_class::HwMon;
+
+pub const FAN_CONFIG: &str = "/etc/system76-power/fan.toml";
+
+/// The curve selected over D-Bus, which is kept across restarts.
+const SELECTED_CURVE: &str = "/var/lib/system76-power/fan-curve.json";
+
+#[derive(Debug, thiserror::Error)]
+pub enum FanConfigError {
+    #[error("failed to read {}: {}", FAN_CONFIG, _0)]
+    Read(io::Error),
+    #[error("failed to parse {}: {}", FAN_CONFIG, _0)]
+    Parse(toml::de::Error),
+    #[error("fan curve '{}' is invalid: {}", _0, _1)]
+    InvalidCurve(String, FanCurveError),
+    #[error("no such fan curve '{}'", _0)]
+    UnknownCurve(String),
+    #[error("fan channel {} is invalid: {}", _0, _1)]
+    InvalidChannel(u8, &'static str),
+    #[error("thermal emergency thresholds are invalid: {}", _0)]
+    InvalidEmergency(&'static str),
+    #[error("sensor '{}' is invalid: {}", _0, _1)]
+    InvalidSensor(String, &'static str),
+    #[error("no such sensor '{}'", _0)]
+    UnknownSensor(String),
+    #[error("failed to read {}: {}", _0.display(), _1)]
+    ReadCurve(PathBuf, io::Error),
+    #[error("failed to parse {}: {}", _0.display(), _1)]
+    ParseCurve(PathBuf, toml::de::Error),
+}
+
+/// How far below the hardware's critical temperature a thermal emergency begins, in thousandths
+/// of a degree Celsius.
+const CRIT_MARGIN: u32 = 5_000;
+
+/// The critical temperature when neither the config nor the hardware gives one.
+const DEFAULT_CRITICAL: u32 = 95_000;
+
+/// How far below the critical temperature a thermal emergency ends, by default.
+const DEFAULT_HYSTERESIS: u32 = 10_000;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FanConfig {
+    curve:     Option<String>,
+    #[serde(default)]
+    curves:    BTreeMap<String, CurveConfig>,
+    #[serde(default)]
+    profiles:  ProfileCurves,
+    #[serde(default)]
+    emergency: EmergencyConfig,
+    #[serde(default)]
+    sensors:   Vec<SensorConfig>,
+    #[serde(default)]
+    fans:      Vec<ChannelConfig>,
+}
+
+/// A hwmon sensor chosen by driver and label.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SensorConfig {
+    name:   String,
+    driver: String,
+    label:  Option<String>,
+    #[serde(default)]
+    offset: f32,
+    #[serde(default = "default_weight")]
+    weight: f32,
+    curve:  Option<String>,
+}
+
+const fn default_weight() -> f32 { 1.0 }
+
+/// Temperatures in degrees Celsius at which a thermal emergency begins and ends.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EmergencyConfig {
+    critical: Option<f32>,
+    recovery: Option<f32>,
+}
+
+/// The curve chosen for each power profile.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileCurves {
+    battery:     Option<String>,
+    balanced:    Option<String>,
+    performance: Option<String>,
+}
+
+impl ProfileCurves {
+    fn get(&self, profile: Profile) -> Option<&String> {
+        match profile {
+            Profile::Battery => self.battery.as_ref(),
+            Profile::Balanced => self.balanced.as_ref(),
+            Profile::Performance => self.performance.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChannelConfig {
+    channel:     u8,
+    curve:       Option<String>,
+    sensors:     Option<Vec<SensorRef>>,
+    #[serde(default)]
+    aggregation: Aggregation,
+    target:      Option<TargetConfig>,
+}
+
+/// A temperature which a fan holds, rather than following a curve.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetConfig {
+    temperature: f32,
+    kp:          Option<f32>,
+    ki:          Option<f32>,
+    kd:          Option<f32>,
+    min_duty:    Option<f32>,
+    max_duty:    Option<f32>,
+}
+
+impl TargetConfig {
+    fn to_tuning(&self) -> PidTuning {
+        let defaults = PidTuning::default();
+        PidTuning {
+            target:   self.temperature,
+            kp:       self.kp.unwrap_or(defaults.kp),
+            ki:       self.ki.unwrap_or(defaults.ki),
+            kd:       self.kd.unwrap_or(defaults.kd),
+            min_duty: self.min_duty.unwrap_or(defaults.min_duty),
+            max_duty: self.max_duty.unwrap_or(defaults.max_duty),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CurveConfig {
+    points:     Vec<(f32, f32)>,
+    #[serde(default)]
+    descending: bool,
+    smoothing:  Option<f32>,
+    ramp_up:    Option<f32>,
+    ramp_down:  Option<f32>,
+    hysteresis: Option<f32>,
+}
+
+impl CurveConfig {
+    fn to_curve(&self) -> Result<FanCurve, FanCurveError> {
+        let defaults = FanTuning::default();
+        let mut curve = FanCurve::builder().allow_descending(self.descending).tuning(FanTuning {
+            smoothing:  self.smoothing.unwrap_or(defaults.smoothing),
+            ramp_up:    self.ramp_up.unwrap_or(defaults.ramp_up),
+            ramp_down:  self.ramp_down.unwrap_or(defaults.ramp_down),
+            hysteresis: self.hysteresis.unwrap_or(defaults.hysteresis),
+        });
+
+        for &(temp, duty) in &self.points {
+            if !(-100.0..=300.0).contains(&temp) {
+                return Err(FanCurveError::TemperatureOutOfRange(temp));
+            }
+
+            if !(0.0..=100.0).contains(&duty) {
+                return Err(FanCurveError::DutyOutOfRange(duty));
+            }
+
+            curve = curve.point((temp * 100.0).round() as i16, (duty * 100.0).round() as u16);
+        }
+
+        curve.build()
+    }
+}
+
+impl FanConfig {
+    /// Reads the config, with every curve validated. A missing file gives the defaults.
+    pub fn load() -> Result<Self, FanConfigError> {
+        let config = match fs::read_to_string(FAN_CONFIG) {
+            Ok(config) => config,
+            Err(why) if why.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(why) => return Err(FanConfigError::Read(why)),
+        };
+
+        let config: Self = toml::from_str(&config).map_err(FanConfigError::Parse)?;
+
+        for (name, curve) in &config.curves {
+            curve.to_curve().map_err(|why| FanConfigError::InvalidCurve(name.clone(), why))?;
+        }
+
+        let profiles = &config.profiles;
+        for name in [&config.curve, &profiles.battery, &profiles.balanced, &profiles.performance]
+            .into_iter()
+            .flatten()
+        {
+            config.curve(name)?;
+        }
+
+        let emergency = &config.emergency;
+        for temp in [emergency.critical, emergency.recovery].into_iter().flatten() {
+            if !(0.0..=150.0).contains(&temp) {
+                return Err(FanConfigError::InvalidEmergency("should be within 0-150°C"));
+            }
+        }
+
+        if let (Some(critical), Some(recovery)) = (emergency.critical, emergency.recovery) {
+            if recovery >= critical {
+                return Err(FanConfigError::InvalidEmergency("recovery must be below critical"));
+            }
+        }
+
+        for (n, sensor) in config.sensors.iter().enumerate() {
+            let invalid = |why| Err(FanConfigError::InvalidSensor(sensor.name.clone(), why));
+
+            if matches!(SensorRef::from(sensor.name.clone()), SensorRef::Group(_)) {
+                return invalid("the name is reserved for built-in sensors");
+            }
+
+            if config.sensors[..n].iter().any(|other| other.name == sensor.name) {
+                return invalid("configured twice");
+            }
+
+            if !(-50.0..=50.0).contains(&sensor.offset) {
+                return invalid("the offset should be within -50-50°C");
+            }
+
+            if !(sensor.weight > 0.0 && sensor.weight.is_finite()) {
+                return invalid("the weight must be above 0");
+            }
+
+            if let Some(ref name) = sensor.curve {
+                config.curve(name)?;
+            }
+        }
+
+        for (n, fan) in config.fans.iter().enumerate() {
+            if fan.channel == 0 {
+                return Err(FanConfigError::InvalidChannel(0, "channels are numbered from 1"));
+            }
+
+            if config.fans[..n].iter().any(|other| other.channel == fan.channel) {
+                return Err(FanConfigError::InvalidChannel(fan.channel, "configured twice"));
+            }
+
+            if fan.sensors.as_ref().is_some_and(Vec::is_empty) {
+                return Err(FanConfigError::InvalidChannel(fan.channel, "no sensors"));
+            }
+
+            if let Some(ref target) = fan.target {
+                if fan.curve.is_some() {
+                    return Err(FanConfigError::InvalidChannel(
+                        fan.channel,
+                        "a target temperature replaces the curve",
+                    ));
+                }
+
+                target
+                    .to_tuning()
+                    .validate()
+                    .map_err(|why| FanConfigError::InvalidChannel(fan.channel, why))?;
+            }
+
+            config.channel(fan.channel)?;
+        }
+
+        Ok(config)
+    }
+
+    /// The curve and sensors of a `pwmN` channel.
+    pub fn channel(&self, channel: u8) -> Result<FanChannel, FanConfigError> {
+        let mut fan = FanChannel::new(channel);
+
+        if let Some(config) = self.fans.iter().find(|fan| fan.channel == channel) {
+            if let Some(ref name) = config.curve {
+                fan.curve = Some((name.clone(), self.curve(name)?));
+            }
+
+            if let Some(ref sensors) = config.sensors {
+                fan.sensors = sensors
+                    .iter()
+                    .map(|sensor| self.fan_sensor(sensor))
+                    .collect::<Result<_, _>>()?;
+            }
+
+            fan.aggregation = config.aggregation;
+            fan.target =
+                config.target.as_ref().map(|target| PidController::new(target.to_tuning()));
+        }
+
+        Ok(fan)
+    }
+
+    /// A sensor which a fan follows, with the weight and curve configured for it.
+    fn fan_sensor(&self, sensor: &SensorRef) -> Result<FanSensor, FanConfigError> {
+        let mut fan_sensor = FanSensor::new(sensor.clone());
+
+        if let SensorRef::Named(ref name) = *sensor {
+            let config = self
+                .sensors
+                .iter()
+                .find(|config| config.name == *name)
+                .ok_or_else(|| FanConfigError::UnknownSensor(name.clone()))?;
+
+            fan_sensor.weight = config.weight;
+            if let Some(ref curve) = config.curve {
+                fan_sensor.curve = Some(self.curve(curve)?);
+            }
+        }
+
+        Ok(fan_sensor)
+    }
+
+    /// The configured sensors of a hwmon device, by its driver name.
+    pub fn hwmon_sources(
+        &self,
+        driver: &str,
+        hwmon: &HwMon,
+        tree: &mut impl HwmonTree,
+    ) -> Vec<HwmonSource> {
+        self.sensors
+            .iter()
+            .filter(|sensor| sensor.driver == driver)
+            .filter_map(|sensor| {
+                let input = match sensor.label {
+                    Some(ref label) => (1..=64).find(|input| {
+                        tree.read(hwmon, &format!("temp{}_label", input))
+                            .is_ok_and(|found| found.trim() == label)
+                    })?,
+                    None => 1,
+                };
+
+                let offset = (sensor.offset * 1000.0).round() as i32;
+                Some(HwmonSource::configured(hwmon.clone(), input, sensor.name.clone(), offset))
+            })
+            .collect()
+    }
+
+    /// Finds a curve by name. Curves in the config replace built-in curves of the same name.
+    pub fn curve(&self, name: &str) -> Result<FanCurve, FanConfigError> {
+        match self.curves.get(name) {
+            Some(curve) => {
+                curve.to_curve().map_err(|why| FanConfigError::InvalidCurve(name.to_owned(), why))
+            }
+            None => {
+                FanCurve::builtin(name).ok_or_else(|| FanConfigError::UnknownCurve(name.into()))
+            }
+        }
+    }
+
+    /// Reads a curve from a file, with the keys of a `[curves.NAME]` table.
+    pub fn curve_file(path: &Path) -> Result<FanCurve, FanConfigError> {
+        let curve = fs::read_to_string(path)
+            .map_err(|why| FanConfigError::ReadCurve(path.to_owned(), why))?;
+        let curve: CurveConfig = toml::from_str(&curve)
+            .map_err(|why| FanConfigError::ParseCurve(path.to_owned(), why))?;
+
+        curve
+            .to_curve()
+            .map_err(|why| FanConfigError::InvalidCurve(path.display().to_string(), why))
+    }
+
+    /// The names of the built-in curves, followed by those defined in the config.
+    pub fn curve_names(&self) -> Vec<String> {
+        let mut names = FanCurve::BUILTIN.iter().map(|&name| name.to_owned()).collect::<Vec<_>>();
+
+        for name in self.curves.keys() {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+
+        names
+    }
+
+    /// The temperatures at which a thermal emergency begins and ends, in thousandths of a degree
+    /// Celsius, given the lowest critical temperature reported by the hardware.
+    pub fn emergency_thresholds(&self, hardware: Option<u32>) -> (u32, u32) {
+        let millis = |temp: f32| (temp * 1000.0).round() as u32;
+
+        let critical = self.emergency.critical.map(millis).unwrap_or_else(|| {
+            hardware.map_or(DEFAULT_CRITICAL, |crit| crit.saturating_sub(CRIT_MARGIN))
+        });
+
+        let recovery = self
+            .emergency
+            .recovery
+            .map(millis)
+            .filter(|&recovery| recovery < critical)
+            .unwrap_or_else(|| critical.saturating_sub(DEFAULT_HYSTERESIS));
+
+        (critical, recovery)
+    }
+
+    /// The curve to use: the one last selected over D-Bus, else the one chosen in the config for
+    /// the power profile, else the one chosen in the config for all profiles, else the given
+    /// default.
+    pub fn selected_curve(&self, profile: Profile, default: &str) -> String {
+        self.choose_curve(load_selected(), profile, default)
+    }
+
+    /// Chooses the curve to use, given the one selected over D-Bus.
+    pub(crate) fn choose_curve(
+        &self,
+        selected: Option<String>,
+        profile: Profile,
+        default: &str,
+    ) -> String {
+        selected
+            .filter(|name| match self.curve(name) {
+                Ok(_) => true,
+                Err(why) => {
+                    log::warn!("ignoring selected fan curve: {}", why);
+                    false
+                }
+            })
+            .or_else(|| self.profiles.get(profile).cloned())
+            .or_else(|| self.curve.clone())
+            .unwrap_or_else(|| default.to_owned())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Selected {
+    curve: String,
+}
+
+fn load_selected() -> Option<String> {
+    let selected = fs::read_to_string(SELECTED_CURVE).ok()?;
+    match serde_json::from_str::<Selected>(&selected) {
+        Ok(selected) => Some(selected.curve),
+        Err(why) => {
+            log::warn!("ignoring invalid selected fan curve: {}", why);
+            None
+        }
+    }
+}
+
+/// Forgets the curve selected over D-Bus, so that the config or model default is used again.
+pub fn clear_selected() {
+    if Path::new(SELECTED_CURVE).exists() {
+        if let Err(why) = fs::remove_file(SELECTED_CURVE) {
+            log::error!("failed to remove {}: {}", SELECTED_CURVE, why);
+        }
+    }
+}
+
+/// Remembers the curve selected over D-Bus, so that it is used again after a restart.
+pub fn save_selected(curve: &str) {
+    let result =
+        Path::new(SELECTED_CURVE).parent().map_or(Ok(()), fs::create_dir_all).and_then(|()| {
+            fs::write(SELECTED_CURVE, serde_json::to_string(&Selected { curve: curve.to_owned() })?)
+        });
+
+    if let Err(why) = result {
+        log::error!("failed to save fan curve to {}: {}", SELECTED_CURVE, why);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fan::Temperatures;
+
+    #[test]
+    fn channel_mapping() {
+        let config: FanConfig = toml::from_str(
+            r#"
+            [[fans]]
+            channel = 1
+            sensors = ["cpu"]
+
+            [[fans]]
+            channel = 3
+            curve = "hedt"
+            sensors = ["gpu"]
+            aggregation = "average"
+            "#,
+        )
+        .unwrap();
+
+        let temps = Temperatures {
+            cpu: vec![50_000, 60_000],
+            gpu: vec![70_000, 80_000],
+            ..Temperatures::default()
+        };
+        let curve = FanCurve::standard();
+
+        let cpu = config.channel(1).unwrap();
+        assert!(cpu.curve.is_none());
+        assert_eq!(cpu.temp(&temps, &curve), Some(60_000));
+
+        let default = config.channel(2).unwrap();
+        assert_eq!(default.temp(&temps, &curve), Some(80_000));
+
+        let gpu = config.channel(3).unwrap();
+        assert_eq!(gpu.curve.as_ref().map(|(name, _)| name.as_str()), Some("hedt"));
+        assert_eq!(gpu.temp(&temps, &curve), Some(75_000));
+
+        // Channels fall back to the hottest sensor when their own can not be read.
+        let cpu_only = Temperatures { cpu: vec![55_000], ..Temperatures::default() };
+        assert_eq!(gpu.temp(&cpu_only, &curve), Some(55_000));
+    }
+
+    #[test]
+    fn emergency_thresholds() {
+        let config = FanConfig::default();
+        assert_eq!(config.emergency_thresholds(None), (95_000, 85_000));
+        assert_eq!(config.emergency_thresholds(Some(100_000)), (95_000, 85_000));
+        assert_eq!(config.emergency_thresholds(Some(90_000)), (85_000, 75_000));
+
+        let config: FanConfig = toml::from_str("[emergency]\ncritical = 88\n").unwrap();
+        assert_eq!(config.emergency_thresholds(Some(100_000)), (88_000, 78_000));
+
+        let config: FanConfig =
+            toml::from_str("[emergency]\ncritical = 88\nrecovery = 84.5\n").unwrap();
+        assert_eq!(config.emergency_thresholds(None), (88_000, 84_500));
+    }
+
+    #[test]
+    fn target_channels() {
+        let config: FanConfig = toml::from_str(
+            r#"
+            [[fans]]
+            channel = 1
+            sensors = ["cpu"]
+            target = { temperature = 70, ki = 0.1, min_duty = 20 }
+            "#,
+        )
+        .unwrap();
+
+        let tuning = config.channel(1).unwrap().target.map(|target| target.tuning());
+        assert_eq!(
+            tuning,
+            Some(PidTuning { target: 70.0, ki: 0.1, min_duty: 20.0, ..PidTuning::default() })
+        );
+        assert!(config.channel(2).unwrap().target.is_none());
+    }
+}
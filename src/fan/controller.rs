@@ -0,0 +1,189 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Smooths the temperature and limits how quickly the duty changes, so that short bursts of load
+//! do not make the fans surge up and down.
+
+use super::{FanCurve, FanCurveError};
+
+/// How a fan curve follows changes in temperature.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FanTuning {
+    /// Weight of each second's temperature in the moving average, from 0 (exclusive) to 1, where 1
+    /// disables smoothing.
+    pub smoothing:  f32,
+    /// The fastest the duty may rise, in percent per second.
+    pub ramp_up:    f32,
+    /// The fastest the duty may fall, in percent per second.
+    pub ramp_down:  f32,
+    /// How far, in degrees Celsius, the temperature must fall before the duty is lowered.
+    pub hysteresis: f32,
+}
+
+impl Default for FanTuning {
+    fn default() -> Self {
+        Self { smoothing: 0.25, ramp_up: 10.0, ramp_down: 2.0, hysteresis: 2.0 }
+    }
+}
+
+impl FanTuning {
+    pub fn validate(&self) -> Result<(), FanCurveError> {
+        if !(self.smoothing > 0.0 && self.smoothing <= 1.0) {
+            return Err(FanCurveError::InvalidSmoothing(self.smoothing));
+        }
+
+        for rate in [self.ramp_up, self.ramp_down] {
+            if !(rate > 0.0 && rate.is_finite()) {
+                return Err(FanCurveError::InvalidRampRate(rate));
+            }
+        }
+
+        if !(self.hysteresis >= 0.0 && self.hysteresis.is_finite()) {
+            return Err(FanCurveError::InvalidHysteresis(self.hysteresis));
+        }
+
+        Ok(())
+    }
+}
+
+/// The state kept between samples to apply a curve's [`FanTuning`].
+#[derive(Debug, Default)]
+pub struct FanController {
+    /// Moving average of the temperature, in degrees Celsius.
+    smoothed: Option<f32>,
+    /// The temperature the duty follows, which only falls once it is a hysteresis band below.
+    held:     Option<f32>,
+    /// The commanded duty, in percent.
+    duty:     Option<f32>,
+}
+
+impl FanController {
+    /// Takes a temperature in hundredths of a degree, sampled `elapsed` seconds after the previous
+    /// one, and returns the duty in hundredths of a percent.
+    pub fn update(&mut self, curve: &FanCurve, temp: i16, elapsed: f32) -> Option<u16> {
+        let tuning = curve.tuning();
+        let temp = f32::from(temp) / 100.0;
+        let elapsed = elapsed.max(0.0);
+
+        // Weight samples by the time they cover, so that a late sample is not under-counted.
+        let alpha = 1.0 - (1.0 - tuning.smoothing).powf(elapsed);
+        let smoothed = self.smoothed.map_or(temp, |smoothed| smoothed + alpha * (temp - smoothed));
+        self.smoothed = Some(smoothed);
+
+        let held = match self.held {
+            Some(held) if smoothed < held && smoothed > held - tuning.hysteresis => held,
+            _ => smoothed,
+        };
+        self.held = Some(held);
+
+        let target = f32::from(curve.get_duty((held * 100.0).round() as i16)?) / 100.0;
+
+        let duty = match self.duty {
+            Some(duty) => {
+                duty + (target - duty).clamp(-tuning.ramp_down * elapsed, tuning.ramp_up * elapsed)
+            }
+            None => target,
+        };
+        self.duty = Some(duty);
+
+        Some((duty * 100.0).round() as u16)
+    }
+
+    /// Forgets the previous samples, such as when the temperature can not be read.
+    pub fn reset(&mut self) { *self = Self::default(); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a trace of temperatures in degrees, one per second, and returns the duties in percent.
+    fn run(curve: &FanCurve, trace: impl IntoIterator<Item = f32>) -> Vec<f32> {
+        let mut controller = FanController::default();
+        trace
+            .into_iter()
+            .map(|temp| {
+                let duty = controller.update(curve, (temp * 100.0) as i16, 1.0).unwrap();
+                f32::from(duty) / 100.0
+            })
+            .collect()
+    }
+
+    /// Short bursts of load between 45°C and 90°C.
+    fn bursts() -> impl Iterator<Item = f32> {
+        (0..120).map(|second| if second % 10 < 3 { 90.0 } else { 45.0 })
+    }
+
+    #[test]
+    fn bounded_slew() {
+        let curve = FanCurve::standard();
+        let tuning = curve.tuning();
+        let duties = run(&curve, bursts());
+
+        for window in duties.windows(2) {
+            let change = window[1] - window[0];
+            assert!(change <= tuning.ramp_up + 0.01, "rose by {}%", change);
+            assert!(-change <= tuning.ramp_down + 0.01, "fell by {}%", -change);
+        }
+
+        // Without smoothing and rate limits, the duty swings over most of its range.
+        let raw = FanCurve::standard().with_tuning(FanTuning {
+            smoothing:  1.0,
+            ramp_up:    100.0,
+            ramp_down:  100.0,
+            hysteresis: 0.0,
+        });
+        let raw_duties = run(&raw, bursts());
+        let swing = |duties: &[f32]| {
+            duties[60..].iter().copied().fold(f32::MIN, f32::max)
+                - duties[60..].iter().copied().fold(f32::MAX, f32::min)
+        };
+
+        assert!(swing(&raw_duties) >= 70.0);
+        assert!(swing(&duties) <= swing(&raw_duties) / 4.0, "swung by {}%", swing(&duties));
+    }
+
+    #[test]
+    fn follows_sustained_load() {
+        let curve = FanCurve::standard();
+        let duties = run(&curve, (0..60).map(|second| if second < 10 { 45.0 } else { 90.0 }));
+
+        assert_eq!(duties[0], 30.0);
+        assert_eq!(duties.last().copied(), Some(100.0));
+    }
+
+    #[test]
+    fn hysteresis_holds_duty() {
+        let curve = FanCurve::standard().with_tuning(FanTuning {
+            smoothing:  1.0,
+            ramp_up:    100.0,
+            ramp_down:  100.0,
+            hysteresis: 2.0,
+        });
+
+        // Wobbling by a degree below 70°C does not lower the duty.
+        let duties = run(&curve, [70.0, 69.0, 70.0, 69.0, 69.5, 68.5]);
+        assert!(duties.iter().all(|&duty| duty == duties[0]), "{:?}", duties);
+
+        // Falling past the band does.
+        let duties = run(&curve, [70.0, 67.5]);
+        assert!(duties[1] < duties[0]);
+    }
+
+    #[test]
+    fn invalid_tuning() {
+        let tuning = |smoothing, ramp_up, hysteresis| FanTuning {
+            smoothing,
+            ramp_up,
+            ramp_down: 1.0,
+            hysteresis,
+        };
+
+        assert_eq!(FanTuning::default().validate(), Ok(()));
+        assert_eq!(tuning(0.0, 1.0, 0.0).validate(), Err(FanCurveError::InvalidSmoothing(0.0)));
+        assert_eq!(tuning(1.5, 1.0, 0.0).validate(), Err(FanCurveError::InvalidSmoothing(1.5)));
+        assert_eq!(tuning(1.0, 0.0, 0.0).validate(), Err(FanCurveError::InvalidRampRate(0.0)));
+        assert_eq!(tuning(1.0, 1.0, -1.0).validate(), Err(FanCurveError::InvalidHysteresis(-1.0)));
+    }
+}
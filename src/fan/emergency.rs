@@ -0,0 +1,108 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Runs every fan at full speed once the temperature rises above a critical threshold, until it
+//! falls below a lower recovery threshold, so that the fans do not flap around the threshold.
+
+use super::FanEvent;
+
+/// Whether the fans are in a thermal emergency.
+#[derive(Debug)]
+pub struct ThermalEmergency {
+    active:   bool,
+    /// The critical threshold at the last check, in thousandths of a degree Celsius.
+    critical: u32,
+}
+
+impl ThermalEmergency {
+    pub const fn new(critical: u32) -> Self { Self { active: false, critical } }
+
+    /// Follows the hottest temperature against the critical and recovery thresholds, all in
+    /// thousandths of a degree Celsius, and returns the event if an emergency began or ended.
+    pub fn check(
+        &mut self,
+        temp: Option<u32>,
+        (critical, recovery): (u32, u32),
+    ) -> Option<FanEvent> {
+        self.critical = critical;
+        let temp = temp?;
+
+        if !self.active && temp > critical {
+            log::error!("fan daemon: thermal emergency at {}°C", temp as f32 / 1000.0);
+            self.active = true;
+            Some(FanEvent::Emergency { temp })
+        } else if self.active && temp < recovery {
+            log::info!("fan daemon: recovered from thermal emergency");
+            self.active = false;
+            Some(FanEvent::Recovered { temp })
+        } else {
+            None
+        }
+    }
+
+    /// Whether the temperature rose above the critical threshold, and has not yet recovered.
+    pub fn active(&self) -> bool { self.active }
+
+    /// Whether manual duties and calibration must stop: during an emergency, or when the
+    /// temperature is unknown or above the critical threshold.
+    pub fn too_hot(&self, temp: Option<u32>) -> bool {
+        self.active || temp.map_or(true, |temp| temp > self.critical)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: (u32, u32) = (95_000, 85_000);
+
+    #[test]
+    fn emergency_hysteresis() {
+        let mut emergency = ThermalEmergency::new(THRESHOLDS.0);
+        assert_eq!(emergency.check(Some(95_000), THRESHOLDS), None);
+        assert!(!emergency.active());
+        assert!(!emergency.too_hot(Some(95_000)));
+        assert!(emergency.too_hot(None));
+
+        assert_eq!(
+            emergency.check(Some(95_001), THRESHOLDS),
+            Some(FanEvent::Emergency { temp: 95_001 })
+        );
+        assert!(emergency.active());
+
+        // The emergency stays active inside the hysteresis band, and while the temperature can
+        // not be read.
+        for temp in [99_000, 90_000, 85_000] {
+            assert_eq!(emergency.check(Some(temp), THRESHOLDS), None);
+            assert!(emergency.active());
+            assert!(emergency.too_hot(Some(temp)));
+        }
+        assert_eq!(emergency.check(None, THRESHOLDS), None);
+        assert!(emergency.active());
+
+        assert_eq!(
+            emergency.check(Some(84_999), THRESHOLDS),
+            Some(FanEvent::Recovered { temp: 84_999 })
+        );
+        assert!(!emergency.active());
+        assert!(!emergency.too_hot(Some(84_999)));
+
+        // Rising into the band again does not start another emergency.
+        assert_eq!(emergency.check(Some(90_000), THRESHOLDS), None);
+        assert!(!emergency.active());
+    }
+
+    #[test]
+    fn changed_thresholds() {
+        let mut emergency = ThermalEmergency::new(THRESHOLDS.0);
+        assert!(!emergency.too_hot(Some(90_000)));
+
+        // A lower critical temperature from the hardware applies from the next check.
+        assert!(emergency.check(Some(90_000), (88_000, 78_000)).is_some());
+        assert!(emergency.too_hot(Some(80_000)));
+        assert!(emergency.check(Some(80_000), (88_000, 78_000)).is_none());
+        assert!(emergency.check(Some(77_000), (88_000, 78_000)).is_some());
+        assert!(emergency.too_hot(Some(88_001)));
+    }
+}
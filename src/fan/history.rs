@@ -0,0 +1,218 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Keeps the temperatures and the fan duties and speeds of the last day, so that what the fans did
+//! can be looked at after they were reported to be loud.
+
+use super::{SensorGroup, SensorRef, Temperatures};
+use crate::Profile;
+use std::{collections::VecDeque, sync::Arc};
+
+/// The seconds covered by each sample that is kept.
+pub const RESOLUTION: u64 = 5;
+
+/// How long samples are kept, in seconds.
+pub const RETENTION: u64 = 24 * 60 * 60;
+
+/// The most samples that are kept.
+const CAPACITY: usize = (RETENTION / RESOLUTION) as usize;
+
+/// The hottest temperatures, and the highest duties and speeds, over a period.
+#[derive(Clone, Debug)]
+pub struct HistorySample {
+    /// The start of the period, in seconds since the Unix epoch.
+    pub time:    u64,
+    /// The hottest temperature, in thousandths of a degree Celsius.
+    pub temp:    Option<u32>,
+    /// The hottest temperature of each sensor, in thousandths of a degree Celsius.
+    pub sensors: Vec<(Arc<str>, u32)>,
+    /// The highest duty of each channel, from 0 to 255. Empty while the firmware controlled the
+    /// fans.
+    pub duties:  Vec<(u8, u8)>,
+    /// The fastest speed of each fan, in RPM.
+    pub rpms:    Vec<(u8, u32)>,
+    /// The power profile at the end of the period.
+    pub profile: Profile,
+}
+
+impl HistorySample {
+    /// Extends the period to cover a later sample.
+    fn merge(&mut self, later: &Self) {
+        self.temp = self.temp.max(later.temp);
+        merge_max(&mut self.sensors, &later.sensors);
+        merge_max(&mut self.duties, &later.duties);
+        merge_max(&mut self.rpms, &later.rpms);
+        self.profile = later.profile;
+    }
+}
+
+/// Keeps the highest value of each key.
+fn merge_max<K: Clone + PartialEq, V: Copy + Ord>(values: &mut Vec<(K, V)>, later: &[(K, V)]) {
+    for (key, value) in later {
+        match values.iter_mut().find(|(existing, _)| existing == key) {
+            Some((_, existing)) => *existing = (*existing).max(*value),
+            None => values.push((key.clone(), *value)),
+        }
+    }
+}
+
+/// The samples of the last day, at a resolution of five seconds.
+#[derive(Debug, Default)]
+pub struct ThermalHistory {
+    samples: VecDeque<HistorySample>,
+    /// The name of each sensor, shared by every sample.
+    names:   Vec<Arc<str>>,
+}
+
+impl ThermalHistory {
+    /// Adds what the fan daemon observed at a time, in seconds since the Unix epoch.
+    pub fn record(
+        &mut self,
+        time: u64,
+        temps: &Temperatures,
+        duties: &[(u8, u8)],
+        rpms: &[(u8, u32)],
+        profile: Profile,
+    ) {
+        let groups = [
+            (SensorRef::Group(SensorGroup::Cpu), "cpu"),
+            (SensorRef::Group(SensorGroup::Gpu), "gpu"),
+        ];
+        let sensors = groups
+            .iter()
+            .map(|(sensor, name)| (*name, temps.get(sensor)))
+            .chain(temps.named.iter().map(|(name, temps)| (name.as_str(), temps.as_slice())))
+            .filter_map(|(name, temps)| {
+                let temp = temps.iter().copied().max()?;
+                Some((self.name(name), temp))
+            })
+            .collect();
+
+        let sample = HistorySample {
+            time: time - time % RESOLUTION,
+            temp: temps.max(),
+            sensors,
+            duties: duties.to_vec(),
+            rpms: rpms.to_vec(),
+            profile,
+        };
+
+        match self.samples.back_mut() {
+            Some(last) if last.time == sample.time => last.merge(&sample),
+            _ => {
+                if self.samples.len() == CAPACITY {
+                    self.samples.pop_front();
+                }
+                self.samples.push_back(sample);
+            }
+        }
+
+        // Samples from before a suspend may be older than the last day.
+        while self.samples.front().is_some_and(|first| first.time + RETENTION <= time) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// The samples from a time onwards, in seconds since the Unix epoch, merged into periods of
+    /// `resolution` seconds.
+    pub fn query(&self, since: u64, resolution: u64) -> Vec<HistorySample> {
+        let resolution = resolution.clamp(RESOLUTION, RETENTION);
+        let mut merged: Vec<HistorySample> = Vec::new();
+
+        for sample in self.samples.iter().filter(|sample| sample.time >= since) {
+            let period = sample.time - sample.time % resolution;
+            match merged.last_mut() {
+                Some(last) if last.time == period => last.merge(sample),
+                _ => merged.push(HistorySample { time: period, ..sample.clone() }),
+            }
+        }
+
+        merged
+    }
+
+    fn name(&mut self, name: &str) -> Arc<str> {
+        match self.names.iter().find(|existing| &***existing == name) {
+            Some(existing) => existing.clone(),
+            None => {
+                let name = Arc::<str>::from(name);
+                self.names.push(name.clone());
+                name
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temps(cpu: u32, nvme: u32) -> Temperatures {
+        let mut temps = Temperatures { cpu: vec![cpu, cpu - 5_000], ..Temperatures::default() };
+        temps.named.insert("nvme".to_owned(), vec![nvme]);
+        temps
+    }
+
+    #[test]
+    fn downsampled_history() {
+        let mut history = ThermalHistory::default();
+        let start = 1_699_999_980;
+
+        // A minute of samples, rising a degree each second.
+        for second in 0..60 {
+            let temp = 40_000 + second as u32 * 1_000;
+            let profile = if second < 30 { Profile::Balanced } else { Profile::Performance };
+            history.record(
+                start + second,
+                &temps(temp, 35_000),
+                &[(1, second as u8)],
+                &[],
+                profile,
+            );
+        }
+
+        let samples = history.query(0, RESOLUTION);
+        assert_eq!(samples.len(), 12);
+        assert_eq!(samples[0].time, start);
+        assert_eq!(samples[0].temp, Some(44_000));
+        assert_eq!(
+            samples[0].sensors,
+            vec![(Arc::from("cpu"), 44_000), (Arc::from("nvme"), 35_000)]
+        );
+        assert_eq!(samples[0].duties, vec![(1, 4)]);
+
+        let samples = history.query(0, 60);
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].temp, Some(99_000));
+        assert_eq!(samples[0].duties, vec![(1, 59)]);
+        assert!(matches!(samples[0].profile, Profile::Performance));
+
+        let samples = history.query(start + 30, 10);
+        assert_eq!(
+            samples.iter().map(|sample| sample.time - start).collect::<Vec<_>>(),
+            vec![30, 40, 50]
+        );
+
+        // Every sample shares the names of the sensors.
+        assert_eq!(history.names.len(), 2);
+    }
+
+    #[test]
+    fn bounded_history() {
+        let mut history = ThermalHistory::default();
+        let start = 1_700_000_000;
+
+        for second in (0..2 * RETENTION).step_by(2) {
+            history.record(start + second, &temps(50_000, 30_000), &[], &[], Profile::Balanced);
+        }
+
+        assert_eq!(history.samples.len(), CAPACITY);
+        let first = history.samples.front().unwrap().time;
+        assert!(first >= start + RETENTION, "{}", first - start);
+
+        // After a suspend, samples older than a day are dropped.
+        let resumed = start + 4 * RETENTION;
+        history.record(resumed, &temps(50_000, 30_000), &[], &[], Profile::Balanced);
+        assert_eq!(history.query(0, RESOLUTION).len(), 1);
+    }
+}
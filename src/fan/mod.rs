@@ -0,0 +1,1646 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+#![allow(clippy::inconsistent_digit_grouping)]
+
+use std::{
+    fmt, fs, io,
+    process::{Command, Stdio},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use sysfs_class::{HwMon, SysClass};
+
+use self::{
+    calibration::{CalibrationStep, Calibrator},
+    history::ThermalHistory,
+    sources::HwmonSource,
+    uevent::{HwmonTree, Rediscovery, SysfsHwmons},
+};
+
+use crate::Profile;
+
+mod calibration;
+mod channel;
+mod config;
+mod controller;
+mod emergency;
+mod history;
+mod pid;
+pub mod simulate;
+mod sources;
+mod uevent;
+pub use self::{
+    calibration::FanCalibration,
+    channel::{
+        Aggregation, FanChannel, FanSensor, SensorGroup, SensorRef, StallDetector, Temperatures,
+    },
+    config::{FanConfig, FanConfigError, FAN_CONFIG},
+    controller::{FanController, FanTuning},
+    emergency::ThermalEmergency,
+    history::HistorySample,
+    pid::{PidController, PidTuning},
+    sources::{Sensor, TemperatureSource},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum FanDaemonError {
+    #[error("failed to collect hwmon devices: {}", _0)]
+    HwmonDevices(io::Error),
+    #[error("platform hwmon not found")]
+    PlatformHwmonNotFound,
+    #[error("cpu hwmon not found")]
+    CpuHwmonNotFound,
+    #[error("duty {}% is out of range: should be 0-100%", _0)]
+    InvalidDuty(u8),
+    #[error("timeout {}s is out of range: should be at most {}s", _0, MAX_MANUAL_TIMEOUT)]
+    InvalidTimeout(u32),
+    #[error("a manual duty can not be set while the temperature is unknown or critical")]
+    Critical,
+    #[error("the fans are being calibrated")]
+    Calibrating,
+    #[error("the fans can not be calibrated while the temperature is unknown or critical")]
+    CalibrationCritical,
+    #[error("the fans are controlled by the firmware, which does not allow setting their duty")]
+    ReadOnly,
+}
+
+/// Something the fan daemon noticed, which is signalled over D-Bus.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FanEvent {
+    /// A fan does not spin while it is driven at a duty from 0 to 255.
+    Failure { channel: u8, rpm: u32, duty: u8 },
+    /// The temperature, in thousandths of a degree Celsius, rose above the critical threshold.
+    Emergency { temp: u32 },
+    /// The temperature fell below the recovery threshold after an emergency.
+    Recovered { temp: u32 },
+    /// Calibrating the fans finished with the fans which could be calibrated, or none if it was
+    /// cancelled.
+    Calibrated(Vec<FanCalibration>),
+}
+
+/// How long a change of fan curve takes to blend from the previous curve to the next.
+const CURVE_TRANSITION: Duration = Duration::from_secs(30);
+
+/// The longest a manual duty may last, in seconds.
+pub const MAX_MANUAL_TIMEOUT: u32 = 3600;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum FanCurveError {
+    #[error("a fan curve needs at least two points")]
+    TooFewPoints,
+    #[error("temperature {}°C is not above that of the previous point", _0)]
+    UnsortedTemperatures(f32),
+    #[error("temperature {}°C is out of range: should be -100-300°C", _0)]
+    TemperatureOutOfRange(f32),
+    #[error("duty {}% is out of range: should be 0-100%", _0)]
+    DutyOutOfRange(f32),
+    #[error("duty {}% is lower than that of the previous point", _0)]
+    DecreasingDuty(f32),
+    #[error("temperature {}°C is given by more than one point", _0)]
+    DuplicateTemperature(f32),
+    #[error("'{}' is not a temperature and a duty, such as 45:30", _0)]
+    InvalidPoint(String),
+    #[error("smoothing {} is out of range: should be above 0 and at most 1", _0)]
+    InvalidSmoothing(f32),
+    #[error("ramp rate {}%/s must be above 0", _0)]
+    InvalidRampRate(f32),
+    #[error("hysteresis {}°C must not be negative", _0)]
+    InvalidHysteresis(f32),
+}
+
+pub struct FanDaemon {
+    model:        String,
+    config:       FanConfig,
+    curve_name:   String,
+    curve:        FanCurve,
+    /// The curve which was in use before the last change, and when it was changed.
+    transition:   Option<(FanCurve, Instant)>,
+    channels:     Vec<FanChannel>,
+    last_step:    Option<Instant>,
+    profile:      Profile,
+    platforms:    Vec<HwMon>,
+    /// Whether the platform hwmons are those of System76 laptops, rather than Thelio Io boards.
+    laptop:       bool,
+    /// Whether the fans can only be monitored, because the platform hwmons have no writable
+    /// `pwm1_enable`. The firmware controls them.
+    read_only:    bool,
+    sensors:      Vec<Sensor>,
+    /// Reads the proprietary NVIDIA driver's GPUs, when they have no hwmon.
+    nvidia:       Option<Sensor>,
+    /// Whether a GPU hwmon of NVIDIA GPUs was found, which replaces `nvidia`.
+    nvidia_hwmon: bool,
+    /// The hottest temperature at the last step.
+    temp:         Option<u32>,
+    /// The duty of each channel at the last step, or none if the firmware controls the fans.
+    duties:       Vec<(u8, u8)>,
+    /// A duty in percent which overrides the curves until it expires.
+    manual:       Option<(u8, Instant)>,
+    /// Runs the fans at full speed while the temperature is critical.
+    emergency:    ThermalEmergency,
+    /// Decides when the hwmons are scanned again.
+    rediscovery:  Rediscovery,
+    /// Whether the last scan found the hwmons needed to control the fans.
+    discovered:   bool,
+    /// The measured limits of each fan which was calibrated.
+    calibrations: Vec<FanCalibration>,
+    /// Drives the fans while they are calibrated.
+    calibrator:   Option<Calibrator>,
+    /// The temperatures, duties and speeds of the last day.
+    history:      ThermalHistory,
+}
+
+impl FanDaemon {
+    pub fn new(nvidia_exists: bool) -> Self {
+        let model = fs::read_to_string("/sys/class/dmi/id/product_version").unwrap_or_default();
+        let model = model.trim().to_owned();
+
+        let config = FanConfig::load().unwrap_or_else(|why| {
+            log::error!("fan daemon: {}", why);
+            FanConfig::default()
+        });
+
+        let (critical, _) = config.emergency_thresholds(None);
+        let mut daemon = Self {
+            model,
+            config,
+            curve_name: String::new(),
+            curve: FanCurve::standard(),
+            transition: None,
+            channels: Vec::new(),
+            last_step: None,
+            profile: Profile::Balanced,
+            platforms: Vec::new(),
+            laptop: false,
+            read_only: false,
+            sensors: Vec::new(),
+            nvidia: nvidia_exists.then(|| Sensor::new(sources::nvidia_source())),
+            nvidia_hwmon: false,
+            temp: None,
+            duties: Vec::new(),
+            manual: None,
+            emergency: ThermalEmergency::new(critical),
+            rediscovery: Rediscovery::new(),
+            discovered: false,
+            calibrations: calibration::load(),
+            calibrator: None,
+            history: ThermalHistory::default(),
+        };
+
+        if daemon.rediscovery.due(Instant::now()) {
+            daemon.discovered =
+                daemon.discover().map_err(|err| log::error!("fan daemon: {}", err)).is_ok();
+        }
+
+        daemon.curve_name = daemon.config.selected_curve(daemon.profile, &daemon.default_curve());
+        daemon.curve = daemon.config.curve(&daemon.curve_name).unwrap_or_else(|why| {
+            log::error!("fan daemon: {}", why);
+            FanCurve::standard()
+        });
+
+        log::info!("fan daemon: using fan curve '{}'", daemon.curve_name);
+
+        daemon
+    }
+
+    /// The built-in curve for this model, which on laptops depends on the power profile.
+    fn default_curve(&self) -> String {
+        if self.laptop {
+            FanCurve::laptop_default(self.profile).to_owned()
+        } else {
+            FanCurve::default_for_model(&self.model).to_owned()
+        }
+    }
+
+    /// Follows a change of power profile, which on laptops selects a different default curve.
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.profile = profile;
+
+        let name = self.config.selected_curve(profile, &self.default_curve());
+        if name != self.curve_name {
+            match self.config.curve(&name) {
+                Ok(curve) => {
+                    log::info!("fan daemon: switched to fan curve '{}' for {:?}", name, profile);
+                    self.switch_curve(name, curve);
+                }
+                Err(why) => log::error!("fan daemon: {}", why),
+            }
+        }
+    }
+
+    /// Changes the curve for all fans, blending from the curve in use over `CURVE_TRANSITION`.
+    fn switch_curve(&mut self, name: String, curve: FanCurve) {
+        let previous = self.active_curve(Instant::now());
+        self.transition = Some((previous, Instant::now()));
+        self.curve_name = name;
+        self.curve = curve;
+    }
+
+    /// The curve for all fans at a point in time, which is a blend of the previous and the next
+    /// curve while switching between them.
+    fn active_curve(&self, now: Instant) -> FanCurve {
+        match self.transition {
+            Some((ref previous, start)) => {
+                let progress = (now - start).as_secs_f32() / CURVE_TRANSITION.as_secs_f32();
+                if progress < 1.0 {
+                    FanCurve::blend(previous, &self.curve, progress)
+                } else {
+                    self.curve.clone()
+                }
+            }
+            None => self.curve.clone(),
+        }
+    }
+
+    /// The name and points of the fan curve in use.
+    pub fn curve(&self) -> (&str, &FanCurve) { (&self.curve_name, &self.curve) }
+
+    /// The names of the fan curves which can be selected.
+    pub fn curve_names(&self) -> Result<Vec<String>, FanConfigError> {
+        Ok(FanConfig::load()?.curve_names())
+    }
+
+    /// Reloads the config, and switches to the named curve, or the default curve if the name is
+    /// empty. The selection is kept across restarts.
+    pub fn set_curve(&mut self, name: &str) -> Result<(), FanConfigError> {
+        let config = FanConfig::load()?;
+
+        let (name, curve) = if name.is_empty() {
+            config::clear_selected();
+            let name = config.selected_curve(self.profile, &self.default_curve());
+            let curve = config.curve(&name)?;
+            (name, curve)
+        } else {
+            let curve = config.curve(name)?;
+            config::save_selected(name);
+            (name.to_owned(), curve)
+        };
+
+        log::info!("fan daemon: switched to fan curve '{}'", name);
+        self.switch_curve(name, curve);
+        self.config = config;
+        self.configure_channels(self.channels.len() as u8);
+
+        // Sensors added to the config are found by the next scan.
+        self.rediscovery.invalidate();
+        Ok(())
+    }
+
+    /// Sets up the curve and sensors of each channel from the config. The controllers of existing
+    /// channels are kept, so that their duty does not jump.
+    fn configure_channels(&mut self, count: u8) {
+        let mut previous = std::mem::take(&mut self.channels);
+
+        self.channels = (1..=count)
+            .map(|channel| {
+                let mut fan = self.config.channel(channel).unwrap_or_else(|why| {
+                    log::error!("fan daemon: {}", why);
+                    FanChannel::new(channel)
+                });
+
+                if let Some(old) = previous.iter_mut().find(|old| old.channel == channel) {
+                    fan.controller = std::mem::take(&mut old.controller);
+                    fan.stall = std::mem::take(&mut old.stall);
+
+                    let tuning = fan.target.as_ref().map(PidController::tuning);
+                    if old.target.as_ref().map(PidController::tuning) == tuning {
+                        fan.target = old.target.take();
+                    }
+                }
+
+                fan.stall.set_calibration(self.calibration(channel));
+
+                fan
+            })
+            .collect();
+    }
+
+    /// The hottest temperature at the last step, in thousandths of a degree Celsius.
+    pub fn temp(&self) -> Option<u32> { self.temp }
+
+    /// The duty of each channel at the last step, from 0 to 255. Empty while the firmware
+    /// controls the fans.
+    pub fn duties(&self) -> &[(u8, u8)] { &self.duties }
+
+    /// Overrides the curves with a duty in percent for `timeout` seconds, or returns to the curves
+    /// if the timeout is 0. The override is cancelled early if the temperature becomes critical.
+    pub fn set_manual_duty(&mut self, percent: u8, timeout: u32) -> Result<(), FanDaemonError> {
+        if timeout == 0 {
+            self.manual = None;
+            return Ok(());
+        }
+
+        if percent > 100 {
+            return Err(FanDaemonError::InvalidDuty(percent));
+        }
+
+        if timeout > MAX_MANUAL_TIMEOUT {
+            return Err(FanDaemonError::InvalidTimeout(timeout));
+        }
+
+        if self.emergency.too_hot(self.temp) {
+            return Err(FanDaemonError::Critical);
+        }
+
+        if self.calibrator.is_some() {
+            return Err(FanDaemonError::Calibrating);
+        }
+
+        if self.read_only {
+            return Err(FanDaemonError::ReadOnly);
+        }
+
+        log::info!("fan daemon: manual duty of {}% for {}s", percent, timeout);
+        self.manual = Some((percent, Instant::now() + Duration::from_secs(u64::from(timeout))));
+        Ok(())
+    }
+
+    /// The measured limits of a fan, if it was calibrated.
+    pub fn calibration(&self, channel: u8) -> Option<&FanCalibration> {
+        self.calibrations.iter().find(|calibration| calibration.channel == channel)
+    }
+
+    /// The measured limits of each fan which was calibrated.
+    pub fn calibrations(&self) -> &[FanCalibration] { &self.calibrations }
+
+    /// How far the calibration of the fans is, from 0 to 1, if they are being calibrated.
+    pub fn calibration_progress(&self) -> Option<f32> {
+        self.calibrator.as_ref().map(Calibrator::progress)
+    }
+
+    /// The samples of the last day from a time onwards, in seconds since the Unix epoch, merged
+    /// into periods of `resolution` seconds. Periods shorter than five seconds are not kept.
+    pub fn history(&self, since: u64, resolution: u64) -> Vec<HistorySample> {
+        self.history.query(since, resolution)
+    }
+
+    /// Starts measuring the spin-up duty and the fastest speed of each fan, which replaces the
+    /// curves for about two minutes. A manual duty is cancelled.
+    pub fn start_calibration(&mut self) -> Result<(), FanDaemonError> {
+        if self.calibrator.is_some() {
+            return Err(FanDaemonError::Calibrating);
+        }
+
+        if !self.discovered || self.channels.is_empty() {
+            return Err(FanDaemonError::PlatformHwmonNotFound);
+        }
+
+        if self.read_only {
+            return Err(FanDaemonError::ReadOnly);
+        }
+
+        if self.emergency.too_hot(self.temp) {
+            return Err(FanDaemonError::CalibrationCritical);
+        }
+
+        log::info!("fan daemon: calibrating {} fans", self.channels.len());
+        self.manual = None;
+        self.calibrator = Some(Calibrator::new(self.channels.iter().map(|fan| fan.channel)));
+        Ok(())
+    }
+
+    /// Stops calibrating the fans at the next step, and keeps the previous calibration.
+    pub fn cancel_calibration(&mut self) {
+        if let Some(ref mut calibrator) = self.calibrator {
+            log::info!("fan daemon: calibration cancelled");
+            calibrator.cancel();
+        }
+    }
+
+    /// Keeps the results of a calibration, and returns to the curves.
+    fn finish_calibration(&mut self, results: Vec<FanCalibration>) -> FanEvent {
+        self.calibrator = None;
+
+        if !results.is_empty() {
+            for result in &results {
+                log::info!(
+                    "fan daemon: fan {} starts at a duty of {}, and runs at up to {} RPM",
+                    result.channel,
+                    result.min_duty,
+                    result.max_rpm
+                );
+            }
+
+            self.calibrations.retain(|old| !results.iter().any(|new| new.channel == old.channel));
+            self.calibrations.extend_from_slice(&results);
+            self.calibrations.sort_by_key(|calibration| calibration.channel);
+            calibration::save(&self.calibrations);
+            self.configure_channels(self.channels.len() as u8);
+        }
+
+        FanEvent::Calibrated(results)
+    }
+
+    /// Discover all utilizable hwmon devices
+    fn discover(&mut self) -> Result<(), FanDaemonError> {
+        self.platforms.clear();
+        self.nvidia_hwmon = false;
+
+        let HwmonScan { platforms, laptop, read_only, nvidia_hwmon, channels, sources } =
+            scan_hwmons(&mut SysfsHwmons, &self.config)?;
+        self.platforms = platforms;
+        self.laptop = laptop;
+        self.nvidia_hwmon = nvidia_hwmon;
+
+        // Keep the backoff of sources which were found before, but use the new sources, whose
+        // names and offsets may have changed in the config.
+        let mut previous = std::mem::take(&mut self.sensors);
+        self.sensors = sources
+            .into_iter()
+            .map(|source| {
+                let (id, sensor) = (source.id(), source.sensor());
+                match previous.iter().position(|old| old.id() == id && old.sensor() == sensor) {
+                    Some(index) => previous.swap_remove(index).replace(source),
+                    None => Sensor::new(source),
+                }
+            })
+            .collect();
+
+        if self.platforms.is_empty() {
+            return Err(FanDaemonError::PlatformHwmonNotFound);
+        }
+
+        if read_only && !self.read_only {
+            log::warn!("fan daemon: pwm1_enable is not writable, leaving the fans to the firmware");
+        }
+        self.read_only = read_only;
+
+        if usize::from(channels) != self.channels.len() {
+            log::info!("fan daemon: found {} fan channels", channels);
+            self.configure_channels(channels);
+        }
+
+        if !self.sensors.iter().any(|sensor| sensor.sensor() == SensorRef::Group(SensorGroup::Cpu))
+        {
+            return Err(FanDaemonError::CpuHwmonNotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Get the maximum measured temperature from any CPU / GPU on the system, in
+    /// thousandths of a Celsius. Thousandths celsius is the standard Linux hwmon temperature unit.
+    pub fn get_temp(&mut self) -> Option<u32> {
+        let temp = self.get_temps().max();
+        log::debug!("current temp: {:?}", temp);
+        temp
+    }
+
+    /// Get the temperatures of each CPU and GPU, in thousandths of a Celsius.
+    ///
+    /// Sensors of suspended GPUs are skipped, and failing sensors are retried with a backoff.
+    pub fn get_temps(&mut self) -> Temperatures {
+        let now = Instant::now();
+        let mut temps = Temperatures::default();
+
+        let nvidia = self.nvidia.as_mut().filter(|_| !self.nvidia_hwmon);
+        for sensor in self.sensors.iter_mut() {
+            let failures = sensor.failures();
+            match sensor.read(now) {
+                Some(readings) => temps.extend(sensor.sensor(), readings),
+                // The hwmon may have been removed, or renumbered.
+                None if sensor.failures() > failures => self.rediscovery.invalidate(),
+                None => (),
+            }
+        }
+
+        if let Some(sensor) = nvidia {
+            if let Some(readings) = sensor.read(now) {
+                temps.extend(sensor.sensor(), readings);
+            }
+        }
+
+        log::debug!("cpu temps: {:?}, gpu temps: {:?}", temps.cpu, temps.gpu);
+
+        temps
+    }
+
+    /// Get the correct duty cycle for a temperature in thousandths Celsius, from 0 to 255
+    /// Thousandths celsius is the standard Linux hwmon temperature unit
+    /// 0 to 255 is the standard Linux hwmon pwm unit
+    pub fn get_duty(&self, temp: u32) -> Option<u8> {
+        self.curve
+            .get_duty((temp / 10) as i16)
+            .map(|duty| (((u32::from(duty)) * 255) / 10_000) as u8)
+    }
+
+    /// Get the speed of each fan channel, in RPM
+    pub fn get_rpms(&self) -> Vec<(u8, u32)> {
+        self.channels
+            .iter()
+            .filter_map(|fan| {
+                let rpm = self.platforms.iter().find_map(|platform| {
+                    platform.parse_file::<u32, _>(format!("fan{}_input", fan.channel)).ok()
+                })?;
+                Some((fan.channel, rpm))
+            })
+            .collect()
+    }
+
+    /// The `pwmN_enable` attributes which switch between manual and automatic control. Thelio Io
+    /// boards switch every channel with `pwm1_enable`.
+    fn enable_attrs(&self) -> Vec<String> {
+        if self.laptop {
+            self.channels.iter().map(|fan| format!("pwm{}_enable", fan.channel)).collect()
+        } else {
+            vec!["pwm1_enable".to_owned()]
+        }
+    }
+
+    /// Set the duty cycle of each channel, from 0 to 255
+    pub fn set_channel_duties(&self, duties: &[(u8, u8)]) -> io::Result<()> {
+        for platform in &self.platforms {
+            for attr in self.enable_attrs() {
+                platform.write_file(attr, "1")?;
+            }
+
+            for &(channel, duty) in duties {
+                platform.write_file(format!("pwm{}", channel), format!("{}", duty))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Set the current duty cycle, from 0 to 255
+    /// 0 to 255 is the standard Linux hwmon pwm unit
+    ///
+    /// `None` returns control of the fans to the firmware.
+    pub fn set_duty(&self, duty_opt: Option<u8>) {
+        if self.read_only {
+            return;
+        }
+
+        if let Some(duty) = duty_opt {
+            let duties = self.channels.iter().map(|fan| (fan.channel, duty)).collect::<Vec<_>>();
+            if let Err(why) = self.set_channel_duties(&duties) {
+                log::warn!("fan daemon: failed to set duty: {}", why);
+                self.set_duty(None);
+            }
+        } else {
+            for platform in &self.platforms {
+                for attr in self.enable_attrs() {
+                    let _ = platform.write_file(attr, "2");
+                }
+            }
+        }
+    }
+
+    /// Calculate the correct duty cycle and apply it to all fans
+    ///
+    /// The temperature is smoothed, and the duty limited in how quickly it changes, according to
+    /// the tuning of the curve.
+    ///
+    /// Fans which stall while they should be spinning are reported, and the other fans are run at
+    /// full speed until they spin again. Above the critical temperature, all fans run at full speed
+    /// until the temperature falls below the recovery temperature.
+    pub fn step(&mut self) -> Vec<FanEvent> {
+        let mut events = Vec::new();
+
+        let now = Instant::now();
+        if self.rediscovery.due(now) {
+            log::debug!("fan daemon: scanning hwmons ({} scans)", self.rediscovery.scans);
+            self.discovered = match self.discover() {
+                Ok(()) => true,
+                Err(why) => {
+                    log::debug!("fan daemon: {}", why);
+                    false
+                }
+            };
+        }
+
+        let mut temps = Temperatures::default();
+        let mut rpms = Vec::new();
+
+        let duties = if !self.discovered {
+            self.temp = None;
+            if self.calibrator.is_some() {
+                events.push(self.finish_calibration(Vec::new()));
+            }
+            Vec::new()
+        } else {
+            let elapsed = self.last_step.map_or(1.0, |last| (now - last).as_secs_f32());
+            self.last_step = Some(now);
+
+            temps = self.get_temps();
+            self.temp = temps.max();
+
+            // Only the CPUs and GPUs are cooled by limiting them.
+            let crit = self
+                .sensors
+                .iter()
+                .filter(|sensor| matches!(sensor.sensor(), SensorRef::Group(_)))
+                .filter_map(Sensor::crit)
+                .min();
+            let thresholds = self.config.emergency_thresholds(crit);
+            if let Some(event) = self.emergency.check(self.temp, thresholds) {
+                if self.emergency.active() {
+                    self.manual = None;
+                    if self.calibrator.is_some() {
+                        events.push(self.finish_calibration(Vec::new()));
+                    }
+                }
+                events.push(event);
+            }
+
+            // The speed of each fan follows the duty it was set to at the last step. Fans are
+            // stopped on purpose while they are calibrated.
+            rpms = self.get_rpms();
+            for fan in self.channels.iter_mut().filter(|_| self.calibrator.is_none()) {
+                let duty = self.duties.iter().find(|&&(channel, _)| channel == fan.channel);
+                let rpm = rpms.iter().find(|&&(channel, _)| channel == fan.channel);
+                if let (Some(&(channel, duty)), Some(&(_, rpm))) = (duty, rpm) {
+                    if fan.stall.check(duty, rpm) {
+                        log::error!(
+                            "fan daemon: fan {} has failed: {} RPM at a duty of {}",
+                            channel,
+                            rpm,
+                            duty
+                        );
+                        events.push(FanEvent::Failure { channel, rpm, duty });
+                    }
+                }
+            }
+
+            if self.transition.as_ref().is_some_and(|&(_, start)| now - start >= CURVE_TRANSITION) {
+                self.transition = None;
+            }
+
+            let active = self.active_curve(now);
+
+            let mut duties = Vec::with_capacity(self.channels.len());
+
+            for fan in &mut self.channels {
+                let curve = fan.curve.as_ref().map_or(&active, |(_, curve)| curve);
+                let duty = fan.temp(&temps, curve).and_then(|temp| match fan.target {
+                    Some(ref mut target) => Some(target.update((temp / 10) as i16, elapsed)),
+                    None => fan.controller.update(curve, (temp / 10) as i16, elapsed),
+                });
+
+                match duty {
+                    Some(duty) => {
+                        let duty = ((u32::from(duty) * 255) / 10_000) as u8;
+                        let calibration = self
+                            .calibrations
+                            .iter()
+                            .find(|calibration| calibration.channel == fan.channel);
+                        duties.push((fan.channel, calibration.map_or(duty, |cal| cal.clamp(duty))))
+                    }
+                    None => {
+                        fan.controller.reset();
+                        if let Some(ref mut target) = fan.target {
+                            target.reset();
+                        }
+                    }
+                }
+            }
+
+            if let Some(ref mut calibrator) = self.calibrator {
+                // The fans are stopped while they are calibrated, so it must be cool enough.
+                if self.emergency.too_hot(self.temp) {
+                    log::warn!("fan daemon: calibration cancelled at {:?}", self.temp);
+                    calibrator.cancel();
+                }
+
+                match calibrator.step(&rpms) {
+                    CalibrationStep::Drive(calibrating) => duties = calibrating,
+                    CalibrationStep::Finished(results) => {
+                        events.push(self.finish_calibration(results))
+                    }
+                }
+            }
+
+            if let Some((percent, deadline)) = self.manual {
+                if now >= deadline {
+                    log::info!("fan daemon: manual duty expired");
+                    self.manual = None;
+                } else if self.emergency.too_hot(self.temp) {
+                    log::warn!("fan daemon: manual duty cancelled at {:?}", self.temp);
+                    self.manual = None;
+                } else {
+                    let duty = ((u32::from(percent) * 255) / 100) as u8;
+                    duties = self.channels.iter().map(|fan| (fan.channel, duty)).collect();
+                }
+            }
+
+            if self.emergency.active() || self.channels.iter().any(|fan| fan.stall.failed()) {
+                duties = self.channels.iter().map(|fan| (fan.channel, 255)).collect();
+            }
+
+            duties
+        };
+
+        // Leave the fans to the firmware unless every channel has a duty.
+        if self.read_only {
+            self.duties.clear();
+        } else if duties.len() == self.channels.len() && !duties.is_empty() {
+            if let Err(why) = self.set_channel_duties(&duties) {
+                log::warn!("fan daemon: failed to set duty: {}", why);
+                self.set_duty(None);
+                self.duties.clear();
+                self.rediscovery.invalidate();
+            } else {
+                self.duties = duties;
+            }
+        } else {
+            self.set_duty(None);
+            self.duties.clear();
+        }
+
+        let time =
+            SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+        self.history.record(time, &temps, &self.duties, &rpms, self.profile);
+
+        events
+    }
+}
+
+/// What a scan of the hwmon devices found.
+struct HwmonScan {
+    /// The hwmons which drive the fans.
+    platforms:    Vec<HwMon>,
+    /// Whether the platforms are those of System76 laptops, rather than Thelio Io boards.
+    laptop:       bool,
+    /// Whether the platforms have no writable `pwm1_enable`.
+    read_only:    bool,
+    /// Whether a GPU hwmon of NVIDIA GPUs was found.
+    nvidia_hwmon: bool,
+    /// The number of fan channels of the platforms.
+    channels:     u8,
+    sources:      Vec<Box<dyn TemperatureSource>>,
+}
+
+/// Finds the hwmons which drive the fans, and the sources of the temperatures they follow.
+fn scan_hwmons(tree: &mut impl HwmonTree, config: &FanConfig) -> Result<HwmonScan, FanDaemonError> {
+    let mut platforms = Vec::new();
+    let mut laptops = Vec::new();
+    let mut nvidia_hwmon = false;
+    let mut sources: Vec<Box<dyn TemperatureSource>> = Vec::new();
+
+    for hwmon in tree.hwmons().map_err(FanDaemonError::HwmonDevices)? {
+        if let Ok(name) = tree.read(&hwmon, "name") {
+            let name = name.trim();
+            log::debug!("hwmon: {}", name);
+
+            for source in config.hwmon_sources(name, &hwmon, tree) {
+                sources.push(Box::new(source));
+            }
+
+            match name {
+                "amdgpu" => sources.push(Box::new(HwmonSource::gpu(hwmon))),
+                "nouveau" | "nvidia" => {
+                    nvidia_hwmon = true;
+                    sources.push(Box::new(HwmonSource::gpu(hwmon)));
+                }
+                "system76" | "system76_acpi" => laptops.push(hwmon),
+                "system76_io" | "system76_thelio_io" => platforms.push(hwmon),
+                "apm_xgene" | "coretemp" | "k10temp" => {
+                    sources.push(Box::new(HwmonSource::cpu(hwmon)))
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let laptop = platforms.is_empty() && !laptops.is_empty();
+    if laptop {
+        // The embedded controller reports the temperatures it controls the fans by, which for the
+        // GPU does not wake it from runtime suspend.
+        for platform in &laptops {
+            for input in 1..=2 {
+                let group = match tree.read(platform, &format!("temp{}_label", input)) {
+                    Ok(label) if label.trim() == "CPU" => SensorGroup::Cpu,
+                    Ok(label) if label.trim() == "GPU" => SensorGroup::Gpu,
+                    _ => continue,
+                };
+
+                sources.push(Box::new(HwmonSource::embedded(platform.clone(), input, group)));
+            }
+        }
+
+        platforms = laptops;
+    }
+
+    // Some versions of system76_acpi only report the duty.
+    let read_only = !platforms.iter().all(|platform| tree.writable(platform, "pwm1_enable"));
+
+    let channels = platforms
+        .iter()
+        .map(|platform| {
+            (1..=u8::MAX).take_while(|n| tree.exists(platform, &format!("pwm{}", n))).count() as u8
+        })
+        .max()
+        .unwrap_or(0);
+
+    Ok(HwmonScan { platforms, laptop, read_only, nvidia_hwmon, channels, sources })
+}
+
+impl Drop for FanDaemon {
+    fn drop(&mut self) { self.set_duty(None); }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FanPoint {
+    // Temperature in hundredths of a degree, 10000 = 100C
+    temp: i16,
+    // duty in hundredths of a percent, 10000 = 100%
+    duty: u16,
+}
+
+impl FanPoint {
+    pub const fn new(temp: i16, duty: u16) -> Self { Self { temp, duty } }
+
+    /// Temperature in hundredths of a degree Celsius.
+    pub const fn temp(self) -> i16 { self.temp }
+
+    /// Duty in hundredths of a percent.
+    pub const fn duty(self) -> u16 { self.duty }
+
+    /// Find the duty between two points and a given temperature, if the temperature
+    /// lies within this range.
+    fn get_duty_between_points(self, next: Self, temp: i16) -> Option<u16> {
+        // If the temp matches the next point, return the next point duty
+        if temp == next.temp {
+            return Some(next.duty);
+        }
+
+        // If the temp matches the previous point, return the previous point duty
+        if temp == self.temp {
+            return Some(self.duty);
+        }
+
+        // If the temp is in between the previous and next points, interpolate the duty
+        if self.temp < temp && next.temp > temp {
+            return Some(self.interpolate_duties(next, temp));
+        }
+
+        None
+    }
+
+    /// Interpolates the current duty with that of the given next point and temperature. The next
+    /// duty may be lower, on a descending segment.
+    fn interpolate_duties(self, next: Self, temp: i16) -> u16 {
+        let dtemp = i32::from(next.temp) - i32::from(self.temp);
+        let dduty = i32::from(next.duty) - i32::from(self.duty);
+
+        let slope = dduty as f32 / dtemp as f32;
+
+        let temp_offset = i32::from(temp) - i32::from(self.temp);
+        let duty_offset = (slope * temp_offset as f32).round() as i32;
+
+        (i32::from(self.duty) + duty_offset).clamp(0, i32::from(u16::MAX)) as u16
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FanCurve {
+    points:     Vec<FanPoint>,
+    tuning:     FanTuning,
+    /// Whether the duty may fall from one point to the next.
+    descending: bool,
+}
+
+/// Builds a [`FanCurve`] from points which are checked by [`FanCurve::validate`].
+#[derive(Debug, Default)]
+pub struct FanCurveBuilder {
+    curve: FanCurve,
+}
+
+impl FanCurveBuilder {
+    /// Adds a point, with a temperature in hundredths of a degree Celsius and a duty in hundredths
+    /// of a percent.
+    #[must_use]
+    pub fn point(mut self, temp: i16, duty: u16) -> Self {
+        self.curve.points.push(FanPoint::new(temp, duty));
+        self
+    }
+
+    #[must_use]
+    pub const fn tuning(mut self, tuning: FanTuning) -> Self {
+        self.curve.tuning = tuning;
+        self
+    }
+
+    /// Allows the duty to fall from one point to the next, such as for a fan which should slow
+    /// down when another takes over.
+    #[must_use]
+    pub const fn allow_descending(mut self, descending: bool) -> Self {
+        self.curve.descending = descending;
+        self
+    }
+
+    pub fn build(self) -> Result<FanCurve, FanCurveError> {
+        self.curve.validate()?;
+        Ok(self.curve)
+    }
+}
+
+impl FanCurve {
+    /// The names of the compiled-in fan curves.
+    pub const BUILTIN: &'static [&'static str] = &[
+        "standard",
+        "threadripper2",
+        "hedt",
+        "xeon",
+        "laptop_battery",
+        "laptop_balanced",
+        "laptop_performance",
+    ];
+
+    /// A compiled-in fan curve, by name.
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "standard" => Some(Self::standard()),
+            "threadripper2" => Some(Self::threadripper2()),
+            "hedt" => Some(Self::hedt()),
+            "xeon" => Some(Self::xeon()),
+            "laptop_battery" => Some(Self::laptop_battery()),
+            "laptop_balanced" => Some(Self::laptop_balanced()),
+            "laptop_performance" => Some(Self::laptop_performance()),
+            _ => None,
+        }
+    }
+
+    /// The name of the compiled-in fan curve for a model, from its DMI product version.
+    pub fn default_for_model(model: &str) -> &'static str {
+        match model {
+            "thelio-major-r1" => "threadripper2",
+            "thelio-astra-a1" | "thelio-astra-a1.1" | "thelio-major-r2" | "thelio-major-r2.1"
+            | "thelio-major-b1" | "thelio-major-b2" | "thelio-major-b3" | "thelio-mega-r1"
+            | "thelio-mega-r1.1" => "hedt",
+            "thelio-massive-b1" => "xeon",
+            _ => "standard",
+        }
+    }
+
+    /// The name of the compiled-in fan curve for laptops in a power profile.
+    pub const fn laptop_default(profile: Profile) -> &'static str {
+        match profile {
+            Profile::Battery => "laptop_battery",
+            Profile::Balanced => "laptop_balanced",
+            Profile::Performance => "laptop_performance",
+        }
+    }
+
+    pub fn builder() -> FanCurveBuilder { FanCurveBuilder::default() }
+
+    pub fn points(&self) -> &[FanPoint] { &self.points }
+
+    /// Whether the duty may fall from one point to the next.
+    pub const fn descending(&self) -> bool { self.descending }
+
+    pub const fn tuning(&self) -> FanTuning { self.tuning }
+
+    /// Changes how the curve follows changes in temperature
+    #[must_use]
+    pub const fn with_tuning(mut self, tuning: FanTuning) -> Self {
+        self.tuning = tuning;
+        self
+    }
+
+    /// Checks that the curve has at least two points, that temperatures are within -100-300°C and
+    /// increase from one point to the next, that duties are within 0-100% and do not decrease
+    /// unless allowed, and that the tuning is valid.
+    pub fn validate(&self) -> Result<(), FanCurveError> {
+        if self.points.len() < 2 {
+            return Err(FanCurveError::TooFewPoints);
+        }
+
+        for point in &self.points {
+            if !(-100_00..=300_00).contains(&point.temp) {
+                return Err(FanCurveError::TemperatureOutOfRange(f32::from(point.temp) / 100.0));
+            }
+
+            if point.duty > 100_00 {
+                return Err(FanCurveError::DutyOutOfRange(f32::from(point.duty) / 100.0));
+            }
+        }
+
+        for window in self.points.windows(2) {
+            let (prev, next) = (window[0], window[1]);
+
+            if next.temp == prev.temp {
+                return Err(FanCurveError::DuplicateTemperature(f32::from(next.temp) / 100.0));
+            }
+
+            if next.temp < prev.temp {
+                return Err(FanCurveError::UnsortedTemperatures(f32::from(next.temp) / 100.0));
+            }
+
+            if next.duty < prev.duty && !self.descending {
+                return Err(FanCurveError::DecreasingDuty(f32::from(next.duty) / 100.0));
+            }
+        }
+
+        self.tuning.validate()
+    }
+
+    /// Adds a point to the fan curve, without checking it. Curves which are not compiled in should
+    /// be made with [`FanCurve::builder`].
+    #[must_use]
+    pub fn append(mut self, temp: i16, duty: u16) -> Self {
+        self.points.push(FanPoint::new(temp, duty));
+        self
+    }
+
+    /// The standard fan curve
+    pub fn standard() -> Self {
+        Self::default()
+            .append(44_99, 0_00)
+            .append(45_00, 30_00)
+            .append(55_00, 35_00)
+            .append(65_00, 40_00)
+            .append(75_00, 50_00)
+            .append(78_00, 60_00)
+            .append(81_00, 70_00)
+            .append(84_00, 80_00)
+            .append(86_00, 90_00)
+            .append(88_00, 100_00)
+    }
+
+    /// Fan curve for threadripper 2
+    pub fn threadripper2() -> Self {
+        Self::default()
+            .append(00_00, 30_00)
+            .append(40_00, 40_00)
+            .append(47_50, 50_00)
+            .append(55_00, 65_00)
+            .append(62_50, 85_00)
+            .append(66_25, 100_00)
+    }
+
+    /// Fan curve for HEDT systems
+    pub fn hedt() -> Self {
+        Self::default()
+            .append(00_00, 30_00)
+            .append(50_00, 35_00)
+            .append(60_00, 45_00)
+            .append(70_00, 55_00)
+            .append(74_00, 60_00)
+            .append(76_00, 70_00)
+            .append(78_00, 80_00)
+            .append(81_00, 100_00)
+    }
+
+    /// Fan curve for xeon
+    pub fn xeon() -> Self {
+        Self::default()
+            .append(00_00, 40_00)
+            .append(50_00, 40_00)
+            .append(55_00, 45_00)
+            .append(60_00, 50_00)
+            .append(65_00, 55_00)
+            .append(70_00, 60_00)
+            .append(72_00, 65_00)
+            .append(74_00, 80_00)
+            .append(76_00, 85_00)
+            .append(77_00, 90_00)
+            .append(78_00, 100_00)
+    }
+
+    /// The curve part of the way from one curve to another, where `progress` is from 0 to 1. The
+    /// duty at each temperature is interpolated between the duties of both curves.
+    pub fn blend(from: &Self, to: &Self, progress: f32) -> Self {
+        let progress = progress.clamp(0.0, 1.0);
+
+        let mut temps =
+            from.points.iter().chain(&to.points).map(|point| point.temp).collect::<Vec<_>>();
+        temps.sort_unstable();
+        temps.dedup();
+
+        let mut curve = Self::default().with_tuning(to.tuning);
+        curve.descending = from.descending || to.descending;
+        for temp in temps {
+            let (Some(from_duty), Some(to_duty)) = (from.get_duty(temp), to.get_duty(temp)) else {
+                continue;
+            };
+
+            let duty =
+                f32::from(from_duty) + (f32::from(to_duty) - f32::from(from_duty)) * progress;
+            curve = curve.append(temp, duty.round() as u16);
+        }
+
+        curve
+    }
+
+    /// Quiet fan curve for laptops on battery
+    pub fn laptop_battery() -> Self {
+        Self::default()
+            .append(54_99, 0_00)
+            .append(55_00, 20_00)
+            .append(65_00, 25_00)
+            .append(75_00, 35_00)
+            .append(82_00, 50_00)
+            .append(88_00, 75_00)
+            .append(93_00, 100_00)
+    }
+
+    /// Fan curve for laptops in the balanced profile
+    pub fn laptop_balanced() -> Self {
+        Self::default()
+            .append(49_99, 0_00)
+            .append(50_00, 25_00)
+            .append(60_00, 30_00)
+            .append(70_00, 40_00)
+            .append(78_00, 55_00)
+            .append(85_00, 80_00)
+            .append(90_00, 100_00)
+    }
+
+    /// Fan curve for laptops in the performance profile
+    pub fn laptop_performance() -> Self {
+        Self::default()
+            .append(00_00, 25_00)
+            .append(50_00, 30_00)
+            .append(60_00, 40_00)
+            .append(70_00, 60_00)
+            .append(78_00, 80_00)
+            .append(85_00, 100_00)
+    }
+
+    /// The lowest temperature at which the curve gives a duty, which is the inverse of
+    /// [`FanCurve::get_duty`]. A descending curve may give the duty on several segments, of which
+    /// the first is used. Above the highest duty of the curve, the temperature at which it gives
+    /// its highest duty is returned.
+    pub fn temp_for_duty(&self, duty: u16) -> Option<i16> {
+        let first = self.points.first()?;
+        if duty <= first.duty {
+            return Some(first.temp);
+        }
+
+        // Every point before `next` gives less than the duty, so a segment which reaches it rises.
+        for window in self.points.windows(2) {
+            let (prev, next) = (window[0], window[1]);
+            if duty <= next.duty {
+                let progress = f32::from(duty - prev.duty) / f32::from(next.duty - prev.duty);
+                let span = i32::from(next.temp) - i32::from(prev.temp);
+                let offset = (span as f32 * progress).round() as i32;
+                return Some((i32::from(prev.temp) + offset) as i16);
+            }
+        }
+
+        self.points.iter().rev().max_by_key(|point| point.duty).map(|point| point.temp)
+    }
+
+    pub fn get_duty(&self, temp: i16) -> Option<u16> {
+        // If the temp is less than the first point, return the first point duty
+        if let Some(first) = self.points.first() {
+            if temp < first.temp {
+                return Some(first.duty);
+            }
+        }
+
+        // Use when we upgrade to 1.28.0
+        // for &[prev, next] in self.points.windows(2) {
+
+        for window in self.points.windows(2) {
+            let prev = window[0];
+            let next = window[1];
+            if let Some(duty) = prev.get_duty_between_points(next, temp) {
+                return Some(duty);
+            }
+        }
+
+        // If the temp is greater than the last point, return the last point duty
+        if let Some(last) = self.points.last() {
+            if temp > last.temp {
+                return Some(last.duty);
+            }
+        }
+
+        // If there are no points, return None
+        None
+    }
+}
+
+/// Writes the points as `temperature:duty` pairs in degrees Celsius and percent, such as
+/// `45:30,55:35,88:100`, after a `descending` marker if the curve allows descending segments.
+/// The tuning is not written.
+impl fmt::Display for FanCurve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.descending {
+            f.write_str("descending,")?;
+        }
+
+        for (n, point) in self.points.iter().enumerate() {
+            if n != 0 {
+                f.write_str(",")?;
+            }
+
+            write!(f, "{}:{}", f32::from(point.temp) / 100.0, f32::from(point.duty) / 100.0)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the points written by [`FanCurve`]'s `Display`, with the default tuning. Descending
+/// segments are only allowed after the `descending` marker.
+impl FromStr for FanCurve {
+    type Err = FanCurveError;
+
+    fn from_str(points: &str) -> Result<Self, Self::Err> {
+        let (descending, points) = match points.split_once(',') {
+            Some((marker, points)) if marker.trim() == "descending" => (true, points),
+            _ => (false, points),
+        };
+
+        let mut builder = Self::builder().allow_descending(descending);
+
+        for point in points.split(',') {
+            let invalid = || FanCurveError::InvalidPoint(point.trim().to_owned());
+            let (temp, duty) = point.split_once(':').ok_or_else(invalid)?;
+            let temp = temp.trim().parse::<f32>().map_err(|_| invalid())?;
+            let duty = duty.trim().parse::<f32>().map_err(|_| invalid())?;
+
+            if !(-100.0..=300.0).contains(&temp) {
+                return Err(FanCurveError::TemperatureOutOfRange(temp));
+            }
+
+            if !(0.0..=100.0).contains(&duty) {
+                return Err(FanCurveError::DutyOutOfRange(duty));
+            }
+
+            builder = builder.point((temp * 100.0).round() as i16, (duty * 100.0).round() as u16);
+        }
+
+        builder.build()
+    }
+}
+
+pub fn nvidia_temperatures<F: FnMut(u32)>(func: F) -> io::Result<()> {
+    let output = Command::new("nvidia-smi")
+        .arg("--query-gpu=temperature.gpu")
+        .arg("--format=csv,noheader")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .stdin(Stdio::null())
+        .output()?;
+
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "non-utf8 output"))?;
+
+    stdout.lines().filter_map(|line| line.parse::<u32>().ok()).for_each(func);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_curves_are_valid() {
+        for name in FanCurve::BUILTIN {
+            assert_eq!(FanCurve::builtin(name).map(|curve| curve.validate()), Some(Ok(())));
+        }
+    }
+
+    #[test]
+    fn invalid_curves() {
+        assert_eq!(
+            FanCurve::default().append(50_00, 30_00).validate(),
+            Err(FanCurveError::TooFewPoints)
+        );
+
+        let unsorted = FanCurve::default().append(50_00, 30_00).append(40_00, 40_00);
+        assert_eq!(unsorted.validate(), Err(FanCurveError::UnsortedTemperatures(40.0)));
+
+        let decreasing = FanCurve::default().append(40_00, 40_00).append(50_00, 30_00);
+        assert_eq!(decreasing.validate(), Err(FanCurveError::DecreasingDuty(30.0)));
+
+        let out_of_range = FanCurve::default().append(40_00, 40_00).append(50_00, 100_01);
+        assert_eq!(out_of_range.validate(), Err(FanCurveError::DutyOutOfRange(100.01)));
+    }
+
+    #[test]
+    fn config_curves() {
+        let config: FanConfig = toml::from_str(
+            r#"
+            curve = "quiet"
+
+            [curves.quiet]
+            points = [[50, 0], [55, 25], [88.5, 100]]
+
+            [curves.standard]
+            points = [[40, 20], [80, 100]]
+
+            [curves.broken]
+            points = [[50, 30], [40, 100]]
+            "#,
+        )
+        .unwrap();
+
+        let quiet = FanCurve::default().append(50_00, 0).append(55_00, 25_00).append(88_50, 100_00);
+        assert_eq!(config.curve("quiet").unwrap(), quiet);
+        assert_eq!(config.curve("standard").unwrap().points().len(), 2);
+        assert_eq!(config.curve("hedt").unwrap(), FanCurve::hedt());
+        assert!(matches!(config.curve("broken"), Err(FanConfigError::InvalidCurve(..))));
+        assert!(matches!(config.curve("loud"), Err(FanConfigError::UnknownCurve(_))));
+        assert_eq!(config.curve_names()[FanCurve::BUILTIN.len()..], ["broken", "quiet"]);
+    }
+
+    #[test]
+    fn laptop_profile_curves() {
+        let battery = FanCurve::laptop_battery();
+        let performance = FanCurve::laptop_performance();
+
+        // The battery curve is never louder than the performance curve.
+        for temp in (40..=95).map(|temp| temp * 100) {
+            assert!(battery.get_duty(temp) <= performance.get_duty(temp), "at {}", temp);
+        }
+
+        assert_eq!(FanCurve::laptop_default(Profile::Balanced), "laptop_balanced");
+        assert_eq!(battery.get_duty(50_00), Some(0));
+    }
+
+    #[test]
+    fn blended_curves() {
+        let from = FanCurve::laptop_battery();
+        let to = FanCurve::laptop_performance();
+
+        assert_eq!(FanCurve::blend(&from, &to, 0.0).get_duty(70_00), from.get_duty(70_00));
+        assert_eq!(FanCurve::blend(&from, &to, 1.0).get_duty(70_00), to.get_duty(70_00));
+
+        // Halfway, each duty is halfway between those of both curves.
+        let halfway = FanCurve::blend(&from, &to, 0.5);
+        assert_eq!(halfway.validate(), Ok(()));
+        for temp in (30..=100).map(|temp| temp * 100) {
+            let (low, high) = (from.get_duty(temp).unwrap(), to.get_duty(temp).unwrap());
+            let duty = halfway.get_duty(temp).unwrap();
+            assert!(duty.abs_diff((low + high) / 2) <= 1, "{} at {}", duty, temp);
+        }
+    }
+
+    #[test]
+    fn profile_curves() {
+        let config: FanConfig = toml::from_str(
+            r#"
+            curve = "hedt"
+
+            [profiles]
+            battery = "laptop_battery"
+            "#,
+        )
+        .unwrap();
+
+        let choose = |selected: Option<&str>, profile| {
+            config.choose_curve(selected.map(String::from), profile, "standard")
+        };
+
+        assert_eq!(choose(None, Profile::Battery), "laptop_battery");
+        assert_eq!(choose(None, Profile::Performance), "hedt");
+        assert_eq!(choose(Some("xeon"), Profile::Battery), "xeon");
+        assert_eq!(choose(Some("loud"), Profile::Battery), "laptop_battery");
+        assert_eq!(
+            FanConfig::default().choose_curve(None, Profile::Battery, "standard"),
+            "standard"
+        );
+    }
+
+    #[test]
+    fn inverse_duty() {
+        let curve = FanCurve::standard();
+        for duty in [30_00, 35_00, 47_50, 100_00] {
+            let temp = curve.temp_for_duty(duty).unwrap();
+            assert_eq!(curve.get_duty(temp), Some(duty), "at {}", temp);
+        }
+
+        assert_eq!(curve.temp_for_duty(0), Some(44_99));
+        assert_eq!(FanCurve::default().temp_for_duty(50_00), None);
+
+        // The whole range of temperatures spans more than an i16.
+        let wide = "-100:0,300:100".parse::<FanCurve>().unwrap();
+        assert_eq!(wide.temp_for_duty(50_00), Some(100_00));
+        assert_eq!(wide.temp_for_duty(100_00), Some(300_00));
+
+        // A descending curve gives a duty first on its rising segment, and its highest duty at its
+        // peak rather than at its last point.
+        let descending = FanCurve::builder()
+            .point(40_00, 30_00)
+            .point(60_00, 80_00)
+            .point(80_00, 50_00)
+            .allow_descending(true)
+            .build()
+            .unwrap();
+        assert_eq!(descending.temp_for_duty(55_00), Some(50_00));
+        assert_eq!(descending.temp_for_duty(80_00), Some(60_00));
+        assert_eq!(descending.temp_for_duty(100_00), Some(60_00));
+    }
+
+    #[test]
+    fn duty_interpolation() {
+        let fan_point = FanPoint::new(20_00, 30_00);
+        let next_point = FanPoint::new(30_00, 35_00);
+
+        assert_eq!(fan_point.get_duty_between_points(next_point, 1500), None);
+        assert_eq!(fan_point.get_duty_between_points(next_point, 2000), Some(3000));
+        assert_eq!(fan_point.get_duty_between_points(next_point, 3000), Some(3500));
+        assert_eq!(fan_point.get_duty_between_points(next_point, 3250), None);
+        assert_eq!(fan_point.get_duty_between_points(next_point, 3500), None);
+    }
+
+    /// A xorshift generator, to check properties over many curves without a property testing
+    /// crate.
+    struct Rng(u64);
+
+    impl Rng {
+        fn range(&mut self, low: i32, high: i32) -> i32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            low + (self.0 % (high - low + 1) as u64) as i32
+        }
+
+        fn curve(&mut self, descending: bool) -> FanCurve {
+            let mut builder = FanCurve::builder().allow_descending(descending);
+            let mut temp = self.range(-100_00, 0);
+            let mut duty = self.range(0, 50_00);
+
+            for _ in 0..self.range(2, 8) {
+                builder = builder.point(temp as i16, duty as u16);
+                temp += self.range(1, 40_00);
+                duty = self.range(if descending { 0 } else { duty }, 100_00);
+            }
+
+            builder.build().unwrap()
+        }
+    }
+
+    #[test]
+    fn interpolation_edges() {
+        let mut rng = Rng(0x5EED);
+
+        for n in 0..1000 {
+            let descending = n % 2 == 1;
+            let curve = rng.curve(descending);
+            let points = curve.points();
+            let (first, last) = (points[0], points[points.len() - 1]);
+
+            assert_eq!(curve.get_duty(i16::MIN), Some(first.duty), "{}", curve);
+            assert_eq!(curve.get_duty(first.temp - 1), Some(first.duty), "{}", curve);
+            assert_eq!(curve.get_duty(last.temp + 1), Some(last.duty), "{}", curve);
+            assert_eq!(curve.get_duty(i16::MAX), Some(last.duty), "{}", curve);
+
+            for window in points.windows(2) {
+                let (prev, next) = (window[0], window[1]);
+                let (low, high) = (prev.duty.min(next.duty), prev.duty.max(next.duty));
+
+                assert_eq!(curve.get_duty(prev.temp), Some(prev.duty), "{}", curve);
+                assert_eq!(curve.get_duty(next.temp), Some(next.duty), "{}", curve);
+
+                let middle = ((i32::from(prev.temp) + i32::from(next.temp)) / 2) as i16;
+                for temp in [prev.temp + 1, middle, next.temp - 1] {
+                    let duty = curve.get_duty(temp).unwrap();
+                    assert!((low..=high).contains(&duty), "{}°C: {} in {}", temp, duty, curve);
+                }
+            }
+
+            if !descending {
+                let duties = (first.temp - 100..=last.temp + 100)
+                    .step_by(37)
+                    .map(|temp| curve.get_duty(temp).unwrap())
+                    .collect::<Vec<_>>();
+                assert!(duties.windows(2).all(|pair| pair[0] <= pair[1]), "{}", curve);
+            }
+        }
+    }
+
+    #[test]
+    fn descending_segments() {
+        let builder = || FanCurve::builder().point(40_00, 80_00).point(60_00, 20_00);
+
+        assert_eq!(builder().build(), Err(FanCurveError::DecreasingDuty(20.0)));
+
+        // Interpolating down a segment used to underflow.
+        let curve = builder().allow_descending(true).build().unwrap();
+        assert_eq!(curve.get_duty(50_00), Some(50_00));
+        assert_eq!(curve.get_duty(59_99), Some(20_03));
+
+        assert_eq!(
+            FanCurve::builder().point(40_00, 30_00).point(40_00, 40_00).build(),
+            Err(FanCurveError::DuplicateTemperature(40.0))
+        );
+        assert_eq!(
+            FanCurve::builder().point(-150_00, 30_00).point(40_00, 40_00).build(),
+            Err(FanCurveError::TemperatureOutOfRange(-150.0))
+        );
+    }
+
+    #[test]
+    fn textual_curves() {
+        let curve = "45:30,55:35,88:100".parse::<FanCurve>().unwrap();
+        assert_eq!(
+            curve,
+            FanCurve::builder()
+                .point(45_00, 30_00)
+                .point(55_00, 35_00)
+                .point(88_00, 100_00)
+                .build()
+                .unwrap()
+        );
+        assert_eq!(curve.to_string(), "45:30,55:35,88:100");
+
+        assert_eq!(
+            FanCurve::standard().to_string(),
+            "44.99:0,45:30,55:35,65:40,75:50,78:60,81:70,84:80,86:90,88:100"
+        );
+        assert_eq!(
+            " 20.5 : 12.25 , 60:100".parse::<FanCurve>().unwrap().to_string(),
+            "20.5:12.25,60:100"
+        );
+
+        assert_eq!("45:30".parse::<FanCurve>(), Err(FanCurveError::TooFewPoints));
+        assert_eq!(
+            "45:30,55".parse::<FanCurve>(),
+            Err(FanCurveError::InvalidPoint("55".to_owned()))
+        );
+        assert_eq!("".parse::<FanCurve>(), Err(FanCurveError::InvalidPoint(String::new())));
+        assert_eq!("45:30,55:120".parse::<FanCurve>(), Err(FanCurveError::DutyOutOfRange(120.0)));
+        assert_eq!(
+            "45:30,40:40".parse::<FanCurve>(),
+            Err(FanCurveError::UnsortedTemperatures(40.0))
+        );
+
+        // Descending segments need the marker.
+        let descending = "descending, 45:50,60:20".parse::<FanCurve>().unwrap();
+        assert!(descending.descending());
+        assert_eq!(descending.to_string(), "descending,45:50,60:20");
+        assert_eq!("45:50,60:20".parse::<FanCurve>(), Err(FanCurveError::DecreasingDuty(20.0)));
+        assert_eq!(
+            "descending".parse::<FanCurve>(),
+            Err(FanCurveError::InvalidPoint("descending".to_owned()))
+        );
+
+        let mut rng = Rng(0xC0FFEE);
+        for n in 0..1000 {
+            let curve = rng.curve(n % 2 == 1);
+            assert_eq!(curve.to_string().parse::<FanCurve>().as_ref(), Ok(&curve));
+        }
+    }
+
+    #[test]
+    fn standard_points() {
+        let standard = FanCurve::standard();
+
+        assert_eq!(standard.get_duty(0), Some(0));
+        assert_eq!(standard.get_duty(4499), Some(0));
+        assert_eq!(standard.get_duty(4500), Some(3000));
+        assert_eq!(standard.get_duty(5500), Some(3500));
+        assert_eq!(standard.get_duty(6500), Some(4000));
+        assert_eq!(standard.get_duty(7500), Some(5000));
+        assert_eq!(standard.get_duty(7800), Some(6000));
+        assert_eq!(standard.get_duty(8100), Some(7000));
+        assert_eq!(standard.get_duty(8400), Some(8000));
+        assert_eq!(standard.get_duty(8600), Some(9000));
+        assert_eq!(standard.get_duty(8800), Some(10000));
+        assert_eq!(standard.get_duty(10000), Some(10000));
+    }
+
+    #[test]
+    fn hedt_points() {
+        let hedt = FanCurve::hedt();
+
+        assert_eq!(hedt.get_duty(0), Some(3000));
+        assert_eq!(hedt.get_duty(5000), Some(3500));
+        assert_eq!(hedt.get_duty(6000), Some(4500));
+        assert_eq!(hedt.get_duty(7000), Some(5500));
+        assert_eq!(hedt.get_duty(7400), Some(6000));
+        assert_eq!(hedt.get_duty(7600), Some(7000));
+        assert_eq!(hedt.get_duty(7800), Some(8000));
+        assert_eq!(hedt.get_duty(8100), Some(10000));
+        assert_eq!(hedt.get_duty(10000), Some(10000));
+    }
+
+    #[test]
+    fn threadripper2_points() {
+        let threadripper2 = FanCurve::threadripper2();
+
+        assert_eq!(threadripper2.get_duty(0), Some(3000));
+        assert_eq!(threadripper2.get_duty(4000), Some(4000));
+        assert_eq!(threadripper2.get_duty(4750), Some(5000));
+        assert_eq!(threadripper2.get_duty(5500), Some(6500));
+        assert_eq!(threadripper2.get_duty(6250), Some(8500));
+        assert_eq!(threadripper2.get_duty(6625), Some(10000));
+        assert_eq!(threadripper2.get_duty(10000), Some(10000));
+    }
+
+    #[test]
+    fn xeon_points() {
+        let xeon = FanCurve::xeon();
+
+        assert_eq!(xeon.get_duty(0), Some(4000));
+        assert_eq!(xeon.get_duty(5000), Some(4000));
+        assert_eq!(xeon.get_duty(5500), Some(4500));
+        assert_eq!(xeon.get_duty(6000), Some(5000));
+        assert_eq!(xeon.get_duty(6500), Some(5500));
+        assert_eq!(xeon.get_duty(7000), Some(6000));
+        assert_eq!(xeon.get_duty(7200), Some(6500));
+        assert_eq!(xeon.get_duty(7400), Some(8000));
+        assert_eq!(xeon.get_duty(7600), Some(8500));
+        assert_eq!(xeon.get_duty(7700), Some(9000));
+        assert_eq!(xeon.get_duty(7800), Some(10000));
+        assert_eq!(xeon.get_duty(10000), Some(10000));
+    }
+}
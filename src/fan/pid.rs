@@ -0,0 +1,233 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Holds a fan's temperature at a target, with the lowest duty which does so, rather than following
+//! a curve.
+
+/// How a fan holds its temperature at a target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PidTuning {
+    /// The temperature to hold, in degrees Celsius.
+    pub target:   f32,
+    /// Duty, in percent, per degree above the target.
+    pub kp:       f32,
+    /// Duty, in percent, added each second per degree above the target.
+    pub ki:       f32,
+    /// Duty, in percent, per degree per second that the temperature rises.
+    pub kd:       f32,
+    /// The lowest duty, in percent.
+    pub min_duty: f32,
+    /// The highest duty, in percent.
+    pub max_duty: f32,
+}
+
+impl Default for PidTuning {
+    fn default() -> Self {
+        Self {
+            target:   75.0,
+            kp:       5.0,
+            ki:       0.2,
+            kd:       0.0,
+            min_duty: 0.0,
+            max_duty: 100.0,
+        }
+    }
+}
+
+impl PidTuning {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !(0.0..=150.0).contains(&self.target) {
+            return Err("the target temperature should be within 0-150°C");
+        }
+
+        if [self.kp, self.ki, self.kd].iter().any(|gain| !(*gain >= 0.0 && gain.is_finite())) {
+            return Err("the gains must not be negative");
+        }
+
+        if self.kp == 0.0 && self.ki == 0.0 {
+            return Err("either kp or ki must be above 0");
+        }
+
+        if !(0.0..=100.0).contains(&self.min_duty) || !(0.0..=100.0).contains(&self.max_duty) {
+            return Err("the duties should be within 0-100%");
+        }
+
+        if self.min_duty > self.max_duty {
+            return Err("the minimum duty is above the maximum duty");
+        }
+
+        Ok(())
+    }
+}
+
+/// The state kept between samples to hold a temperature at its target.
+#[derive(Debug)]
+pub struct PidController {
+    tuning:   PidTuning,
+    /// The duty, in percent, accumulated from the error over time. It is kept within the duty
+    /// limits, and does not grow while the duty is limited, so that it does not wind up.
+    integral: f32,
+    /// The previous temperature, in degrees Celsius.
+    previous: Option<f32>,
+}
+
+impl PidController {
+    /// A controller which starts at the lowest duty.
+    pub fn new(tuning: PidTuning) -> Self {
+        Self { tuning, integral: tuning.min_duty, previous: None }
+    }
+
+    pub const fn tuning(&self) -> PidTuning { self.tuning }
+
+    /// Takes a temperature in hundredths of a degree, sampled `elapsed` seconds after the previous
+    /// one, and returns the duty in hundredths of a percent.
+    pub fn update(&mut self, temp: i16, elapsed: f32) -> u16 {
+        let tuning = self.tuning;
+        let temp = f32::from(temp) / 100.0;
+        let elapsed = elapsed.max(0.0);
+        let error = temp - tuning.target;
+
+        // The derivative follows the temperature rather than the error, which does not change
+        // when the target does.
+        let derivative = match self.previous {
+            Some(previous) if elapsed > 0.0 => (temp - previous) / elapsed,
+            _ => 0.0,
+        };
+        self.previous = Some(temp);
+
+        let integral = self.integral + tuning.ki * error * elapsed;
+        let unlimited = tuning.kp * error + integral + tuning.kd * derivative;
+        let winding_up = (unlimited > tuning.max_duty && error > 0.0)
+            || (unlimited < tuning.min_duty && error < 0.0);
+        if !winding_up {
+            self.integral = integral.clamp(tuning.min_duty, tuning.max_duty);
+        }
+
+        let duty = (tuning.kp * error + self.integral + tuning.kd * derivative)
+            .clamp(tuning.min_duty, tuning.max_duty);
+
+        (duty * 100.0).round() as u16
+    }
+
+    /// Forgets the previous samples, such as when the temperature can not be read.
+    pub fn reset(&mut self) { *self = Self::new(self.tuning); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A heat source cooled by a fan, whose sensor lags behind its temperature.
+    struct Plant {
+        temp:   f32,
+        sensor: f32,
+    }
+
+    impl Plant {
+        const AMBIENT: f32 = 25.0;
+        /// Heat, in joules, to raise the temperature by a degree.
+        const CAPACITY: f32 = 20.0;
+
+        fn new(temp: f32) -> Self { Self { temp, sensor: temp } }
+
+        /// Runs for a second with a heat source in watts and a duty in hundredths of a percent.
+        fn step(&mut self, heat: f32, duty: u16) {
+            // Cooling, in watts per degree above the ambient temperature, rises with the duty.
+            let conductance = 0.5 + 2.0 * f32::from(duty) / 10_000.0;
+            for _ in 0..10 {
+                self.temp +=
+                    (heat - conductance * (self.temp - Self::AMBIENT)) / Self::CAPACITY / 10.0;
+            }
+
+            self.sensor += (self.temp - self.sensor) / 2.0;
+        }
+    }
+
+    /// Runs the controller against the plant with a heat source in watts, one second per step,
+    /// and returns the temperature and the duty in percent at each step.
+    fn run(
+        plant: &mut Plant,
+        controller: &mut PidController,
+        heat: f32,
+        steps: usize,
+    ) -> Vec<(f32, f32)> {
+        (0..steps)
+            .map(|_| {
+                let duty = controller.update((plant.sensor * 100.0).round() as i16, 1.0);
+                plant.step(heat, duty);
+                (plant.temp, f32::from(duty) / 100.0)
+            })
+            .collect()
+    }
+
+    fn range(samples: &[(f32, f32)]) -> (f32, f32) {
+        samples
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(low, high), &(temp, _)| (low.min(temp), high.max(temp)))
+    }
+
+    #[test]
+    fn holds_target() {
+        let mut plant = Plant::new(40.0);
+        let mut controller = PidController::new(PidTuning::default());
+        let trace = run(&mut plant, &mut controller, 60.0, 600);
+
+        // It settles at the target without oscillating, and overshoots by a few degrees at most.
+        let (low, high) = range(&trace[500..]);
+        assert!(low > 74.8 && high < 75.2, "{}-{}°C", low, high);
+        assert!(range(&trace).1 < 82.0);
+
+        // At the duty which balances the heat, rather than a higher one.
+        let duty = trace.last().unwrap().1;
+        assert!((34.0..=36.0).contains(&duty), "{}%", duty);
+
+        // And follows a change of load back to the target.
+        let trace = run(&mut plant, &mut controller, 80.0, 600);
+        let (low, high) = range(&trace[500..]);
+        assert!(low > 74.8 && high < 75.2, "{}-{}°C", low, high);
+        assert!(range(&trace).1 < 80.0);
+    }
+
+    #[test]
+    fn limits_windup() {
+        // Too much heat for the fan, which runs at its highest duty.
+        let mut plant = Plant::new(75.0);
+        let mut controller = PidController::new(PidTuning::default());
+        let trace = run(&mut plant, &mut controller, 150.0, 300);
+        assert!(trace[100..].iter().all(|&(_, duty)| duty >= 95.0));
+
+        // Once the heat falls, the duty falls with it, rather than unwinding for minutes.
+        let trace = run(&mut plant, &mut controller, 40.0, 300);
+        assert!(trace[10].1 < 50.0, "{}%", trace[10].1);
+        let (low, high) = range(&trace[200..]);
+        assert!(low > 74.5 && high < 75.5, "{}-{}°C", low, high);
+
+        // Too little heat to reach the target, with the fan at its lowest duty.
+        let tuning = PidTuning { min_duty: 20.0, ..PidTuning::default() };
+        let mut plant = Plant::new(40.0);
+        let mut controller = PidController::new(tuning);
+        let trace = run(&mut plant, &mut controller, 10.0, 300);
+        assert!(trace.iter().all(|&(_, duty)| duty == 20.0));
+
+        // The overshoot once the heat rises is no worse than from a fresh start.
+        let mut fresh = Plant::new(plant.temp);
+        let trace = run(&mut plant, &mut controller, 60.0, 300);
+        let fresh = run(&mut fresh, &mut PidController::new(tuning), 60.0, 300);
+        assert!(range(&trace).1 <= range(&fresh).1 + 0.5);
+    }
+
+    #[test]
+    fn invalid_tuning() {
+        assert_eq!(PidTuning::default().validate(), Ok(()));
+
+        let invalid = [
+            PidTuning { target: 200.0, ..PidTuning::default() },
+            PidTuning { kp: -1.0, ..PidTuning::default() },
+            PidTuning { kp: 0.0, ki: 0.0, ..PidTuning::default() },
+            PidTuning { max_duty: 120.0, ..PidTuning::default() },
+            PidTuning { min_duty: 60.0, max_duty: 40.0, ..PidTuning::default() },
+        ];
+        assert!(invalid.iter().all(|tuning| tuning.validate().is_err()));
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Runs a fan curve without the hardware, to see what it does before it is deployed.
+
+use super::{FanController, FanCurve};
+
+/// The temperatures of a sweep, in degrees Celsius.
+pub const SWEEP: std::ops::RangeInclusive<i16> = 0..=100;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum TraceError {
+    #[error("line {}: expected a temperature, or a time and a temperature", _0)]
+    InvalidLine(usize),
+    #[error("line {}: temperature {}°C is out of range: should be -100-300°C", _0, _1)]
+    TemperatureOutOfRange(usize, f32),
+    #[error("line {}: time {}s is not after that of the previous sample", _0, _1)]
+    UnsortedTime(usize, f32),
+}
+
+/// A recorded temperature, in degrees Celsius, and when it was recorded, in seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceSample {
+    pub time: f32,
+    pub temp: f32,
+}
+
+/// Parses a trace of temperatures in degrees Celsius, one per line, either alone and a second
+/// apart, or after the time of the sample in seconds: `time,temperature`. A header and lines
+/// starting with `#` are skipped.
+pub fn parse_trace(csv: &str) -> Result<Vec<TraceSample>, TraceError> {
+    let mut trace: Vec<TraceSample> = Vec::new();
+
+    for (n, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields = line.split(',').map(|field| field.trim().parse::<f32>()).collect::<Vec<_>>();
+        let sample = match fields.as_slice() {
+            [Ok(temp)] => {
+                TraceSample { time: trace.last().map_or(0.0, |last| last.time + 1.0), temp: *temp }
+            }
+            [Ok(time), Ok(temp)] => TraceSample { time: *time, temp: *temp },
+            _ if trace.is_empty() && fields.iter().all(Result::is_err) => continue,
+            _ => return Err(TraceError::InvalidLine(n + 1)),
+        };
+
+        if !(-100.0..=300.0).contains(&sample.temp) {
+            return Err(TraceError::TemperatureOutOfRange(n + 1, sample.temp));
+        }
+
+        if !sample.time.is_finite() || trace.last().is_some_and(|last| sample.time <= last.time) {
+            return Err(TraceError::UnsortedTime(n + 1, sample.time));
+        }
+
+        trace.push(sample);
+    }
+
+    Ok(trace)
+}
+
+/// The duty which a curve gave for a temperature.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimulatedDuty {
+    /// Seconds since the start of the trace.
+    pub time:   f32,
+    /// Degrees Celsius.
+    pub temp:   f32,
+    /// The duty of the curve at this temperature, in hundredths of a percent.
+    pub target: Option<u16>,
+    /// The duty after the curve's smoothing, ramp rates and hysteresis, in hundredths of a
+    /// percent.
+    pub duty:   Option<u16>,
+}
+
+impl SimulatedDuty {
+    /// The duty as it would be written to `pwmN`, from 0 to 255.
+    pub fn pwm(&self) -> Option<u8> {
+        self.duty.map(|duty| ((u32::from(duty) * 255) / 10_000) as u8)
+    }
+}
+
+/// The duty of the curve at each temperature of the sweep, without smoothing.
+pub fn sweep(curve: &FanCurve) -> Vec<SimulatedDuty> {
+    SWEEP
+        .map(|temp| {
+            let duty = curve.get_duty(temp * 100);
+            SimulatedDuty { time: 0.0, temp: f32::from(temp), target: duty, duty }
+        })
+        .collect()
+}
+
+/// Replays a trace through the curve, as the fan daemon would have followed it.
+pub fn replay(curve: &FanCurve, trace: &[TraceSample]) -> Vec<SimulatedDuty> {
+    let mut controller = FanController::default();
+    let mut previous: Option<f32> = None;
+
+    trace
+        .iter()
+        .map(|sample| {
+            let temp = (sample.temp * 100.0).round() as i16;
+            let elapsed = previous.map_or(1.0, |previous| sample.time - previous);
+            previous = Some(sample.time);
+
+            SimulatedDuty {
+                time:   sample.time,
+                temp:   sample.temp,
+                target: curve.get_duty(temp),
+                duty:   controller.update(curve, temp, elapsed),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traces() {
+        let sample = |time, temp| TraceSample { time, temp };
+
+        assert_eq!(
+            parse_trace("temperature\n45\n\n# load\n90.5\n"),
+            Ok(vec![sample(0.0, 45.0), sample(1.0, 90.5)])
+        );
+        assert_eq!(
+            parse_trace("time,temp\n0,45\n2.5,60\n10,70\n"),
+            Ok(vec![sample(0.0, 45.0), sample(2.5, 60.0), sample(10.0, 70.0)])
+        );
+        assert_eq!(parse_trace("45\nhot\n"), Err(TraceError::InvalidLine(2)));
+        assert_eq!(parse_trace("0,45\n0,50\n"), Err(TraceError::UnsortedTime(2, 0.0)));
+        assert_eq!(parse_trace("400\n"), Err(TraceError::TemperatureOutOfRange(1, 400.0)));
+    }
+
+    #[test]
+    fn simulated_duties() {
+        let curve = FanCurve::standard();
+
+        let table = sweep(&curve);
+        assert_eq!(table.len(), SWEEP.len());
+        assert!(table.windows(2).all(|pair| pair[0].duty <= pair[1].duty));
+        assert_eq!(table.last().and_then(SimulatedDuty::pwm), Some(255));
+
+        // A jump in temperature is followed at the curve's ramp rate.
+        let trace = parse_trace("45\n45\n90\n90\n90\n").unwrap();
+        let duties = replay(&curve, &trace);
+        assert_eq!(duties[0].duty, duties[0].target);
+        assert_eq!(duties[2].target, Some(10_000));
+        assert!(duties[2].duty < duties[3].duty && duties[3].duty < duties[4].duty);
+
+        // Without tuning, the replay matches the curve.
+        let raw = FanCurve::standard().with_tuning(crate::fan::FanTuning {
+            smoothing:  1.0,
+            ramp_up:    100.0,
+            ramp_down:  100.0,
+            hysteresis: 0.0,
+        });
+        assert!(replay(&raw, &trace).iter().all(|duty| duty.duty == duty.target));
+    }
+}
@@ -0,0 +1,420 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Sources of the temperatures which the fans follow.
+//!
+//! Reading the temperature of a discrete GPU which is runtime-suspended would wake it, so such
+//! sources are skipped until the GPU is awake for another reason. Sources which fail are retried
+//! with an exponential backoff, rather than every second or never again.
+
+use super::{nvidia_temperatures, SensorGroup, SensorRef};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void, CStr},
+    fs, io,
+    path::{Path, PathBuf},
+    ptr,
+    time::{Duration, Instant},
+};
+use sysfs_class::{HwMon, PciDevice, SysClass};
+
+/// The longest time between retries of a failing source.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+pub trait TemperatureSource: Send {
+    /// Names the source in logs, and identifies it across rediscovery.
+    fn id(&self) -> String;
+
+    /// The sensor this source is read as.
+    fn sensor(&self) -> SensorRef;
+
+    /// Whether the device is runtime-suspended, and would be woken by reading it.
+    fn suspended(&self) -> bool { false }
+
+    /// Reads each temperature, in thousandths of a degree Celsius.
+    fn read(&mut self) -> io::Result<Vec<u32>>;
+
+    /// The critical temperature reported by the hardware, in thousandths of a degree Celsius.
+    fn crit(&self) -> Option<u32> { None }
+}
+
+/// Whether the runtime power management of a device has suspended it.
+pub fn runtime_suspended(device: &Path) -> bool {
+    fs::read_to_string(device.join("power/runtime_status"))
+        .is_ok_and(|status| status.trim() == "suspended")
+}
+
+/// A `tempN_input` of a hwmon device.
+pub struct HwmonSource {
+    hwmon:  HwMon,
+    input:  u8,
+    sensor: SensorRef,
+    /// Added to each reading, in thousandths of a degree Celsius.
+    offset: i32,
+    /// The device which is woken by reading the sensor.
+    device: Option<PathBuf>,
+}
+
+impl HwmonSource {
+    /// The package temperature of a CPU, from `coretemp` or `k10temp`.
+    pub fn cpu(hwmon: HwMon) -> Self { Self::group(hwmon, 1, SensorGroup::Cpu, None) }
+
+    /// The temperature of a GPU, from `amdgpu`, `nouveau` or the NVIDIA open kernel module.
+    pub fn gpu(hwmon: HwMon) -> Self {
+        let device = Some(hwmon.path().join("device"));
+        Self::group(hwmon, 1, SensorGroup::Gpu, device)
+    }
+
+    /// A temperature read by an embedded controller, which does not wake the device it measures.
+    pub fn embedded(hwmon: HwMon, input: u8, group: SensorGroup) -> Self {
+        Self::group(hwmon, input, group, None)
+    }
+
+    /// A sensor chosen in the config, with an offset in thousandths of a degree Celsius.
+    pub fn configured(hwmon: HwMon, input: u8, name: String, offset: i32) -> Self {
+        Self { hwmon, input, sensor: SensorRef::Named(name), offset, device: None }
+    }
+
+    fn group(hwmon: HwMon, input: u8, group: SensorGroup, device: Option<PathBuf>) -> Self {
+        Self { hwmon, input, sensor: SensorRef::Group(group), offset: 0, device }
+    }
+}
+
+impl TemperatureSource for HwmonSource {
+    fn id(&self) -> String { format!("{}/temp{}", self.hwmon.path().display(), self.input) }
+
+    fn sensor(&self) -> SensorRef { self.sensor.clone() }
+
+    fn suspended(&self) -> bool { self.device.as_deref().is_some_and(runtime_suspended) }
+
+    fn read(&mut self) -> io::Result<Vec<u32>> {
+        let temp = self.hwmon.parse_file::<i32, _>(format!("temp{}_input", self.input))?;
+        Ok(vec![temp.saturating_add(self.offset).max(0) as u32])
+    }
+
+    fn crit(&self) -> Option<u32> {
+        self.hwmon.parse_file(format!("temp{}_crit", self.input)).ok().filter(|&crit| crit != 0)
+    }
+}
+
+/// The PCI devices of NVIDIA GPUs, which NVML and `nvidia-smi` wake when queried.
+fn nvidia_devices() -> Vec<PathBuf> {
+    PciDevice::all()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|dev| dev.vendor().is_ok_and(|vendor| vendor == 0x10DE))
+        .filter(|dev| dev.class().is_ok_and(|class| (class >> 16) & 0xFF == 0x03))
+        .map(|dev| dev.path().to_owned())
+        .collect()
+}
+
+type NvmlReturn = c_int;
+type NvmlDevice = *mut c_void;
+
+type InitFn = unsafe extern "C" fn() -> NvmlReturn;
+type CountFn = unsafe extern "C" fn(*mut c_uint) -> NvmlReturn;
+type HandleFn = unsafe extern "C" fn(c_uint, *mut NvmlDevice) -> NvmlReturn;
+type TemperatureFn = unsafe extern "C" fn(NvmlDevice, c_int, *mut c_uint) -> NvmlReturn;
+
+const NVML_SUCCESS: NvmlReturn = 0;
+const NVML_TEMPERATURE_GPU: c_int = 0;
+
+/// The NVIDIA Management Library, loaded at runtime so that it is only needed on systems with
+/// the proprietary driver.
+pub struct Nvml {
+    library:     *mut c_void,
+    initialized: bool,
+    devices:     Vec<PathBuf>,
+    init:        InitFn,
+    shutdown:    InitFn,
+    count:       CountFn,
+    handle:      HandleFn,
+    temperature: TemperatureFn,
+}
+
+// NVML is thread-safe, and its functions are only called with the fan daemon locked.
+unsafe impl Send for Nvml {}
+
+impl Nvml {
+    /// Loads `libnvidia-ml.so.1`, without initializing it yet.
+    pub fn load() -> io::Result<Self> {
+        unsafe {
+            let library = libc::dlopen(b"libnvidia-ml.so.1\0".as_ptr().cast(), libc::RTLD_NOW);
+            if library.is_null() {
+                return Err(dl_error("failed to load libnvidia-ml.so.1"));
+            }
+
+            macro_rules! symbol {
+                ($name:literal, $type:ty) => {{
+                    let symbol = libc::dlsym(library, $name.as_ptr().cast());
+                    if symbol.is_null() {
+                        let why = dl_error("failed to load NVML function");
+                        libc::dlclose(library);
+                        return Err(why);
+                    }
+                    std::mem::transmute::<*mut c_void, $type>(symbol)
+                }};
+            }
+
+            Ok(Self {
+                library,
+                initialized: false,
+                devices: nvidia_devices(),
+                init: symbol!(b"nvmlInit_v2\0", InitFn),
+                shutdown: symbol!(b"nvmlShutdown\0", InitFn),
+                count: symbol!(b"nvmlDeviceGetCount_v2\0", CountFn),
+                handle: symbol!(b"nvmlDeviceGetHandleByIndex_v2\0", HandleFn),
+                temperature: symbol!(b"nvmlDeviceGetTemperature\0", TemperatureFn),
+            })
+        }
+    }
+}
+
+fn dl_error(context: &str) -> io::Error {
+    let why = unsafe { libc::dlerror() };
+    let why = if why.is_null() {
+        String::new()
+    } else {
+        unsafe { CStr::from_ptr(why as *const c_char) }.to_string_lossy().into_owned()
+    };
+
+    io::Error::new(io::ErrorKind::NotFound, format!("{}: {}", context, why))
+}
+
+fn nvml_result(result: NvmlReturn) -> io::Result<()> {
+    if result == NVML_SUCCESS {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, format!("NVML error {}", result)))
+    }
+}
+
+impl TemperatureSource for Nvml {
+    fn id(&self) -> String { "NVML".to_owned() }
+
+    fn sensor(&self) -> SensorRef { SensorRef::Group(SensorGroup::Gpu) }
+
+    fn suspended(&self) -> bool { self.devices.iter().any(|device| runtime_suspended(device)) }
+
+    fn read(&mut self) -> io::Result<Vec<u32>> {
+        unsafe {
+            if !self.initialized {
+                nvml_result((self.init)())?;
+                self.initialized = true;
+            }
+
+            let mut count = 0;
+            nvml_result((self.count)(&mut count))?;
+
+            let mut temps = Vec::with_capacity(count as usize);
+            for index in 0..count {
+                let mut device = ptr::null_mut();
+                nvml_result((self.handle)(index, &mut device))?;
+
+                let mut temp = 0;
+                nvml_result((self.temperature)(device, NVML_TEMPERATURE_GPU, &mut temp))?;
+                temps.push(temp * 1000);
+            }
+
+            Ok(temps)
+        }
+    }
+}
+
+impl Drop for Nvml {
+    fn drop(&mut self) {
+        unsafe {
+            if self.initialized {
+                (self.shutdown)();
+            }
+
+            libc::dlclose(self.library);
+        }
+    }
+}
+
+/// Spawns `nvidia-smi` for each reading, when NVML can not be loaded.
+pub struct NvidiaSmi {
+    devices: Vec<PathBuf>,
+}
+
+impl NvidiaSmi {
+    fn new() -> Self { Self { devices: nvidia_devices() } }
+}
+
+impl TemperatureSource for NvidiaSmi {
+    fn id(&self) -> String { "nvidia-smi".to_owned() }
+
+    fn sensor(&self) -> SensorRef { SensorRef::Group(SensorGroup::Gpu) }
+
+    fn suspended(&self) -> bool { self.devices.iter().any(|device| runtime_suspended(device)) }
+
+    fn read(&mut self) -> io::Result<Vec<u32>> {
+        let mut temps = Vec::new();
+        nvidia_temperatures(|temp| temps.push(temp * 1000))?;
+        Ok(temps)
+    }
+}
+
+/// The temperature source of the proprietary NVIDIA driver: NVML if it can be loaded, else
+/// `nvidia-smi`.
+pub fn nvidia_source() -> Box<dyn TemperatureSource> {
+    match Nvml::load() {
+        Ok(nvml) => Box::new(nvml),
+        Err(why) => {
+            log::info!("fan daemon: falling back to nvidia-smi: {}", why);
+            Box::new(NvidiaSmi::new())
+        }
+    }
+}
+
+/// A temperature source, and when it may be retried after failing.
+pub struct Sensor {
+    source:   Box<dyn TemperatureSource>,
+    /// The critical temperature of the source, which is read once when it is found.
+    crit:     Option<u32>,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Sensor {
+    pub fn new(source: Box<dyn TemperatureSource>) -> Self {
+        let crit = source.crit();
+        Self { source, crit, failures: 0, retry_at: None }
+    }
+
+    /// Reads a source which was found again from now on, keeping the backoff of this one.
+    pub fn replace(self, source: Box<dyn TemperatureSource>) -> Self {
+        Self { crit: source.crit(), source, ..self }
+    }
+
+    pub fn id(&self) -> String { self.source.id() }
+
+    pub fn sensor(&self) -> SensorRef { self.source.sensor() }
+
+    pub fn crit(&self) -> Option<u32> { self.crit }
+
+    /// How many times in a row the source has failed to be read.
+    pub fn failures(&self) -> u32 { self.failures }
+
+    /// Reads the source, unless its device is suspended or it is waiting to be retried.
+    pub fn read(&mut self, now: Instant) -> Option<Vec<u32>> {
+        if self.retry_at.is_some_and(|retry_at| now < retry_at) || self.source.suspended() {
+            return None;
+        }
+
+        match self.source.read() {
+            Ok(temps) => {
+                if self.failures != 0 {
+                    log::info!("fan daemon: {} recovered", self.source.id());
+                }
+
+                self.failures = 0;
+                self.retry_at = None;
+                Some(temps)
+            }
+            Err(why) => {
+                let backoff = Duration::from_secs(1 << self.failures.min(16)).min(MAX_BACKOFF);
+                if self.failures == 0 {
+                    log::warn!("fan daemon: failed to read {}: {}", self.source.id(), why);
+                } else {
+                    log::debug!("fan daemon: failed to read {}: {}", self.source.id(), why);
+                }
+
+                self.failures += 1;
+                self.retry_at = Some(now + backoff);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    };
+
+    #[derive(Default)]
+    struct Fake {
+        reads:     Arc<AtomicU32>,
+        failing:   Arc<AtomicBool>,
+        suspended: Arc<AtomicBool>,
+    }
+
+    impl TemperatureSource for Fake {
+        fn id(&self) -> String { "fake".to_owned() }
+
+        fn sensor(&self) -> SensorRef { SensorRef::Group(SensorGroup::Gpu) }
+
+        fn suspended(&self) -> bool { self.suspended.load(Ordering::SeqCst) }
+
+        fn read(&mut self) -> io::Result<Vec<u32>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            if self.failing.load(Ordering::SeqCst) {
+                Err(io::Error::new(io::ErrorKind::Other, "failing"))
+            } else {
+                Ok(vec![50_000])
+            }
+        }
+    }
+
+    #[test]
+    fn retries_with_backoff() {
+        let fake = Fake::default();
+        let (reads, failing) = (fake.reads.clone(), fake.failing.clone());
+        let mut sensor = Sensor::new(Box::new(fake));
+
+        failing.store(true, Ordering::SeqCst);
+        let start = Instant::now();
+        let read_at = |sensor: &mut Sensor, secs| sensor.read(start + Duration::from_secs(secs));
+
+        // Each failure doubles the time until the next attempt: at 0, 1, 3, 7 and 15 seconds.
+        for second in 0..16 {
+            assert_eq!(read_at(&mut sensor, second), None);
+        }
+        assert_eq!(reads.load(Ordering::SeqCst), 5);
+
+        // Once it recovers, it is read every time again.
+        failing.store(false, Ordering::SeqCst);
+        assert_eq!(read_at(&mut sensor, 31), Some(vec![50_000]));
+        assert_eq!(read_at(&mut sensor, 32), Some(vec![50_000]));
+        assert_eq!(reads.load(Ordering::SeqCst), 7);
+    }
+
+    #[test]
+    fn replaced_sources() {
+        let fake = Fake::default();
+        fake.failing.store(true, Ordering::SeqCst);
+        let mut sensor = Sensor::new(Box::new(fake));
+
+        let start = Instant::now();
+        assert_eq!(sensor.read(start), None);
+
+        // The source found again is read instead, once the backoff of the old one has passed.
+        let found = Fake::default();
+        let reads = found.reads.clone();
+        let mut sensor = sensor.replace(Box::new(found));
+        assert_eq!(sensor.failures(), 1);
+        assert_eq!(sensor.read(start + Duration::from_millis(500)), None);
+        assert_eq!(reads.load(Ordering::SeqCst), 0);
+        assert_eq!(sensor.read(start + Duration::from_secs(1)), Some(vec![50_000]));
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn skips_suspended_devices() {
+        let fake = Fake::default();
+        let (reads, suspended) = (fake.reads.clone(), fake.suspended.clone());
+        let mut sensor = Sensor::new(Box::new(fake));
+
+        suspended.store(true, Ordering::SeqCst);
+        assert_eq!(sensor.read(Instant::now()), None);
+        assert_eq!(reads.load(Ordering::SeqCst), 0);
+
+        suspended.store(false, Ordering::SeqCst);
+        assert_eq!(sensor.read(Instant::now()), Some(vec![50_000]));
+    }
+}
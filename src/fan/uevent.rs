@@ -0,0 +1,380 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Decides when the hwmon devices must be scanned again.
+//!
+//! Scanning reads the name of every hwmon, and the labels of the sensors chosen in the config, so
+//! it is only repeated when the kernel reports that a hwmon was added or removed, or when reading
+//! or writing one of the devices that were found fails.
+
+use std::{
+    fs, io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::{Duration, Instant},
+};
+use sysfs_class::{HwMon, SysClass};
+
+/// How often the hwmon devices are scanned when uevents can not be received.
+pub const FALLBACK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The multicast group of the uevents sent by the kernel, rather than those relayed by udev.
+const KERNEL_UEVENTS: u32 = 1;
+
+/// The hwmon devices, and the attributes which scanning them reads.
+pub trait HwmonTree {
+    fn hwmons(&mut self) -> io::Result<Vec<HwMon>>;
+
+    fn read(&mut self, hwmon: &HwMon, attr: &str) -> io::Result<String>;
+
+    fn exists(&mut self, hwmon: &HwMon, attr: &str) -> bool;
+
+    /// Whether an attribute can be written, without writing it.
+    fn writable(&mut self, hwmon: &HwMon, attr: &str) -> bool;
+}
+
+/// The hwmon devices of this system.
+pub struct SysfsHwmons;
+
+impl HwmonTree for SysfsHwmons {
+    fn hwmons(&mut self) -> io::Result<Vec<HwMon>> { HwMon::all() }
+
+    fn read(&mut self, hwmon: &HwMon, attr: &str) -> io::Result<String> { hwmon.read_file(attr) }
+
+    fn exists(&mut self, hwmon: &HwMon, attr: &str) -> bool { hwmon.path().join(attr).exists() }
+
+    fn writable(&mut self, hwmon: &HwMon, attr: &str) -> bool {
+        fs::OpenOptions::new().write(true).open(hwmon.path().join(attr)).is_ok()
+    }
+}
+
+/// Reports whether a hwmon was added or removed.
+pub trait HwmonEvents {
+    /// Whether any hwmon was added or removed since the last call.
+    fn changed(&mut self) -> bool;
+}
+
+/// A netlink socket which receives the kernel's uevents.
+pub struct UeventMonitor {
+    socket: OwnedFd,
+}
+
+impl UeventMonitor {
+    pub fn open() -> io::Result<Self> {
+        unsafe {
+            let fd = libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let socket = OwnedFd::from_raw_fd(fd);
+
+            let mut address: libc::sockaddr_nl = mem::zeroed();
+            address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            address.nl_groups = KERNEL_UEVENTS;
+
+            let result = libc::bind(
+                socket.as_raw_fd(),
+                (&address as *const libc::sockaddr_nl).cast(),
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            );
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self { socket })
+        }
+    }
+}
+
+impl HwmonEvents for UeventMonitor {
+    /// Reads the pending uevents, and returns whether any hwmon was added or removed. Events which
+    /// were dropped because too many were pending count as a change.
+    fn changed(&mut self) -> bool {
+        let mut buffer = [0u8; 8192];
+        let mut changed = false;
+
+        loop {
+            let read = unsafe {
+                libc::recv(self.socket.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len(), 0)
+            };
+
+            if read < 0 {
+                let why = io::Error::last_os_error();
+                match why.kind() {
+                    io::ErrorKind::WouldBlock => (),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => {
+                        log::debug!("fan daemon: failed to receive uevents: {}", why);
+                        changed = true;
+                    }
+                }
+                break;
+            }
+
+            changed |= hwmon_changed(&buffer[..read as usize]);
+        }
+
+        changed
+    }
+}
+
+/// Whether a uevent reports that a hwmon was added or removed.
+pub fn hwmon_changed(message: &[u8]) -> bool {
+    let mut fields = message.split(|&byte| byte == 0);
+    let mut add_or_remove = false;
+    let mut hwmon = false;
+
+    // The header, `ACTION@DEVPATH`, is followed by `KEY=VALUE` fields.
+    fields.next();
+    for field in fields {
+        match field {
+            b"ACTION=add" | b"ACTION=remove" => add_or_remove = true,
+            b"SUBSYSTEM=hwmon" => hwmon = true,
+            _ => (),
+        }
+    }
+
+    add_or_remove && hwmon
+}
+
+/// Tracks whether the devices found by the last scan may be out of date.
+pub struct Rediscovery<M = UeventMonitor> {
+    monitor:   Option<M>,
+    /// When the devices were last scanned.
+    scanned:   Option<Instant>,
+    /// Whether a device that was found could not be read or written.
+    stale:     bool,
+    /// How many scans were made, for the logs and to show the saving.
+    pub scans: u64,
+}
+
+impl Rediscovery {
+    /// Listens for uevents, or falls back to scanning at an interval if they can not be received.
+    pub fn new() -> Self {
+        let monitor = UeventMonitor::open()
+            .map_err(|why| {
+                log::warn!(
+                    "fan daemon: failed to listen for uevents, rescanning every {}s: {}",
+                    FALLBACK_INTERVAL.as_secs(),
+                    why
+                )
+            })
+            .ok();
+
+        Self::with_monitor(monitor)
+    }
+}
+
+impl<M: HwmonEvents> Rediscovery<M> {
+    pub fn with_monitor(monitor: Option<M>) -> Self {
+        Self { monitor, scanned: None, stale: false, scans: 0 }
+    }
+
+    /// Marks the devices as out of date, such as when one could not be read.
+    pub fn invalidate(&mut self) { self.stale = true; }
+
+    /// Whether the devices must be scanned again. If so, they are assumed to be scanned now.
+    pub fn due(&mut self, now: Instant) -> bool {
+        // Always drain the socket, so that old events do not cause a later scan.
+        let changed = self.monitor.as_mut().is_some_and(M::changed);
+
+        let due = match self.scanned {
+            None => true,
+            Some(scanned) => {
+                changed
+                    || self.stale
+                    || (self.monitor.is_none() && now - scanned >= FALLBACK_INTERVAL)
+            }
+        };
+
+        if due {
+            self.scanned = Some(now);
+            self.stale = false;
+            self.scans += 1;
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fan::{config::FanConfig, scan_hwmons};
+    use std::path::PathBuf;
+
+    #[test]
+    fn hwmon_uevents() {
+        let event = |fields: &[&str]| fields.join("\0").into_bytes();
+
+        assert!(hwmon_changed(&event(&[
+            "add@/devices/virtual/thermal/thermal_zone0/hwmon3",
+            "ACTION=add",
+            "DEVPATH=/devices/virtual/thermal/thermal_zone0/hwmon3",
+            "SUBSYSTEM=hwmon",
+            "SEQNUM=4321",
+        ])));
+        assert!(hwmon_changed(&event(&[
+            "remove@/devices/pci0000:00/0000:00:01.0/0000:01:00.0/hwmon/hwmon5",
+            "ACTION=remove",
+            "SUBSYSTEM=hwmon",
+        ])));
+        assert!(!hwmon_changed(&event(&[
+            "change@/devices/platform/hwmon",
+            "ACTION=change",
+            "SUBSYSTEM=hwmon",
+        ])));
+        assert!(!hwmon_changed(&event(&[
+            "add@/devices/pci0000:00/0000:00:14.0/usb1/1-1",
+            "ACTION=add",
+            "SUBSYSTEM=usb",
+        ])));
+        assert!(!hwmon_changed(b""));
+    }
+
+    /// Reports the hwmon changes which the test queues.
+    #[derive(Default)]
+    struct QueuedEvents {
+        pending: bool,
+    }
+
+    impl HwmonEvents for QueuedEvents {
+        fn changed(&mut self) -> bool { std::mem::take(&mut self.pending) }
+    }
+
+    /// A hwmon tree in a temporary directory, which counts the sysfs accesses of scans.
+    struct CountingTree {
+        root:     PathBuf,
+        accesses: u64,
+    }
+
+    impl CountingTree {
+        /// A CPU, an NVMe drive and a Thelio Io board with two fans.
+        fn new() -> Self {
+            let root = std::env::temp_dir()
+                .join(format!("system76-power-hwmon-scans-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+
+            let hwmons: [&[(&str, &str)]; 3] = [
+                &[("name", "coretemp"), ("temp1_input", "45000")],
+                &[("name", "nvme"), ("temp1_label", "Composite"), ("temp2_label", "Sensor 1")],
+                &[("name", "system76_io"), ("pwm1", "0"), ("pwm2", "0"), ("pwm1_enable", "1")],
+            ];
+            for (n, files) in hwmons.iter().enumerate() {
+                let hwmon = root.join(format!("hwmon{}", n));
+                fs::create_dir_all(&hwmon).unwrap();
+                for (file, value) in files.iter() {
+                    fs::write(hwmon.join(file), value).unwrap();
+                }
+            }
+
+            Self { root, accesses: 0 }
+        }
+    }
+
+    impl Drop for CountingTree {
+        fn drop(&mut self) { let _ = fs::remove_dir_all(&self.root); }
+    }
+
+    impl HwmonTree for CountingTree {
+        fn hwmons(&mut self) -> io::Result<Vec<HwMon>> {
+            self.accesses += 1;
+            let mut paths = fs::read_dir(&self.root)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            paths.sort();
+            paths.iter().map(|path| HwMon::from_path(path)).collect()
+        }
+
+        fn read(&mut self, hwmon: &HwMon, attr: &str) -> io::Result<String> {
+            self.accesses += 1;
+            SysfsHwmons.read(hwmon, attr)
+        }
+
+        fn exists(&mut self, hwmon: &HwMon, attr: &str) -> bool {
+            self.accesses += 1;
+            SysfsHwmons.exists(hwmon, attr)
+        }
+
+        fn writable(&mut self, hwmon: &HwMon, attr: &str) -> bool {
+            self.accesses += 1;
+            SysfsHwmons.writable(hwmon, attr)
+        }
+    }
+
+    const STEPS: u64 = 3600;
+
+    /// Steps once a second, with hwmon changes and failures at the given seconds, scanning the
+    /// hwmons whenever they are due, or at every step without a `Rediscovery`, and returns the
+    /// number of sysfs accesses of the scans.
+    fn accesses(
+        tree: &mut CountingTree,
+        mut rediscovery: Option<Rediscovery<QueuedEvents>>,
+        changes: &[u64],
+        failures: &[u64],
+    ) -> u64 {
+        let config: FanConfig = toml::from_str(
+            "[[sensors]]\nname = \"ssd\"\ndriver = \"nvme\"\nlabel = \"Sensor 1\"\n",
+        )
+        .unwrap();
+
+        tree.accesses = 0;
+        let start = Instant::now();
+        for second in 0..STEPS {
+            let due = match rediscovery {
+                Some(ref mut rediscovery) => {
+                    if let Some(ref mut monitor) = rediscovery.monitor {
+                        monitor.pending |= changes.contains(&second);
+                    }
+
+                    if failures.contains(&second) {
+                        rediscovery.invalidate();
+                    }
+
+                    rediscovery.due(start + Duration::from_secs(second))
+                }
+                None => true,
+            };
+
+            if due {
+                let scan = scan_hwmons(tree, &config).unwrap();
+                assert_eq!((scan.platforms.len(), scan.channels, scan.sources.len()), (1, 2, 2));
+            }
+        }
+
+        tree.accesses
+    }
+
+    #[test]
+    fn fewer_scans() {
+        let mut tree = CountingTree::new();
+
+        // Listing the hwmons, reading their names and the labels of the NVMe drive, checking that
+        // pwm1_enable is writable, and probing pwm1 to pwm3.
+        let per_scan = 1 + 3 + 2 + 1 + 3;
+
+        // Scanning every second, as before, would have accessed sysfs this often.
+        assert_eq!(accesses(&mut tree, None, &[], &[]), STEPS * per_scan);
+
+        // Without uevents, the devices are scanned at the fallback interval instead.
+        let fallback = || Some(Rediscovery::with_monitor(None));
+        let fallback_scans = STEPS / FALLBACK_INTERVAL.as_secs();
+        assert_eq!(accesses(&mut tree, fallback(), &[], &[]), fallback_scans * per_scan);
+
+        // A device that fails is scanned for at the next step.
+        assert_eq!(accesses(&mut tree, fallback(), &[], &[5]), (fallback_scans + 1) * per_scan);
+
+        // With uevents, the devices are only scanned once, unless one changes or fails.
+        let monitored = || Some(Rediscovery::with_monitor(Some(QueuedEvents::default())));
+        assert_eq!(accesses(&mut tree, monitored(), &[], &[]), per_scan);
+        assert_eq!(accesses(&mut tree, monitored(), &[500, 1500], &[100, 2000]), 5 * per_scan);
+
+        // A change and a failure at the same step need one scan.
+        assert_eq!(accesses(&mut tree, monitored(), &[700], &[700]), 2 * per_scan);
+    }
+}
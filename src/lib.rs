@@ -28,6 +28,7 @@ pub mod logging;
 pub mod modprobe;
 pub mod module;
 pub mod pci;
+pub mod power_supply;
 pub mod radeon;
 pub mod runtime_pm;
 pub mod snd;
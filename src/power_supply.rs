@@ -0,0 +1,215 @@
+// Copyright 2024 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Helpers for reading the state of AC adapters and batteries from
+//! `/sys/class/power_supply`.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use system76_power_zbus::BatteryInfo;
+
+const SYSFS_PATH: &str = "/sys/class/power_supply";
+
+fn read_attr(supply: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(supply.join(attr)).ok().map(|value| value.trim().to_owned())
+}
+
+/// Power supplies of the given `type`, such as `Mains` or `Battery`.
+fn supplies(kind: &str) -> impl Iterator<Item = PathBuf> + '_ {
+    fs::read_dir(SYSFS_PATH)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(move |path| read_attr(path, "type").as_deref() == Some(kind))
+}
+
+/// Whether any AC adapter is online, or `None` if the system has no AC adapter.
+#[must_use]
+pub fn ac_online() -> Option<bool> {
+    let mut found = false;
+
+    for supply in supplies("Mains") {
+        found = true;
+        if read_attr(&supply, "online").as_deref() == Some("1") {
+            return Some(true);
+        }
+    }
+
+    found.then_some(false)
+}
+
+/// The sysfs directories of the system batteries, such as `/sys/class/power_supply/BAT0`.
+///
+/// Batteries of peripherals, such as wireless mice, are skipped.
+#[must_use]
+pub fn batteries() -> Vec<PathBuf> {
+    let mut batteries = supplies("Battery")
+        .filter(|path| read_attr(path, "scope").as_deref() != Some("Device"))
+        .collect::<Vec<_>>();
+    batteries.sort();
+    batteries
+}
+
+/// The sysfs directory of the first system battery.
+#[must_use]
+pub fn battery() -> Option<PathBuf> { batteries().into_iter().next() }
+
+/// The charging status reported by the first battery, such as `Charging` or `Full`.
+#[must_use]
+pub fn battery_status() -> Option<String> { read_attr(&battery()?, "status") }
+
+/// The charge level of the first battery, in percent.
+#[must_use]
+pub fn battery_capacity() -> Option<u8> { capacity(&battery()?) }
+
+/// The charge level of a battery, in percent.
+#[must_use]
+pub fn capacity(battery: &Path) -> Option<u8> { read_attr(battery, "capacity")?.parse().ok() }
+
+/// The charge behaviours the battery supports, and the one which is selected.
+///
+/// The kernel lists the choices in `charge_behaviour`, with the selected one in brackets, such
+/// as `[auto] inhibit-charge force-discharge`.
+#[must_use]
+pub fn charge_behaviours(battery: &Path) -> Option<(Vec<String>, String)> {
+    let behaviours = read_attr(battery, "charge_behaviour")?;
+
+    let mut selected = String::new();
+    let choices = behaviours
+        .split_ascii_whitespace()
+        .map(|choice| match choice.strip_prefix('[').and_then(|c| c.strip_suffix(']')) {
+            Some(choice) => {
+                selected = choice.to_owned();
+                selected.clone()
+            }
+            None => choice.to_owned(),
+        })
+        .collect();
+
+    Some((choices, selected))
+}
+
+/// The first system battery which has a `charge_behaviour` attribute.
+#[must_use]
+pub fn charge_behaviour_battery() -> Option<PathBuf> {
+    batteries().into_iter().find(|battery| battery.join("charge_behaviour").exists())
+}
+
+/// Selects a charge behaviour, such as `auto`, `inhibit-charge` or `force-discharge`.
+pub fn set_charge_behaviour(battery: &Path, behaviour: &str) -> io::Result<()> {
+    fs::write(battery.join("charge_behaviour"), behaviour)
+}
+
+/// Reads a sysfs value in millionths, such as µWh or µA, and scales it to whole units.
+fn read_micro(supply: &Path, attr: &str) -> Option<f64> {
+    read_attr(supply, attr)?.parse::<u64>().ok().map(|value| value as f64 / 1_000_000.0)
+}
+
+/// Reads a rate in millionths, such as `current_now` or `power_now`, whose sign many batteries
+/// use for the direction of the current. Only its magnitude is kept.
+fn read_rate(supply: &Path, attr: &str) -> Option<f64> {
+    read_attr(supply, attr)?
+        .parse::<i64>()
+        .ok()
+        .map(|value| value.unsigned_abs() as f64 / 1_000_000.0)
+}
+
+/// Collects the health and charge state of a battery.
+///
+/// Batteries report either energy (`energy_*`, in µWh) or charge (`charge_*`, in µAh); the
+/// capacities are given in whichever unit the battery uses.
+#[must_use]
+pub fn battery_info(battery: &Path) -> BatteryInfo {
+    let (unit, prefix) =
+        if battery.join("energy_full").exists() { ("Wh", "energy") } else { ("Ah", "charge") };
+
+    let design_capacity = read_micro(battery, &[prefix, "_full_design"].concat()).unwrap_or(0.0);
+    let full_capacity = read_micro(battery, &[prefix, "_full"].concat()).unwrap_or(0.0);
+    let capacity = read_micro(battery, &[prefix, "_now"].concat()).unwrap_or(0.0);
+
+    let wear = if design_capacity > 0.0 && full_capacity > 0.0 {
+        ((1.0 - full_capacity / design_capacity) * 100.0).max(0.0)
+    } else {
+        0.0
+    };
+
+    let current = read_rate(battery, "current_now");
+    let power = read_rate(battery, "power_now")
+        .or_else(|| Some(current? * read_micro(battery, "voltage_now")?))
+        .unwrap_or(0.0);
+
+    // The rate at which the capacity changes, in the capacity unit per hour.
+    let rate = if prefix == "energy" { power } else { current.unwrap_or(0.0) };
+
+    let status = read_attr(battery, "status").unwrap_or_default();
+
+    let seconds_at_rate = |remaining: f64| {
+        if rate > 0.0 {
+            (remaining.max(0.0) / rate * 3600.0) as u64
+        } else {
+            0
+        }
+    };
+
+    let (seconds_to_empty, seconds_to_full) = match status.as_str() {
+        "Discharging" => (seconds_at_rate(capacity), 0),
+        "Charging" => (0, seconds_at_rate(full_capacity - capacity)),
+        _ => (0, 0),
+    };
+
+    BatteryInfo {
+        name: battery
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        manufacturer: read_attr(battery, "manufacturer").unwrap_or_default(),
+        model: read_attr(battery, "model_name").unwrap_or_default(),
+        technology: read_attr(battery, "technology").unwrap_or_default(),
+        status,
+        capacity_unit: unit.to_owned(),
+        design_capacity,
+        full_capacity,
+        capacity,
+        wear,
+        cycle_count: read_attr(battery, "cycle_count")
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0),
+        power,
+        seconds_to_empty,
+        seconds_to_full,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_rates() {
+        let battery =
+            std::env::temp_dir().join(format!("system76-power-BAT{}", std::process::id()));
+        fs::create_dir_all(&battery).unwrap();
+        let attrs = [
+            ("status", "Discharging"),
+            ("charge_full_design", "4000000"),
+            ("charge_full", "3600000"),
+            ("charge_now", "1800000"),
+            ("current_now", "-900000"),
+            ("voltage_now", "12000000"),
+        ];
+        for (attr, value) in attrs {
+            fs::write(battery.join(attr), value).unwrap();
+        }
+
+        let info = battery_info(&battery);
+        fs::remove_dir_all(&battery).unwrap();
+
+        assert_eq!(info.capacity_unit, "Ah");
+        assert!((info.power - 10.8).abs() < 1e-9, "{} W", info.power);
+        assert_eq!(info.seconds_to_empty, 2 * 60 * 60);
+    }
+}
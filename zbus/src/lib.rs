@@ -12,6 +12,74 @@ pub struct ChargeProfile {
     pub end:         u8,
 }
 
+/// Which charge thresholds the hardware supports.
+#[derive(Deserialize, Serialize, Type, Debug, Default, Clone)]
+pub struct ChargeCapabilities {
+    /// Name of the interface used to set thresholds.
+    pub backend:      String,
+    pub start:        bool,
+    pub end:          bool,
+    /// Accepted start thresholds, or empty if any value from 0 to 100 is accepted.
+    pub start_values: Vec<u8>,
+    /// Accepted end thresholds, or empty if any value from 0 to 100 is accepted.
+    pub end_values:   Vec<u8>,
+    /// Whether the daemon emulates the thresholds by inhibiting charging, rather than the
+    /// firmware enforcing them.
+    pub emulated:     bool,
+}
+
+/// The measured limits of a fan.
+#[derive(Deserialize, Serialize, Type, Debug, Clone, Copy)]
+pub struct FanCalibrationInfo {
+    pub channel:  u8,
+    /// The lowest duty at which the fan reliably starts, in hundredths of a percent.
+    pub min_duty: u16,
+    /// The speed at full duty, in RPM.
+    pub max_rpm:  u32,
+}
+
+/// The hottest temperatures, and the highest duties and speeds, over a period.
+#[derive(Deserialize, Serialize, Type, Debug, Clone)]
+pub struct ThermalSample {
+    /// The start of the period, in seconds since the Unix epoch.
+    pub time:        u64,
+    /// The hottest temperature, in thousandths of a degree Celsius, or 0 if it could not be read.
+    pub temperature: u32,
+    /// The hottest temperature of each sensor, in thousandths of a degree Celsius.
+    pub sensors:     Vec<(String, u32)>,
+    /// The highest duty of each channel, in hundredths of a percent. Empty while the firmware
+    /// controlled the fans.
+    pub duties:      Vec<(u8, u16)>,
+    /// The fastest speed of each fan, in RPM.
+    pub speeds:      Vec<(u8, u32)>,
+    /// The power profile at the end of the period.
+    pub profile:     String,
+}
+
+/// Health and charge state of a battery, as reported by the kernel.
+///
+/// Capacities are in watt-hours when `capacity_unit` is `Wh`, or amp-hours when it is `Ah`.
+/// Values which the battery does not report are zero.
+#[derive(Deserialize, Serialize, Type, Debug, Default)]
+pub struct BatteryInfo {
+    pub name:             String,
+    pub manufacturer:     String,
+    pub model:            String,
+    pub technology:       String,
+    pub status:           String,
+    pub capacity_unit:    String,
+    pub design_capacity:  f64,
+    pub full_capacity:    f64,
+    pub capacity:         f64,
+    /// Percentage of the design capacity which has been lost.
+    pub wear:             f64,
+    pub cycle_count:      u32,
+    /// Power draw or charge rate, in watts.
+    pub power:            f64,
+    pub seconds_to_empty: u64,
+    pub seconds_to_full:  u64,
+}
+
 #[zbus::dbus_proxy(
     interface = "com.system76.PowerDaemon",
     default_service = "com.system76.PowerDaemon",
@@ -57,15 +125,63 @@ trait PowerDaemon {
     /// AutoGraphicsPower
     fn auto_graphics_power(&self) -> zbus::Result<()>;
 
+    /// StartCalibration method
+    fn start_calibration(&self) -> zbus::Result<()>;
+
+    /// CancelCalibration method
+    fn cancel_calibration(&self) -> zbus::Result<()>;
+
+    /// GetCalibration method
+    fn get_calibration(&self) -> zbus::Result<(String, String)>;
+
+    /// GetBatteryInfo method
+    fn get_battery_info(&self) -> zbus::Result<Vec<BatteryInfo>>;
+
+    /// GetChargeBehaviour method
+    fn get_charge_behaviour(&self) -> zbus::Result<(String, Vec<String>)>;
+
+    /// SetChargeBehaviour method
+    fn set_charge_behaviour(&self, behaviour: &str, timeout: u32) -> zbus::Result<()>;
+
+    /// GetSmartCharging method
+    fn get_smart_charging(&self) -> zbus::Result<bool>;
+
+    /// SetSmartCharging method
+    fn set_smart_charging(&self, enabled: bool) -> zbus::Result<()>;
+
+    /// GetNextFullCharge method
+    fn get_next_full_charge(&self) -> zbus::Result<u64>;
+
     /// GetChargeProfiles method
     fn get_charge_profiles(&self) -> zbus::Result<Vec<ChargeProfile>>;
 
+    /// GetChargeCapabilities method
+    fn get_charge_capabilities(&self) -> zbus::Result<ChargeCapabilities>;
+
     /// GetChargeThresholds method
     fn get_charge_thresholds(&self) -> zbus::Result<(u8, u8)>;
 
     /// SetChargeThresholds method
     fn set_charge_thresholds(&self, thresholds: &(u8, u8)) -> zbus::Result<()>;
 
+    /// ChargeToFullOnce method
+    fn charge_to_full_once(&self) -> zbus::Result<()>;
+
+    /// ChargeToFullUntil method
+    fn charge_to_full_until(&self, timestamp: u64) -> zbus::Result<()>;
+
+    /// CalibrationProgress signal
+    #[dbus_proxy(signal)]
+    fn calibration_progress(&self, stage: &str, message: &str) -> zbus::Result<()>;
+
+    /// CalibrationFinished signal
+    #[dbus_proxy(signal)]
+    fn calibration_finished(&self, battery: BatteryInfo) -> zbus::Result<()>;
+
+    /// ChargeThresholdsChanged signal
+    #[dbus_proxy(signal)]
+    fn charge_thresholds_changed(&self, start: u8, end: u8) -> zbus::Result<()>;
+
     /// HotPlugDetect signal
     #[dbus_proxy(signal)]
     fn hot_plug_detect(&self, port: u64) -> zbus::Result<()>;
@@ -74,3 +190,55 @@ trait PowerDaemon {
     #[dbus_proxy(signal)]
     fn power_profile_switch(&self, profile: &str) -> zbus::Result<()>;
 }
+
+#[zbus::dbus_proxy(
+    interface = "com.system76.PowerDaemon.Fan",
+    default_service = "com.system76.PowerDaemon",
+    default_path = "/com/system76/PowerDaemon"
+)]
+trait Fan {
+    /// GetCurve method
+    fn get_curve(&self) -> zbus::Result<(String, Vec<(i16, u16)>)>;
+
+    /// GetCurves method
+    fn get_curves(&self) -> zbus::Result<Vec<String>>;
+
+    /// SetCurve method
+    fn set_curve(&self, name: &str) -> zbus::Result<()>;
+
+    /// GetTemperature method
+    fn get_temperature(&self) -> zbus::Result<u32>;
+
+    /// GetDuties method
+    fn get_duties(&self) -> zbus::Result<Vec<(u8, u16)>>;
+
+    /// GetSpeeds method
+    fn get_speeds(&self) -> zbus::Result<Vec<(u8, u32)>>;
+
+    /// SetManualDuty method
+    fn set_manual_duty(&self, percent: u8, timeout: u32) -> zbus::Result<()>;
+
+    /// Calibrate method
+    fn calibrate(&self) -> zbus::Result<()>;
+
+    /// CancelCalibration method
+    fn cancel_calibration(&self) -> zbus::Result<()>;
+
+    /// GetCalibration method
+    fn get_calibration(&self) -> zbus::Result<(bool, u8, Vec<FanCalibrationInfo>)>;
+
+    /// GetThermalHistory method
+    fn get_thermal_history(&self, since: u64, resolution: u32) -> zbus::Result<Vec<ThermalSample>>;
+
+    /// FanFailure signal
+    #[dbus_proxy(signal)]
+    fn fan_failure(&self, channel: u8, rpm: u32, duty: u16) -> zbus::Result<()>;
+
+    /// ThermalEmergency signal
+    #[dbus_proxy(signal)]
+    fn thermal_emergency(&self, active: bool, temperature: u32) -> zbus::Result<()>;
+
+    /// FanCalibrated signal
+    #[dbus_proxy(signal)]
+    fn fan_calibrated(&self, calibrations: Vec<FanCalibrationInfo>) -> zbus::Result<()>;
+}